@@ -21,6 +21,8 @@ fn main() {
                 Button::text("A", Color::Primary, None),
                 Button::text("B", Color::Secondary, Some(r#"{"a": "b"}"#.into())),
             ],
+            // Row 1
+            vec![Button::location(None)],
         ],
         false, // One-time? (i.e. show only until a button is pressed on the keyboard?)
     );
@@ -38,6 +40,26 @@ fn main() {
                 eprintln!("{:?}", ctx.send());
             }),
         )
+        // Used when the user sends their location (e.g. using the location button):
+        .on_geo(Handler::new(|ctx| {
+            let message = match (ctx.coordinates(), ctx.place()) {
+                (Some(coordinates), Some(place)) => format!(
+                    "You are at {}, {} ({}).",
+                    coordinates.latitude(),
+                    coordinates.longitude(),
+                    place.title().as_deref().unwrap_or("unknown place"),
+                ),
+                (Some(coordinates), None) => format!(
+                    "You are at {}, {}.",
+                    coordinates.latitude(),
+                    coordinates.longitude(),
+                ),
+                _ => "Where are you?".into(),
+            };
+
+            ctx.response().set_message(&message);
+            eprintln!("{:?}", ctx.send());
+        }))
         // Used when the specified payload is found inside of the message:
         .payload(r#"{"a":"b"}"#, simple_handler("You pressed button B!"))
         // Accept all remaining payloads:
@@ -0,0 +1,32 @@
+//! Replays requests recorded by `Bot::with_recorder` through a `Core` against
+//! a mock VK API, and prints the calls the handlers made.
+//!
+//! Usage: `cargo run --example replay -- requests.jsonl`
+
+use vk_bot::{api::MockAPI, record, Core, Event, Handler};
+
+fn main() {
+    let path = std::env::args()
+        .nth(1)
+        .expect("usage: replay <recorded requests file>");
+
+    // Use the `Core` of your bot here.
+    let core = Core::new().on(
+        Event::NoMatch,
+        Handler::new(|ctx| {
+            ctx.response()
+                .set_message("I don't understand this message...");
+            eprintln!("{:?}", ctx.send());
+        }),
+    );
+
+    let api = MockAPI::new();
+    match record::replay_file(&path, &core, &api) {
+        Ok(handled) => println!("handled {} requests", handled),
+        Err(e) => eprintln!("{}", e),
+    }
+
+    for call in api.calls() {
+        println!("{} {:?}", call.method(), call.params());
+    }
+}
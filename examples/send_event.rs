@@ -0,0 +1,102 @@
+//! Sends a Callback API request to a running bot, e.g. `examples/basic.rs`.
+//!
+//! Usage:
+//!
+//! ```text
+//! send_event [--url URL] [--group-id ID] [--secret SECRET] EVENT [OPTIONS]
+//!
+//! EVENT:
+//!   confirmation
+//!   message_new --peer ID [--from ID] [--text TEXT] [--payload JSON] [--attach photo1_2]...
+//!   message_allow --peer ID [--key KEY]
+//!   message_deny --peer ID
+//!   message_typing_state --peer ID
+//!   template FILE           (a JSON file with `type` and `object`)
+//! ```
+//!
+//! The secret defaults to the `VK_BOT_SECRET` environment variable.
+
+use std::{collections::HashMap, env, fs, process};
+use vk_bot::callback::{EventSender, NewMessage};
+
+fn main() {
+    let mut options: HashMap<String, Vec<String>> = HashMap::new();
+    let mut positional = Vec::new();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.strip_prefix("--") {
+            Some(name) => match args.next() {
+                Some(value) => options.entry(name.into()).or_default().push(value),
+                None => fail(&format!("missing value of --{}", name)),
+            },
+            None => positional.push(arg),
+        }
+    }
+
+    let option = |name: &str| options.get(name).and_then(|values| values.last()).cloned();
+    let peer = || -> i64 {
+        option("peer")
+            .unwrap_or_else(|| fail("missing --peer"))
+            .parse()
+            .unwrap_or_else(|_| fail("invalid --peer"))
+    };
+
+    let url = option("url").unwrap_or_else(|| "http://localhost:12345/".into());
+    let group_id = option("group-id")
+        .map_or(Ok(1), |id| id.parse())
+        .unwrap_or_else(|_| fail("invalid --group-id"));
+
+    let mut events = EventSender::new(&url, group_id);
+    if let Some(secret) = option("secret").or_else(|| env::var("VK_BOT_SECRET").ok()) {
+        events = events.secret(&secret);
+    }
+
+    let request = match positional.first().map(String::as_str) {
+        Some("confirmation") => events.confirmation(),
+        Some("message_new") => {
+            let mut message = NewMessage::new(peer(), &option("text").unwrap_or_default());
+            if let Some(from_id) = option("from") {
+                message =
+                    message.from_id(from_id.parse().unwrap_or_else(|_| fail("invalid --from")));
+            }
+            if let Some(payload) = option("payload") {
+                message = message.payload(&payload);
+            }
+            for attachment in options.get("attach").into_iter().flatten() {
+                message = message.attach(
+                    attachment
+                        .parse()
+                        .unwrap_or_else(|e| fail(&format!("invalid --attach: {}", e))),
+                );
+            }
+            events.message_new(&message)
+        }
+        Some("message_allow") => events.message_allow(peer(), option("key").as_deref()),
+        Some("message_deny") => events.message_deny(peer()),
+        Some("message_typing_state") => events.message_typing_state(peer()),
+        Some("template") => {
+            let path = positional
+                .get(1)
+                .unwrap_or_else(|| fail("missing template file"));
+            let template = fs::read_to_string(path)
+                .unwrap_or_else(|e| fail(&format!("failed to read {}: {}", path, e)));
+            events.template(
+                &serde_json::from_str(&template)
+                    .unwrap_or_else(|e| fail(&format!("invalid template: {}", e))),
+            )
+        }
+        _ => fail("unknown or missing event, see examples/send_event.rs for usage"),
+    };
+
+    println!("-> {}", request);
+    match events.send(&request) {
+        Ok(body) => println!("<- {}", body),
+        Err(e) => fail(&e.to_string()),
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1)
+}
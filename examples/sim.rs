@@ -0,0 +1,60 @@
+//! Talk to a bot in the terminal, without a VK token. Replace the `Core` below
+//! with the one of your bot.
+//!
+//! Usage: `cargo run --example sim`, then type messages, or `:help`.
+
+use vk_bot::{
+    keyboard::{Button, Color, Keyboard},
+    sim, Core, Event, Handler,
+};
+
+fn main() {
+    let kbd = Keyboard::new(
+        vec![
+            vec![
+                Button::text("A", Color::Primary, None),
+                Button::text("B", Color::Secondary, Some(r#"{"a":"b"}"#.into())),
+            ],
+            vec![Button::location(None)],
+        ],
+        false,
+    );
+
+    let core = Core::new()
+        .cmd_prefix("/")
+        .cmd(
+            "keyboard",
+            Handler::new(move |ctx| {
+                ctx.response().set_message("Here you go:");
+                ctx.response().set_keyboard(kbd.clone());
+                eprintln!("{:?}", ctx.send());
+            }),
+        )
+        .payload(
+            r#"{"a":"b"}"#,
+            Handler::new(|ctx| {
+                ctx.response().set_message("You pressed button B!");
+                eprintln!("{:?}", ctx.send());
+            }),
+        )
+        .on_geo(Handler::new(|ctx| {
+            let coordinates = ctx.coordinates().unwrap();
+            let message = format!(
+                "You are at {}, {}.",
+                coordinates.latitude(),
+                coordinates.longitude()
+            );
+            ctx.response().set_message(&message);
+            eprintln!("{:?}", ctx.send());
+        }))
+        .on(
+            Event::NoMatch,
+            Handler::new(|ctx| {
+                ctx.response()
+                    .set_message("I don't understand this message... Try /keyboard.");
+                eprintln!("{:?}", ctx.send());
+            }),
+        );
+
+    sim::run(core).expect("failed to run the simulator");
+}
@@ -0,0 +1,341 @@
+//! The [`VkAPI`] trait, which abstracts VK API calls made by the bot, and
+//! [`MockAPI`], a recording implementation of it for tests.
+//!
+//! [`Context`](crate::Context), [`Sender`](crate::context::Sender),
+//! [`Queue`](crate::queue::Queue) and [`Bot`](crate::Bot) make all VK API
+//! calls through a [`VkAPI`], which is an [`rvk::APIClient`] unless another
+//! one is given (see [`Bot::with_api`](crate::Bot::with_api)). Thus handlers
+//! can be tested with a [`MockAPI`] instead, asserting on what they would have
+//! sent:
+//!
+//! ```
+//! use serde_json::json;
+//! use vk_bot::{api::MockAPI, request::CallbackAPIRequest, Core, Handler};
+//!
+//! let core = Core::new().cmd_prefix("/").cmd(
+//!     "hi",
+//!     Handler::new(|ctx| {
+//!         ctx.response().set_message("Hello!");
+//!         ctx.send().unwrap();
+//!     }),
+//! );
+//!
+//! let message = json!({"id": 1, "date": 1, "peer_id": 42, "from_id": 42, "text": "/hi"});
+//! let req = CallbackAPIRequest::new(None, 1, "message_new", serde_json::from_value(message)?);
+//!
+//! let api = MockAPI::new();
+//! core.handle(&req, &api).unwrap();
+//!
+//! let sent = api.calls_to("messages.send");
+//! assert_eq!(sent.len(), 1);
+//! assert_eq!(sent[0].param("peer_id"), Some("42"));
+//! assert_eq!(sent[0].param("message"), Some("Hello!"));
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use crate::upload::{self, UploadError};
+use rvk::{error::Error, APIClient, Params};
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// The pseudo-method name [`MockAPI`] records [`VkAPI::upload_file`] calls
+/// under.
+pub const UPLOAD: &str = "upload";
+
+/// Something VK API calls can be made through.
+pub trait VkAPI: Debug + Send + Sync {
+    /// Calls the VK API method named `method` (e.g. `messages.send`) with
+    /// `params`, and returns its response.
+    fn call_method(&self, method: &str, params: Params) -> Result<Value, Error>;
+
+    /// `POST`s a file to an upload server URL, see [`upload::upload_file`].
+    fn upload_file(
+        &self,
+        url: &str,
+        field: &str,
+        file_name: &str,
+        bytes: Vec<u8>,
+    ) -> Result<Value, UploadError> {
+        upload::upload_file(url, field, file_name, bytes)
+    }
+}
+
+impl VkAPI for APIClient {
+    fn call_method(&self, method: &str, params: Params) -> Result<Value, Error> {
+        APIClient::call_method(self, method, params)
+    }
+}
+
+impl<T: VkAPI + ?Sized> VkAPI for &T {
+    fn call_method(&self, method: &str, params: Params) -> Result<Value, Error> {
+        (**self).call_method(method, params)
+    }
+
+    fn upload_file(
+        &self,
+        url: &str,
+        field: &str,
+        file_name: &str,
+        bytes: Vec<u8>,
+    ) -> Result<Value, UploadError> {
+        (**self).upload_file(url, field, file_name, bytes)
+    }
+}
+
+impl<T: VkAPI + ?Sized> VkAPI for Arc<T> {
+    fn call_method(&self, method: &str, params: Params) -> Result<Value, Error> {
+        (**self).call_method(method, params)
+    }
+
+    fn upload_file(
+        &self,
+        url: &str,
+        field: &str,
+        file_name: &str,
+        bytes: Vec<u8>,
+    ) -> Result<Value, UploadError> {
+        (**self).upload_file(url, field, file_name, bytes)
+    }
+}
+
+/// A call recorded by a [`MockAPI`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Call {
+    method: String,
+    params: Params,
+}
+
+impl Call {
+    /// Returns the name of the called method, or [`UPLOAD`] for uploads.
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    /// Returns the parameters of the call.
+    ///
+    /// For uploads, these are `url`, `field`, `file_name` and `size`.
+    pub fn params(&self) -> &Params {
+        &self.params
+    }
+
+    /// Returns the parameter named `key`, if present.
+    pub fn param(&self, key: &str) -> Option<&str> {
+        self.params.get(key).map(String::as_str)
+    }
+}
+
+/// A [`VkAPI`] which makes no requests, but records every call, and returns
+/// scripted responses.
+///
+/// Responses scripted via [`MockAPI::respond`] and [`MockAPI::fail`] are
+/// returned in order, per method. When there are none left, a plausible
+/// default response is returned:
+/// - `messages.send` returns a new message ID (or, with `peer_ids`, a new
+///   message ID per peer),
+/// - `*.getMessagesUploadServer` returns an `upload_url`, uploads return a
+///   file, and `photos.saveMessagesPhoto` and `docs.save` return a new photo
+///   or document,
+/// - other methods return `1`.
+#[derive(Debug, Default)]
+pub struct MockAPI {
+    calls: Mutex<Vec<Call>>,
+    responses: Mutex<HashMap<String, VecDeque<Result<Value, Error>>>>,
+    last_id: AtomicI64,
+}
+
+impl MockAPI {
+    /// Creates a new [`MockAPI`] without any recorded calls or scripted
+    /// responses.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Scripts the next unscripted call of `method` to return `response`.
+    pub fn respond(&self, method: &str, response: Value) {
+        self.script(method, Ok(response));
+    }
+
+    /// Scripts the next unscripted call of `method` to fail with `error`.
+    pub fn fail(&self, method: &str, error: Error) {
+        self.script(method, Err(error));
+    }
+
+    fn script(&self, method: &str, response: Result<Value, Error>) {
+        self.responses
+            .lock()
+            .unwrap()
+            .entry(method.into())
+            .or_default()
+            .push_back(response);
+    }
+
+    /// Returns all calls made so far, in order.
+    pub fn calls(&self) -> Vec<Call> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// Returns the calls of `method` made so far, in order.
+    pub fn calls_to(&self, method: &str) -> Vec<Call> {
+        self.calls
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|call| call.method == method)
+            .cloned()
+            .collect()
+    }
+
+    /// Forgets the calls made so far (but not the scripted responses).
+    pub fn clear(&self) {
+        self.calls.lock().unwrap().clear();
+    }
+
+    /// Records a call, and returns its response.
+    fn record(&self, method: &str, params: Params) -> Result<Value, Error> {
+        let scripted = self
+            .responses
+            .lock()
+            .unwrap()
+            .get_mut(method)
+            .and_then(VecDeque::pop_front);
+        let response = scripted.unwrap_or_else(|| Ok(self.default_response(method, &params)));
+
+        self.calls.lock().unwrap().push(Call {
+            method: method.into(),
+            params,
+        });
+
+        response
+    }
+
+    /// Returns the response to a call for which no response was scripted.
+    fn default_response(&self, method: &str, params: &Params) -> Value {
+        match method {
+            "messages.send" => match params.get("peer_ids") {
+                Some(peer_ids) => peer_ids
+                    .split(',')
+                    .map(|peer_id| {
+                        json!({
+                            "peer_id": peer_id.parse::<i64>().unwrap_or_default(),
+                            "message_id": self.next_id(),
+                        })
+                    })
+                    .collect(),
+                None => self.next_id().into(),
+            },
+            "photos.getMessagesUploadServer" | "docs.getMessagesUploadServer" => {
+                json!({ "upload_url": format!("https://upload.invalid/{}", method) })
+            }
+            UPLOAD => json!({
+                "server": 1,
+                "photo": r#"[{"photo":"mock"}]"#,
+                "hash": "mock",
+                "file": "mock",
+            }),
+            "photos.saveMessagesPhoto" => json!([{ "id": self.next_id(), "owner_id": 1 }]),
+            "docs.save" => json!({
+                "type": "doc",
+                "doc": { "id": self.next_id(), "owner_id": 1 },
+            }),
+            _ => 1.into(),
+        }
+    }
+
+    fn next_id(&self) -> i64 {
+        self.last_id.fetch_add(1, Ordering::SeqCst) + 1
+    }
+}
+
+impl VkAPI for MockAPI {
+    fn call_method(&self, method: &str, params: Params) -> Result<Value, Error> {
+        self.record(method, params)
+    }
+
+    fn upload_file(
+        &self,
+        url: &str,
+        field: &str,
+        file_name: &str,
+        bytes: Vec<u8>,
+    ) -> Result<Value, UploadError> {
+        let mut params = Params::new();
+        params.insert("url".into(), url.into());
+        params.insert("field".into(), field.into());
+        params.insert("file_name".into(), file_name.into());
+        params.insert("size".into(), bytes.len().to_string());
+
+        Ok(self.record(UPLOAD, params)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rvk::error::APIError;
+
+    fn params(pairs: &[(&str, &str)]) -> Params {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn records_calls() {
+        let api = MockAPI::new();
+
+        api.call_method("users.get", params(&[("user_ids", "1")]))
+            .unwrap();
+        api.call_method("messages.send", params(&[("peer_id", "2")]))
+            .unwrap();
+
+        let calls = api.calls();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].method(), "users.get");
+        assert_eq!(calls[0].param("user_ids"), Some("1"));
+        assert_eq!(api.calls_to("messages.send")[0].param("peer_id"), Some("2"));
+
+        api.clear();
+        assert!(api.calls().is_empty());
+    }
+
+    #[test]
+    fn scripted_responses() {
+        let api = MockAPI::new();
+        api.respond("users.get", json!([{"id": 1}]));
+        api.fail(
+            "users.get",
+            Error::API(APIError::new(5, "auth".into(), HashMap::new())),
+        );
+
+        assert_eq!(
+            api.call_method("users.get", Params::new()).unwrap(),
+            json!([{"id": 1}])
+        );
+        assert!(matches!(
+            api.call_method("users.get", Params::new()),
+            Err(Error::API(e)) if e.code() == 5
+        ));
+        assert_eq!(api.call_method("users.get", Params::new()).unwrap(), 1);
+        assert_eq!(api.calls().len(), 3);
+    }
+
+    #[test]
+    fn default_message_ids() {
+        let api = MockAPI::new();
+
+        assert_eq!(api.call_method("messages.send", Params::new()).unwrap(), 1);
+        assert_eq!(
+            api.call_method("messages.send", params(&[("peer_ids", "5,6")]))
+                .unwrap(),
+            json!([{"peer_id": 5, "message_id": 2}, {"peer_id": 6, "message_id": 3}])
+        );
+    }
+}
@@ -0,0 +1,689 @@
+//! Attachments of incoming messages.
+
+use rvk::objects::Integer;
+use serde::de::{Deserialize, DeserializeOwned, Deserializer, Error as _};
+use serde_derive::Deserialize;
+use serde_json::Value;
+
+/// An attachment of a [`Message`](crate::message::Message).
+///
+/// VK sends attachments as `{"type": "photo", "photo": {...}}`; the object
+/// under the key named by `type` is parsed into the respective variant. If the
+/// object of a supported type can not be parsed, the attachment becomes
+/// [`Attachment::Other`] (and a warning is logged), so that one malformed
+/// attachment does not make the whole message unreadable.
+#[derive(Debug, Clone)]
+pub enum Attachment {
+    /// Type `photo`.
+    Photo(Photo),
+    /// Type `video`.
+    Video(Video),
+    /// Type `audio`.
+    Audio(Audio),
+    /// Type `doc`.
+    Doc(Doc),
+    /// Type `sticker`.
+    Sticker(Sticker),
+    /// Type `link`.
+    Link(Link),
+    /// Type `wall`.
+    Wall(Wall),
+    /// Type `audio_message` (voice message).
+    AudioMessage(AudioMessage),
+    /// Type `graffiti`.
+    Graffiti(Graffiti),
+    /// Type `poll`.
+    Poll(Poll),
+    /// Any other attachment type which is not (yet) supported by this crate,
+    /// or an attachment of a supported type which could not be parsed.
+    Other {
+        /// The `type` field of the attachment.
+        r#type: String,
+        /// The object under the key named by `type`, or [`Value::Null`] if
+        /// there was none.
+        object: Value,
+    },
+}
+
+impl<'de> Deserialize<'de> for Attachment {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        fn parse<T: DeserializeOwned>(object: &Value) -> Result<T, serde_json::Error> {
+            T::deserialize(object)
+        }
+
+        let mut value = Value::deserialize(deserializer)?;
+
+        let r#type = value
+            .get("type")
+            .and_then(Value::as_str)
+            .ok_or_else(|| D::Error::missing_field("type"))?
+            .to_string();
+
+        let object = value
+            .get_mut(&r#type)
+            .map(Value::take)
+            .unwrap_or(Value::Null);
+
+        let attachment = match r#type.as_str() {
+            "photo" => parse(&object).map(Attachment::Photo),
+            "video" => parse(&object).map(Attachment::Video),
+            "audio" => parse(&object).map(Attachment::Audio),
+            "doc" => parse(&object).map(Attachment::Doc),
+            "sticker" => parse(&object).map(Attachment::Sticker),
+            "link" => parse(&object).map(Attachment::Link),
+            "wall" => parse(&object).map(Attachment::Wall),
+            "audio_message" => parse(&object).map(Attachment::AudioMessage),
+            "graffiti" => parse(&object).map(Attachment::Graffiti),
+            "poll" => parse(&object).map(Attachment::Poll),
+            _ => return Ok(Attachment::Other { r#type, object }),
+        };
+
+        Ok(attachment.unwrap_or_else(|e| {
+            warn!("failed to parse `{}` attachment: {}", r#type, e);
+            Attachment::Other { r#type, object }
+        }))
+    }
+}
+
+/// A photo.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Photo {
+    id: Integer,
+    owner_id: Integer,
+    access_key: Option<String>,
+    album_id: Option<Integer>,
+    #[serde(default)]
+    text: String,
+    date: Option<Integer>,
+    #[serde(default)]
+    sizes: Vec<PhotoSize>,
+}
+
+impl Photo {
+    /// Returns the ID of this photo.
+    pub fn id(&self) -> Integer {
+        self.id
+    }
+
+    /// Returns the ID of this photo's owner.
+    pub fn owner_id(&self) -> Integer {
+        self.owner_id
+    }
+
+    /// Returns the access key of this photo, if present.
+    pub fn access_key(&self) -> &Option<String> {
+        &self.access_key
+    }
+
+    /// Returns the ID of the album this photo is in, if present.
+    pub fn album_id(&self) -> Option<Integer> {
+        self.album_id
+    }
+
+    /// Returns the description of this photo.
+    pub fn text(&self) -> &String {
+        &self.text
+    }
+
+    /// Returns the date this photo was uploaded on (Unix time), if present.
+    pub fn date(&self) -> Option<Integer> {
+        self.date
+    }
+
+    /// Returns the available sizes of this photo.
+    pub fn sizes(&self) -> &Vec<PhotoSize> {
+        &self.sizes
+    }
+}
+
+/// A copy of a [`Photo`] in a particular size.
+#[derive(Debug, Deserialize, Clone)]
+pub struct PhotoSize {
+    #[serde(rename = "type")]
+    r#type: String,
+    url: String,
+    #[serde(default)]
+    width: Integer,
+    #[serde(default)]
+    height: Integer,
+}
+
+impl PhotoSize {
+    /// Returns the size type (`s`, `m`, `x`, ...).
+    pub fn r#type(&self) -> &String {
+        &self.r#type
+    }
+
+    /// Returns the URL of this copy.
+    pub fn url(&self) -> &String {
+        &self.url
+    }
+
+    /// Returns the width of this copy in pixels.
+    pub fn width(&self) -> Integer {
+        self.width
+    }
+
+    /// Returns the height of this copy in pixels.
+    pub fn height(&self) -> Integer {
+        self.height
+    }
+}
+
+/// A video.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Video {
+    id: Integer,
+    owner_id: Integer,
+    access_key: Option<String>,
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
+    duration: Integer,
+}
+
+impl Video {
+    /// Returns the ID of this video.
+    pub fn id(&self) -> Integer {
+        self.id
+    }
+
+    /// Returns the ID of this video's owner.
+    pub fn owner_id(&self) -> Integer {
+        self.owner_id
+    }
+
+    /// Returns the access key of this video, if present.
+    pub fn access_key(&self) -> &Option<String> {
+        &self.access_key
+    }
+
+    /// Returns the title of this video.
+    pub fn title(&self) -> &String {
+        &self.title
+    }
+
+    /// Returns the duration of this video in seconds.
+    pub fn duration(&self) -> Integer {
+        self.duration
+    }
+}
+
+/// An audio recording.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Audio {
+    id: Integer,
+    owner_id: Integer,
+    access_key: Option<String>,
+    #[serde(default)]
+    artist: String,
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
+    duration: Integer,
+    url: Option<String>,
+}
+
+impl Audio {
+    /// Returns the ID of this audio.
+    pub fn id(&self) -> Integer {
+        self.id
+    }
+
+    /// Returns the ID of this audio's owner.
+    pub fn owner_id(&self) -> Integer {
+        self.owner_id
+    }
+
+    /// Returns the access key of this audio, if present.
+    pub fn access_key(&self) -> &Option<String> {
+        &self.access_key
+    }
+
+    /// Returns the artist of this audio.
+    pub fn artist(&self) -> &String {
+        &self.artist
+    }
+
+    /// Returns the title of this audio.
+    pub fn title(&self) -> &String {
+        &self.title
+    }
+
+    /// Returns the duration of this audio in seconds.
+    pub fn duration(&self) -> Integer {
+        self.duration
+    }
+
+    /// Returns the URL of the audio file, if present.
+    pub fn url(&self) -> &Option<String> {
+        &self.url
+    }
+}
+
+/// A document.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Doc {
+    id: Integer,
+    owner_id: Integer,
+    access_key: Option<String>,
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
+    size: Integer,
+    #[serde(default)]
+    ext: String,
+    url: Option<String>,
+    #[serde(rename = "type")]
+    doc_type: Option<Integer>,
+}
+
+impl Doc {
+    /// Returns the ID of this document.
+    pub fn id(&self) -> Integer {
+        self.id
+    }
+
+    /// Returns the ID of this document's owner.
+    pub fn owner_id(&self) -> Integer {
+        self.owner_id
+    }
+
+    /// Returns the access key of this document, if present.
+    pub fn access_key(&self) -> &Option<String> {
+        &self.access_key
+    }
+
+    /// Returns the title of this document.
+    pub fn title(&self) -> &String {
+        &self.title
+    }
+
+    /// Returns the size of this document in bytes.
+    pub fn size(&self) -> Integer {
+        self.size
+    }
+
+    /// Returns the extension of this document (e.g. `pdf`).
+    pub fn ext(&self) -> &String {
+        &self.ext
+    }
+
+    /// Returns the URL of this document, if present.
+    pub fn url(&self) -> &Option<String> {
+        &self.url
+    }
+
+    /// Returns the numeric document type (`1` for text documents, `4` for
+    /// images, etc.), if present.
+    pub fn doc_type(&self) -> Option<Integer> {
+        self.doc_type
+    }
+}
+
+/// A sticker.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Sticker {
+    #[serde(default)]
+    product_id: Integer,
+    sticker_id: Integer,
+}
+
+impl Sticker {
+    /// Returns the ID of the sticker pack this sticker belongs to.
+    pub fn product_id(&self) -> Integer {
+        self.product_id
+    }
+
+    /// Returns the ID of this sticker.
+    pub fn sticker_id(&self) -> Integer {
+        self.sticker_id
+    }
+}
+
+/// A link.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Link {
+    url: String,
+    #[serde(default)]
+    title: String,
+    caption: Option<String>,
+    description: Option<String>,
+}
+
+impl Link {
+    /// Returns the URL of this link.
+    pub fn url(&self) -> &String {
+        &self.url
+    }
+
+    /// Returns the title of this link.
+    pub fn title(&self) -> &String {
+        &self.title
+    }
+
+    /// Returns the caption of this link, if present.
+    pub fn caption(&self) -> &Option<String> {
+        &self.caption
+    }
+
+    /// Returns the description of this link, if present.
+    pub fn description(&self) -> &Option<String> {
+        &self.description
+    }
+}
+
+/// A wall post.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Wall {
+    id: Integer,
+    owner_id: Option<Integer>,
+    to_id: Option<Integer>,
+    from_id: Option<Integer>,
+    #[serde(default)]
+    text: String,
+}
+
+impl Wall {
+    /// Returns the ID of this post.
+    pub fn id(&self) -> Integer {
+        self.id
+    }
+
+    /// Returns the ID of the wall this post is on, if present.
+    ///
+    /// VK may send this as either `owner_id` or `to_id`.
+    pub fn owner_id(&self) -> Option<Integer> {
+        self.owner_id.or(self.to_id)
+    }
+
+    /// Returns the ID of this post's author, if present.
+    pub fn from_id(&self) -> Option<Integer> {
+        self.from_id
+    }
+
+    /// Returns the text of this post.
+    pub fn text(&self) -> &String {
+        &self.text
+    }
+}
+
+/// A voice message.
+#[derive(Debug, Deserialize, Clone)]
+pub struct AudioMessage {
+    id: Integer,
+    owner_id: Integer,
+    access_key: Option<String>,
+    #[serde(default)]
+    duration: Integer,
+    #[serde(default)]
+    waveform: Vec<Integer>,
+    link_ogg: Option<String>,
+    link_mp3: Option<String>,
+}
+
+impl AudioMessage {
+    /// Returns the ID of this voice message.
+    pub fn id(&self) -> Integer {
+        self.id
+    }
+
+    /// Returns the ID of this voice message's owner.
+    pub fn owner_id(&self) -> Integer {
+        self.owner_id
+    }
+
+    /// Returns the access key of this voice message, if present.
+    pub fn access_key(&self) -> &Option<String> {
+        &self.access_key
+    }
+
+    /// Returns the duration of this voice message in seconds.
+    pub fn duration(&self) -> Integer {
+        self.duration
+    }
+
+    /// Returns the waveform of this voice message.
+    pub fn waveform(&self) -> &Vec<Integer> {
+        &self.waveform
+    }
+
+    /// Returns the URL of the `.ogg` file, if present.
+    pub fn link_ogg(&self) -> &Option<String> {
+        &self.link_ogg
+    }
+
+    /// Returns the URL of the `.mp3` file, if present.
+    pub fn link_mp3(&self) -> &Option<String> {
+        &self.link_mp3
+    }
+}
+
+/// A graffiti.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Graffiti {
+    id: Integer,
+    owner_id: Integer,
+    access_key: Option<String>,
+    url: Option<String>,
+    #[serde(default)]
+    width: Integer,
+    #[serde(default)]
+    height: Integer,
+}
+
+impl Graffiti {
+    /// Returns the ID of this graffiti.
+    pub fn id(&self) -> Integer {
+        self.id
+    }
+
+    /// Returns the ID of this graffiti's owner.
+    pub fn owner_id(&self) -> Integer {
+        self.owner_id
+    }
+
+    /// Returns the access key of this graffiti, if present.
+    pub fn access_key(&self) -> &Option<String> {
+        &self.access_key
+    }
+
+    /// Returns the URL of the graffiti image, if present.
+    pub fn url(&self) -> &Option<String> {
+        &self.url
+    }
+
+    /// Returns the width of this graffiti in pixels.
+    pub fn width(&self) -> Integer {
+        self.width
+    }
+
+    /// Returns the height of this graffiti in pixels.
+    pub fn height(&self) -> Integer {
+        self.height
+    }
+}
+
+/// A poll.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Poll {
+    id: Integer,
+    owner_id: Integer,
+    #[serde(default)]
+    question: String,
+    #[serde(default)]
+    answers: Vec<PollAnswer>,
+    #[serde(default)]
+    anonymous: bool,
+    #[serde(default)]
+    multiple: bool,
+}
+
+impl Poll {
+    /// Returns the ID of this poll.
+    pub fn id(&self) -> Integer {
+        self.id
+    }
+
+    /// Returns the ID of this poll's owner.
+    pub fn owner_id(&self) -> Integer {
+        self.owner_id
+    }
+
+    /// Returns the question of this poll.
+    pub fn question(&self) -> &String {
+        &self.question
+    }
+
+    /// Returns the answer options of this poll.
+    pub fn answers(&self) -> &Vec<PollAnswer> {
+        &self.answers
+    }
+
+    /// Indicates whether this poll is anonymous.
+    pub fn anonymous(&self) -> bool {
+        self.anonymous
+    }
+
+    /// Indicates whether multiple answers can be chosen in this poll.
+    pub fn multiple(&self) -> bool {
+        self.multiple
+    }
+}
+
+/// An answer option of a [`Poll`].
+#[derive(Debug, Deserialize, Clone)]
+pub struct PollAnswer {
+    id: Integer,
+    #[serde(default)]
+    text: String,
+    #[serde(default)]
+    votes: Integer,
+    #[serde(default)]
+    rate: f64,
+}
+
+impl PollAnswer {
+    /// Returns the ID of this answer.
+    pub fn id(&self) -> Integer {
+        self.id
+    }
+
+    /// Returns the text of this answer.
+    pub fn text(&self) -> &String {
+        &self.text
+    }
+
+    /// Returns the number of votes for this answer.
+    pub fn votes(&self) -> Integer {
+        self.votes
+    }
+
+    /// Returns the percentage of votes for this answer.
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn photo() -> Result<(), serde_json::Error> {
+        let attachment: Attachment = serde_json::from_value(json!({
+            "type": "photo",
+            "photo": {
+                "id": 2,
+                "owner_id": 1,
+                "access_key": "key",
+                "sizes": [{"type": "s", "url": "https://example.com/s.jpg", "width": 75, "height": 50}],
+            },
+        }))?;
+
+        match attachment {
+            Attachment::Photo(photo) => {
+                assert_eq!(photo.id(), 2);
+                assert_eq!(photo.owner_id(), 1);
+                assert_eq!(photo.access_key(), &Some("key".to_string()));
+                assert_eq!(photo.sizes()[0].width(), 75);
+            }
+            other => panic!("expected photo, got {:?}", other),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn sticker() -> Result<(), serde_json::Error> {
+        let attachment: Attachment = serde_json::from_value(json!({
+            "type": "sticker",
+            "sticker": {"product_id": 1, "sticker_id": 9},
+        }))?;
+
+        match attachment {
+            Attachment::Sticker(sticker) => assert_eq!(sticker.sticker_id(), 9),
+            other => panic!("expected sticker, got {:?}", other),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_attachment_becomes_other() -> Result<(), serde_json::Error> {
+        let attachment: Attachment = serde_json::from_value(json!({
+            "type": "photo",
+            "photo": {"id": "not a number"},
+        }))?;
+
+        match attachment {
+            Attachment::Other { r#type, object } => {
+                assert_eq!(r#type, "photo");
+                assert_eq!(object, json!({"id": "not a number"}));
+            }
+            other => panic!("expected other, got {:?}", other),
+        }
+
+        // The other attachments of the message are still available.
+        let msg: crate::message::Message = serde_json::from_value(json!({
+            "date": 1,
+            "from_id": 1,
+            "text": "",
+            "attachments": [
+                {"type": "photo", "photo": {}},
+                {"type": "sticker", "sticker": {"product_id": 1, "sticker_id": 9}},
+            ],
+        }))?;
+        assert!(matches!(msg.attachments()[0], Attachment::Other { .. }));
+        assert!(matches!(msg.attachments()[1], Attachment::Sticker(_)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_type() -> Result<(), serde_json::Error> {
+        let attachment: Attachment = serde_json::from_value(json!({
+            "type": "market",
+            "market": {"id": 1},
+        }))?;
+
+        match attachment {
+            Attachment::Other { r#type, object } => {
+                assert_eq!(r#type, "market");
+                assert_eq!(object, json!({"id": 1}));
+            }
+            other => panic!("expected other, got {:?}", other),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn missing_type() {
+        assert!(serde_json::from_value::<Attachment>(json!({"photo": {}})).is_err());
+    }
+}
@@ -1,31 +1,214 @@
 //! The [`Bot`] struct and server setup.
 
-use crate::{core::Core, request::CallbackAPIRequest};
+use crate::{
+    api::VkAPI,
+    config::{BotBuilder, ConfigError, REDACTED},
+    context::Sender,
+    core::{Core, EventFromStrError},
+    dedup::{MemorySeenSet, SeenSet},
+    dispatch::{DispatchError, Dispatcher},
+    pool::PoolConfig,
+    queue::{Queue, QueueConfig, Queued},
+    record::Recorder,
+    request::CallbackAPIRequest,
+    response::AttachmentInformation,
+    upload::{self, UploadError},
+    webhook::{self, Response, Webhook},
+};
+#[cfg(feature = "rocket")]
 use rocket::{
     config::{Config, Environment},
+    fairing::{Fairing, Info, Kind},
     http::Status,
-    State,
+    Rocket, Route, State,
 };
+#[cfg(feature = "rocket")]
 use rocket_contrib::json::Json;
-use rvk::APIClient;
+use rvk::{objects::Integer, APIClient};
+use serde::Deserialize;
+use serde_json::Value;
+#[cfg(feature = "rocket")]
+use std::sync::Mutex;
+use std::{
+    fmt::{self, Debug, Formatter},
+    net::{IpAddr, Ipv4Addr},
+    path::PathBuf,
+    sync::Arc,
+    time::SystemTime,
+};
 
 /// The string `ok` which needs to be sent in response to every Callback API
 /// request.
 const VK_OK: &str = "ok";
 
 /// [`Bot`] represents a chat bot, and hands received requests to [`Core`].
-#[derive(Debug)]
+///
+/// The [`Debug`] output does not contain the confirmation token and secret.
 pub struct Bot {
-    api: APIClient,
     confirmation_token: String,
     group_id: i32,
     secret: Option<String>,
+    server: ServerConfig,
+    handling: Arc<Handling>,
+    dispatcher: Option<Dispatcher>,
+    recorder: Option<Recorder>,
+}
+
+/// Configuration of the HTTP server of a [`Bot`].
+///
+/// By default, the server listens on all IPv4 interfaces (`0.0.0.0`), with
+/// the Callback API route mounted at `/`, and without TLS.
+///
+/// Rocket 0.4 can not listen on Unix sockets; to serve a bot through a
+/// reverse proxy, listen on a loopback address (e.g. `127.0.0.1` or `::1`)
+/// instead, or serve it with the hyper adapter of [`webhook`] (the `hyper`
+/// feature) on a `tokio::net::UnixListener`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerConfig {
+    address: IpAddr,
     port: u16,
+    path: String,
+    tls: Option<(PathBuf, PathBuf)>,
+}
+
+impl ServerConfig {
+    /// Creates a new [`ServerConfig`] for `port`, with the default address
+    /// and path.
+    pub fn new(port: u16) -> Self {
+        Self {
+            address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port,
+            path: "/".into(),
+            tls: None,
+        }
+    }
+
+    /// Sets the IPv4 or IPv6 address to listen on.
+    pub fn address(mut self, address: IpAddr) -> Self {
+        self.address = address;
+        self
+    }
+
+    /// Sets the port to listen on.
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Sets the path the Callback API route is mounted at, e.g. `/bots/1`, so
+    /// that several bots can share a host behind a reverse proxy. A missing
+    /// leading `/` is added, and trailing ones are removed.
+    pub fn path(mut self, path: &str) -> Self {
+        self.path = format!("/{}", path.trim_matches('/'));
+        self
+    }
+
+    /// Makes the server use TLS with the certificate chain and private key
+    /// in the given PEM files.
+    #[cfg(feature = "tls")]
+    pub fn tls<C: Into<PathBuf>, K: Into<PathBuf>>(mut self, certs: C, key: K) -> Self {
+        self.tls = Some((certs.into(), key.into()));
+        self
+    }
+
+    /// Returns the path the Callback API route is mounted at.
+    #[cfg(feature = "rocket")]
+    pub(crate) fn mount_point(&self) -> &str {
+        &self.path
+    }
+
+    /// Checks that Rocket can be configured to serve on this server, e.g.
+    /// that the TLS certificates and key can be read. Without the `rocket`
+    /// feature, there is nothing to check.
+    ///
+    /// # Errors
+    /// - [`ConfigError::Invalid`] if the configuration is rejected by Rocket.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        #[cfg(feature = "rocket")]
+        self.rocket_config()?;
+        Ok(())
+    }
+
+    /// Returns the Rocket configuration for this server.
+    ///
+    /// # Errors
+    /// - [`ConfigError::Invalid`] if the TLS certificates or key can not be
+    ///   read.
+    #[cfg(feature = "rocket")]
+    pub(crate) fn rocket_config(&self) -> Result<Config, ConfigError> {
+        #[allow(unused_mut)]
+        let mut builder = Config::build(Environment::Production)
+            .address(self.address.to_string())
+            .port(self.port);
+
+        #[cfg(feature = "tls")]
+        {
+            if let Some((certs, key)) = &self.tls {
+                builder = builder.tls(
+                    certs.to_string_lossy().into_owned(),
+                    key.to_string_lossy().into_owned(),
+                );
+            }
+        }
+
+        builder.finalize().map_err(|e| {
+            ConfigError::Invalid(match &self.tls {
+                Some((certs, key)) => format!(
+                    "invalid server configuration (TLS certificates `{}`, key `{}`): {}",
+                    certs.display(),
+                    key.display(),
+                    e
+                ),
+                None => format!("invalid server configuration: {}", e),
+            })
+        })
+    }
+}
+
+/// Everything needed to handle a request, shared with the [`Dispatcher`].
+struct Handling {
+    api: Arc<dyn VkAPI>,
     core: Core,
+    queue: Queue,
+    seen: Box<dyn SeenSet>,
+}
+
+impl Handling {
+    /// See [`Bot::handle`].
+    fn handle(&self, req: &CallbackAPIRequest) -> Result<(), EventFromStrError> {
+        if let Some(event_id) = req.event_id() {
+            if !self.seen.insert(event_id, SystemTime::now()) {
+                debug!("dropping redelivered event `{}`", event_id);
+                return Ok(());
+            }
+        }
+
+        self.core.handle_with_queue(req, &*self.api, &self.queue)
+    }
+}
+
+impl Debug for Handling {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        // The API is left out, as `rvk::APIClient` would print its token.
+        f.debug_struct("Handling")
+            .field("core", &self.core)
+            .field("queue", &self.queue)
+            .field("seen", &self.seen)
+            .finish()
+    }
 }
 
 impl Bot {
-    /// Creates a new [`Bot`].
+    /// Returns a [`BotBuilder`] to create a [`Bot`] with named settings, or
+    /// from a [`BotConfig`](crate::config::BotConfig). See the
+    /// [`config`](crate::config) module.
+    pub fn builder() -> BotBuilder {
+        BotBuilder::new()
+    }
+
+    /// Creates a new [`Bot`], with an outbound [`Queue`] using the default
+    /// [`QueueConfig`], and a default [`MemorySeenSet`] to drop redelivered
+    /// events.
     #[must_use = "the bot does nothing unless started via `.start()`"]
     pub fn new(
         vk_token: &str,
@@ -35,44 +218,196 @@ impl Bot {
         port: u16,
         core: Core,
     ) -> Self {
+        let api: Arc<dyn VkAPI> = Arc::new(APIClient::new(vk_token));
+
         Self {
-            api: APIClient::new(vk_token),
             confirmation_token: confirmation_token.into(),
             group_id,
             secret,
-            port,
-            core,
+            server: ServerConfig::new(port),
+            handling: Arc::new(Handling {
+                queue: Queue::new(QueueConfig::default(), Arc::clone(&api)),
+                api,
+                core,
+                seen: Box::new(MemorySeenSet::default()),
+            }),
+            dispatcher: None,
+            recorder: None,
         }
     }
 
-    /// Alias for `self.core.handle(req, self.api())`.
-    pub fn handle(&self, req: &CallbackAPIRequest) {
-        self.core.handle(req, self.api());
+    /// Replaces the [`ServerConfig`] of this [`Bot`] (including the port given
+    /// to [`Bot::new`]).
+    #[must_use = "the bot does nothing unless started via `.start()`"]
+    pub fn with_server_config(mut self, server: ServerConfig) -> Self {
+        self.server = server;
+        self
+    }
+
+    /// Replaces the [`SeenSet`] this [`Bot`] uses to drop redelivered events,
+    /// e.g. with a [`FileSeenSet`](crate::dedup::FileSeenSet) to keep it
+    /// across restarts.
+    #[must_use = "the bot does nothing unless started via `.start()`"]
+    pub fn with_seen_set<S: SeenSet + 'static>(self, seen: S) -> Self {
+        self.update_handling(|handling| handling.seen = Box::new(seen))
     }
 
-    /// Starts this [`Bot`], consuming `self`.
+    /// Replaces the [`VkAPI`] this [`Bot`] makes VK API calls through (an
+    /// [`rvk::APIClient`] by default), e.g. with a
+    /// [`MockAPI`](crate::api::MockAPI) in tests.
     ///
-    /// # Panics
-    /// - if Rocket was not able to launch.
-    pub fn start(self) -> ! {
-        info!("starting bot...");
+    /// The outbound [`Queue`] is replaced with one with the same
+    /// [`QueueConfig`] which sends messages using `api`.
+    #[must_use = "the bot does nothing unless started via `.start()`"]
+    pub fn with_api<A: VkAPI + 'static>(self, api: A) -> Self {
+        self.update_handling(|handling| {
+            let api: Arc<dyn VkAPI> = Arc::new(api);
+            handling.queue = Queue::new(handling.queue.config().clone(), Arc::clone(&api));
+            handling.api = api;
+        })
+    }
 
-        let err = rocket::custom(
-            Config::build(Environment::Production)
-                .address("0.0.0.0")
-                .port(self.port)
-                .unwrap(),
-        )
-        .mount("/", routes![post, get])
-        .manage(self)
-        .launch();
+    /// Replaces the outbound [`Queue`] of this [`Bot`], e.g. with one created
+    /// with a custom [`QueueConfig`].
+    #[must_use = "the bot does nothing unless started via `.start()`"]
+    pub fn with_queue(self, queue: Queue) -> Self {
+        self.update_handling(|handling| handling.queue = queue)
+    }
 
-        panic!("{}", err);
+    /// Makes this [`Bot`] respond to Callback API requests right away, and
+    /// handle them in the background using a [`Dispatcher`] with a worker
+    /// pool with the given configuration. See the [`pool`](crate::pool)
+    /// module.
+    #[must_use = "the bot does nothing unless started via `.start()`"]
+    pub fn with_worker_pool(mut self, config: PoolConfig) -> Self {
+        let handling = Arc::clone(&self.handling);
+        self.dispatcher = Some(Dispatcher::new(config, move |req| handling.handle(req)));
+        self
+    }
+
+    /// Makes this [`Bot`] record every received request (which passed the
+    /// `secret` and group ID checks) using `recorder`. See the
+    /// [`record`](crate::record) module.
+    #[must_use = "the bot does nothing unless started via `.start()`"]
+    pub fn with_recorder(mut self, recorder: Recorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Changes the shared [`Handling`], stopping the dispatcher (if any) while
+    /// doing so.
+    fn update_handling<F: FnOnce(&mut Handling)>(mut self, f: F) -> Self {
+        let config = self.dispatcher.take().map(|d| d.config().clone());
+
+        f(Arc::get_mut(&mut self.handling).expect("handling is only shared with the dispatcher"));
+
+        match config {
+            Some(config) => self.with_worker_pool(config),
+            None => self,
+        }
+    }
+
+    /// Handles a request using `self.core().handle_with_queue(req,
+    /// self.api(), self.queue())`, unless it is a redelivery of an event which
+    /// was already received (according to its `event_id`), in which case it
+    /// is dropped.
+    ///
+    /// Note that an event is considered received before it is handled, so if
+    /// handling it fails, a redelivery is dropped too.
+    ///
+    /// This always handles the request on the current thread; see
+    /// [`Bot::dispatch`].
+    pub fn handle(&self, req: &CallbackAPIRequest) -> Result<(), EventFromStrError> {
+        self.handling.handle(req)
+    }
+
+    /// Handles a request in the background, if this [`Bot`] has a worker pool
+    /// (see [`Bot::with_worker_pool`]), or on the current thread otherwise
+    /// (see [`Bot::handle`]).
+    ///
+    /// See [`Dispatcher::dispatch`] for details.
+    pub fn dispatch(&self, req: CallbackAPIRequest) -> Result<(), DispatchError> {
+        match &self.dispatcher {
+            Some(dispatcher) => dispatcher.dispatch(req),
+            None => self.handle(&req).map_err(DispatchError::Event),
+        }
+    }
+
+    /// Returns a [`Sender`] which can send messages outside of handlers (e.g.
+    /// from a background thread, with the [`Bot`] shared via
+    /// [`std::sync::Arc`]), through the outbound [`Queue`].
+    pub fn sender(&self) -> Sender<'_> {
+        Sender::with_queue(self.api(), self.queue())
+    }
+
+    /// Returns a [`VkAPI`] which makes calls through the outbound [`Queue`].
+    fn queued(&self) -> Queued<'_> {
+        Queued::new(self.api(), Some(self.queue()))
+    }
+
+    /// Uploads a photo to be sent to `peer_id`. See [`upload::upload_photo`].
+    pub fn upload_photo(
+        &self,
+        peer_id: Option<Integer>,
+        file_name: &str,
+        bytes: Vec<u8>,
+    ) -> Result<AttachmentInformation, UploadError> {
+        upload::upload_photo(&self.queued(), peer_id, file_name, bytes)
     }
 
-    /// Returns the global [`rvk::APIClient`] which is used in this [`Bot`].
-    pub fn api(&self) -> &APIClient {
-        &self.api
+    /// Uploads a document to be sent to `peer_id`. See
+    /// [`upload::upload_document`].
+    pub fn upload_document(
+        &self,
+        peer_id: Integer,
+        file_name: &str,
+        bytes: Vec<u8>,
+        title: Option<&str>,
+    ) -> Result<AttachmentInformation, UploadError> {
+        upload::upload_document(&self.queued(), peer_id, file_name, bytes, title)
+    }
+
+    /// Uploads a voice message to be sent to `peer_id`. See
+    /// [`upload::upload_audio_message`].
+    pub fn upload_audio_message(
+        &self,
+        peer_id: Integer,
+        file_name: &str,
+        bytes: Vec<u8>,
+    ) -> Result<AttachmentInformation, UploadError> {
+        upload::upload_audio_message(&self.queued(), peer_id, file_name, bytes)
+    }
+
+    /// Returns the [`VkAPI`] which is used in this [`Bot`] (see
+    /// [`Bot::with_api`]).
+    pub fn api(&self) -> &dyn VkAPI {
+        &*self.handling.api
+    }
+
+    /// Returns the [`Core`] of this [`Bot`].
+    pub fn core(&self) -> &Core {
+        &self.handling.core
+    }
+
+    /// Returns the [`Dispatcher`] of this [`Bot`], if it handles requests in
+    /// the background.
+    pub fn dispatcher(&self) -> Option<&Dispatcher> {
+        self.dispatcher.as_ref()
+    }
+
+    /// Returns the outbound [`Queue`] of this [`Bot`].
+    pub fn queue(&self) -> &Queue {
+        &self.handling.queue
+    }
+
+    /// Returns the [`Recorder`] of this [`Bot`], if any.
+    pub fn recorder(&self) -> Option<&Recorder> {
+        self.recorder.as_ref()
+    }
+
+    /// Returns the [`ServerConfig`] of this [`Bot`].
+    pub fn server_config(&self) -> &ServerConfig {
+        &self.server
     }
 
     /// Returns the confirmation token stored in this [`Bot`].
@@ -91,79 +426,546 @@ impl Bot {
     }
 }
 
-/// Handles `GET` requests by returning
-/// [`rocket::http::Status::MethodNotAllowed`].
-#[get("/")]
-fn get() -> Status {
-    debug!("received a GET request");
-    Status::MethodNotAllowed
+impl Debug for Bot {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("Bot")
+            .field("confirmation_token", &REDACTED)
+            .field("group_id", &self.group_id)
+            .field("secret", &self.secret.as_ref().map(|_| REDACTED))
+            .field("server", &self.server)
+            .field("handling", &self.handling)
+            .field("dispatcher", &self.dispatcher)
+            .field("recorder", &self.recorder)
+            .finish()
+    }
 }
 
-/// Handles `POST` requests by first checking that secret and group ID are
-/// correct, and then responds with either confirmation token (if that is what
-/// was requested) or [`VK_OK`] in the other case.
-#[post("/", format = "json", data = "<data>")]
-fn post(data: Json<CallbackAPIRequest>, state: State<Bot>) -> Result<String, Status> {
-    let bot = &*state;
+#[cfg(feature = "rocket")]
+impl Bot {
+    /// Starts this [`Bot`], consuming `self`.
+    ///
+    /// # Panics
+    /// - if the server configuration is invalid (see
+    ///   [`ServerConfig::validate`]),
+    /// - if Rocket was not able to launch.
+    pub fn start(self) -> ! {
+        info!("starting bot...");
+
+        let config = self
+            .server
+            .rocket_config()
+            .unwrap_or_else(|e| panic!("{}", e));
+        let path = self.server.mount_point().to_string();
+
+        let err = self.mount(rocket::custom(config), &path).launch();
+
+        panic!("{}", err);
+    }
+
+    /// Returns the routes which handle Callback API requests. They need a
+    /// [`Bot`] in Rocket's managed state; see [`Bot::mount`].
+    pub fn routes() -> Vec<Route> {
+        routes![post, get]
+    }
+
+    /// Mounts the routes of this [`Bot`] at `path` on `rocket`, an existing
+    /// Rocket application, and hands `self` to it as managed state, consuming
+    /// `self`.
+    ///
+    /// The [`ServerConfig`] of this [`Bot`] is not used, as the application
+    /// is configured (and launched) by its owner. As a Rocket application
+    /// can only manage one [`Bot`], use a
+    /// [`MultiBot`](crate::multi::MultiBot) to serve several communities.
+    ///
+    /// ```no_run
+    /// # #![feature(proc_macro_hygiene, decl_macro)]
+    /// # #[macro_use] extern crate rocket;
+    /// use vk_bot::{Bot, Core};
+    ///
+    /// #[get("/")]
+    /// fn admin() -> &'static str {
+    ///     "Admin page"
+    /// }
+    ///
+    /// let bot = Bot::new("...", "f123456", 1, None, 12345, Core::new());
+    /// bot.mount(rocket::ignite(), "/vk")
+    ///     .mount("/admin", routes![admin])
+    ///     .launch();
+    /// ```
+    pub fn mount(self, rocket: Rocket, path: &str) -> Rocket {
+        rocket.mount(path, Self::routes()).manage(self)
+    }
+
+    /// Returns a [`BotFairing`], which does what [`Bot::mount`] does when
+    /// attached to a Rocket application, consuming `self`.
+    pub fn fairing(self, path: &str) -> BotFairing {
+        BotFairing {
+            path: path.into(),
+            bot: Mutex::new(Some(self)),
+        }
+    }
+}
+
+impl Webhook for Bot {
+    /// Checks that `secret` and group ID of the request are correct, records it
+    /// (if this [`Bot`] has a [`Recorder`]), and then responds with either
+    /// confirmation token (if that is what was requested) or `ok` in the
+    /// other case. Requests to paths other than `/` are not found.
+    fn respond(&self, path: &str, data: &Value) -> Response {
+        if !path.trim_matches('/').is_empty() {
+            debug!("received a POST request to an unknown path `{}`", path);
+            return Response::error(webhook::NOT_FOUND);
+        }
+
+        let req = match CallbackAPIRequest::deserialize(data) {
+            Ok(req) => req,
+            Err(e) => {
+                debug!("received a POST request with an invalid body: {}", e);
+                return Response::error(webhook::UNPROCESSABLE_ENTITY);
+            }
+        };
 
-    match &data {
-        x if x.secret() != bot.secret() => {
+        if req.secret() != self.secret() {
             debug!("received a POST request with invalid `secret`");
-            Err(Status::Forbidden)
+            return Response::error(webhook::FORBIDDEN);
         }
-        x if x.group_id() != bot.group_id() => {
+
+        if req.group_id() != self.group_id() {
             debug!("received a POST request with invalid `group_id`");
-            Err(Status::Forbidden)
+            return Response::error(webhook::FORBIDDEN);
         }
-        x if x.r#type() == "confirmation" => {
-            debug!("responded with confirmation token");
-            Ok(bot.confirmation_token().clone())
+
+        if let Some(recorder) = self.recorder() {
+            recorder.record(data, SystemTime::now());
         }
-        _ => {
-            bot.handle(&data);
-            Ok(VK_OK.into())
+
+        match req {
+            x if x.r#type() == "confirmation" => {
+                debug!("responded with confirmation token");
+                Response::ok(self.confirmation_token())
+            }
+            _ => match self.dispatch(req) {
+                // VK will keep retrying the request if we do not respond with
+                // `ok`, which would not help with an unsupported event anyway.
+                Err(DispatchError::Event(e)) => {
+                    warn!("failed to handle request: {}", e);
+                    Response::ok(VK_OK)
+                }
+                // VK will deliver the event again later.
+                Err(DispatchError::Pool(e)) => {
+                    warn!("failed to enqueue request: {}", e);
+                    Response::error(webhook::SERVICE_UNAVAILABLE)
+                }
+                Ok(()) => Response::ok(VK_OK),
+            },
         }
     }
 }
 
+/// A Rocket fairing which mounts the routes of a [`Bot`] when attached. See
+/// [`Bot::fairing`].
+#[cfg(feature = "rocket")]
+#[derive(Debug)]
+pub struct BotFairing {
+    path: String,
+    bot: Mutex<Option<Bot>>,
+}
+
+#[cfg(feature = "rocket")]
+impl Fairing for BotFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "vk-bot",
+            kind: Kind::Attach,
+        }
+    }
+
+    fn on_attach(&self, rocket: Rocket) -> Result<Rocket, Rocket> {
+        match self.bot.lock().unwrap().take() {
+            Some(bot) => Ok(bot.mount(rocket, &self.path)),
+            None => {
+                error!("the bot fairing was already attached");
+                Err(rocket)
+            }
+        }
+    }
+}
+
+/// Handles `GET` requests by returning
+/// [`rocket::http::Status::MethodNotAllowed`].
+#[cfg(feature = "rocket")]
+#[get("/")]
+fn get() -> Status {
+    debug!("received a GET request");
+    Status::MethodNotAllowed
+}
+
+/// Handles `POST` requests using [`Webhook::respond`].
+#[cfg(feature = "rocket")]
+#[post("/", format = "json", data = "<data>")]
+fn post(data: Json<Value>, state: State<Bot>) -> Result<String, Status> {
+    rocket_response(state.respond("/", &data))
+}
+
+/// Converts a [`Response`] to what Rocket routes return.
+#[cfg(feature = "rocket")]
+pub(crate) fn rocket_response(response: Response) -> Result<String, Status> {
+    response
+        .into_result()
+        .map_err(|status| Status::from_code(status).unwrap_or(Status::InternalServerError))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use serde_json::json;
 
     #[test]
+    #[cfg(feature = "rocket")]
     fn get_returns_405() {
         assert_eq!(get(), Status::MethodNotAllowed);
     }
 
-    fn post_test(secret: &str, group_id: i32, event: &str) -> Result<String, Status> {
-        let rocket = rocket::ignite().manage(Bot::new(
+    fn post_test(secret: &str, group_id: i32, event: &str) -> Result<String, u16> {
+        let bot = Bot::new(
             "vk_token",
             "confirmation_token",
             1,
             Some("secret".into()),
             12345,
             Default::default(),
-        ));
+        );
 
-        post(
-            Json(CallbackAPIRequest::new(
-                Some(secret.into()),
-                group_id,
-                event,
-                Default::default(),
-            )),
-            State::from(&rocket).unwrap(),
+        webhook::handle(
+            &bot,
+            "POST",
+            "/",
+            Some("application/json"),
+            json!({"secret": secret, "group_id": group_id, "type": event})
+                .to_string()
+                .as_bytes(),
+        )
+        .into_result()
+    }
+
+    #[test]
+    #[cfg(feature = "rocket")]
+    fn mount() {
+        let bot = || {
+            Bot::new(
+                "vk_token",
+                "confirmation_token",
+                1,
+                None,
+                12345,
+                Core::new(),
+            )
+        };
+
+        let rocket = bot().mount(rocket::ignite(), "/vk");
+        assert_eq!(
+            post(
+                Json(json!({"group_id": 1, "type": "confirmation"})),
+                State::from(&rocket).unwrap(),
+            ),
+            Ok("confirmation_token".into())
+        );
+
+        let fairing = bot().fairing("/vk");
+        let rocket = fairing.on_attach(rocket::ignite()).ok().unwrap();
+        assert!(State::<Bot>::from(&rocket).is_some());
+        assert!(fairing.on_attach(rocket::ignite()).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "rocket")]
+    fn post_maps_errors_to_status() {
+        use crate::{
+            core::Handler,
+            pool::{Backpressure, PoolConfig},
+            Event,
+        };
+        use std::sync::mpsc;
+
+        let (resume_tx, resume_rx) = mpsc::channel::<()>();
+        let resume_rx = Mutex::new(resume_rx);
+        let core = Core::new().on(
+            Event::MessageTypingState,
+            Handler::new(move |_| {
+                // Blocks until `resume_tx` is dropped, so that the pool fills up.
+                let _ = resume_rx.lock().unwrap().recv();
+            }),
+        );
+        let pool = PoolConfig::new()
+            .workers(1)
+            .capacity(1)
+            .backpressure(Backpressure::Reject);
+        let bot = Bot::new(
+            "vk_token",
+            "confirmation_token",
+            1,
+            Some("secret".into()),
+            12345,
+            core,
         )
+        .with_worker_pool(pool);
+
+        let rocket = rocket::ignite().manage(bot);
+        let send = |data: Value| post(Json(data), State::from(&rocket).unwrap());
+
+        assert_eq!(
+            send(json!({"secret": "wrong_secret", "group_id": 1, "type": "confirmation"})),
+            Err(Status::Forbidden)
+        );
+        assert_eq!(send(json!({"type": 1})), Err(Status::UnprocessableEntity));
+
+        let typing = json!({
+            "secret": "secret",
+            "group_id": 1,
+            "type": "message_typing_state",
+            "object": {"state": "typing", "from_id": 7, "to_id": -1},
+        });
+        assert!((0..10).any(|_| send(typing.clone()) == Err(Status::ServiceUnavailable)));
+
+        drop(resume_tx);
     }
 
     #[test]
     fn post_invalid_secret_returns_403() {
-        assert_eq!(post_test("wrong_secret", 1, ""), Err(Status::Forbidden));
+        assert_eq!(post_test("wrong_secret", 1, ""), Err(webhook::FORBIDDEN));
     }
 
     #[test]
     fn post_invalid_group_id_returns_403() {
-        assert_eq!(post_test("secret", 1337, ""), Err(Status::Forbidden));
+        assert_eq!(post_test("secret", 1337, ""), Err(webhook::FORBIDDEN));
+    }
+
+    #[test]
+    fn handle_drops_redelivered_events() {
+        use crate::{core::Handler, request::Object, Event};
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+
+        let handled = Arc::new(AtomicUsize::new(0));
+        let core = {
+            let handled = Arc::clone(&handled);
+            Core::new().on(
+                Event::MessageTypingState,
+                Handler::new(move |_| {
+                    handled.fetch_add(1, Ordering::SeqCst);
+                }),
+            )
+        };
+        let bot = Bot::new("vk_token", "confirmation_token", 1, None, 12345, core);
+
+        let req = |event_id: Option<&str>| {
+            let req = CallbackAPIRequest::new(None, 1, "message_typing_state", Object::default());
+            match event_id {
+                Some(event_id) => req.with_event_id(event_id),
+                None => req,
+            }
+        };
+
+        bot.handle(&req(Some("a"))).unwrap();
+        bot.handle(&req(Some("a"))).unwrap();
+        bot.handle(&req(Some("b"))).unwrap();
+        assert_eq!(handled.load(Ordering::SeqCst), 2);
+
+        // Events without an ID can not be deduplicated.
+        bot.handle(&req(None)).unwrap();
+        bot.handle(&req(None)).unwrap();
+        assert_eq!(handled.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn with_api() {
+        use crate::{api::MockAPI, core::Handler, request::Object, Event};
+
+        let core = Core::new().on(
+            Event::MessageTypingState,
+            Handler::new(|ctx| {
+                ctx.response().set_message("Typing...");
+                ctx.send().unwrap();
+            }),
+        );
+        let api = Arc::new(MockAPI::new());
+        let bot = Bot::new("vk_token", "confirmation_token", 1, None, 12345, core)
+            .with_api(Arc::clone(&api));
+
+        let object = Object::new(Some(7), None, None, None, None, None, Default::default());
+        bot.handle(&CallbackAPIRequest::new(
+            None,
+            1,
+            "message_typing_state",
+            object,
+        ))
+        .unwrap();
+
+        let sent = api.calls_to("messages.send");
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].param("peer_id"), Some("7"));
+        assert_eq!(sent[0].param("message"), Some("Typing..."));
+        assert_eq!(bot.queue().metrics().sent(), 1);
+    }
+
+    #[test]
+    fn dispatch_in_background() {
+        use crate::{core::Handler, request::Object, Event};
+        use std::{
+            sync::{mpsc, Mutex},
+            time::Duration,
+        };
+
+        let (tx, rx) = mpsc::channel();
+        let (resume_tx, resume_rx) = mpsc::channel::<()>();
+        let resume_rx = Mutex::new(resume_rx);
+
+        let core = {
+            let tx = Mutex::new(tx);
+            Core::new().on(
+                Event::MessageTypingState,
+                Handler::new(move |ctx| {
+                    resume_rx.lock().unwrap().recv().unwrap();
+                    tx.lock().unwrap().send(ctx.peer_id()).unwrap();
+                }),
+            )
+        };
+        let bot = Bot::new("vk_token", "confirmation_token", 1, None, 12345, core)
+            .with_worker_pool(PoolConfig::new().workers(2));
+
+        let req = |from_id| {
+            CallbackAPIRequest::new(None, 1, "message_typing_state", {
+                Object::new(
+                    Some(from_id),
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    Default::default(),
+                )
+            })
+        };
+
+        // Returns before the handler finishes.
+        bot.dispatch(req(2)).unwrap();
+        bot.dispatch(req(3)).unwrap();
+        assert!(rx.try_recv().is_err());
+
+        resume_tx.send(()).unwrap();
+        resume_tx.send(()).unwrap();
+
+        let mut peers = vec![
+            rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+            rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+        ];
+        peers.sort();
+        assert_eq!(peers, vec![Some(2), Some(3)]);
+
+        assert!(matches!(
+            bot.dispatch(CallbackAPIRequest::new(
+                None,
+                1,
+                "unknown",
+                Object::default()
+            )),
+            Err(DispatchError::Event(_))
+        ));
+    }
+
+    #[test]
+    fn post_records_requests() {
+        let path = std::env::temp_dir().join(format!("vk-bot-post-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let bot = Bot::new(
+            "vk_token",
+            "confirmation_token",
+            1,
+            Some("secret".into()),
+            12345,
+            Default::default(),
+        )
+        .with_recorder(Recorder::create(&path).unwrap());
+
+        for secret in &["wrong_secret", "secret"] {
+            let _ = bot.respond(
+                "/",
+                &json!({"secret": secret, "group_id": 1, "type": "confirmation"}),
+            );
+        }
+
+        let records = crate::record::load(&path).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(
+            records[0].request(),
+            &json!({"group_id": 1, "type": "confirmation"})
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn post_invalid_body_returns_422() {
+        let bot = Bot::new(
+            "vk_token",
+            "confirmation_token",
+            1,
+            None,
+            12345,
+            Default::default(),
+        );
+
+        assert_eq!(
+            bot.respond("/", &json!({"type": 1})),
+            Response::error(webhook::UNPROCESSABLE_ENTITY)
+        );
+        assert_eq!(
+            bot.respond("/other", &json!({"group_id": 1, "type": "confirmation"})),
+            Response::error(webhook::NOT_FOUND)
+        );
+    }
+
+    #[test]
+    fn server_config() {
+        use std::net::Ipv6Addr;
+
+        let config = ServerConfig::new(80);
+        assert_eq!(config.address, IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        assert_eq!(config.path, "/");
+
+        let config = config
+            .address(IpAddr::V6(Ipv6Addr::LOCALHOST))
+            .port(8080)
+            .path("bots/1/");
+        assert_eq!(config.address.to_string(), "::1");
+        assert_eq!(config.port, 8080);
+        assert_eq!(config.path, "/bots/1");
+        assert_eq!(ServerConfig::new(80).path("//").path, "/");
+
+        let bot = Bot::new(
+            "vk_token",
+            "confirmation_token",
+            1,
+            None,
+            80,
+            Default::default(),
+        )
+        .with_server_config(config.clone());
+        assert_eq!(bot.server_config(), &config);
+    }
+
+    #[test]
+    fn server_config_validate() {
+        assert!(ServerConfig::new(80).validate().is_ok());
+
+        #[cfg(all(feature = "rocket", feature = "tls"))]
+        {
+            let missing = ServerConfig::new(443).tls("missing/certs.pem", "missing/key.pem");
+            assert!(matches!(missing.validate(), Err(ConfigError::Invalid(_))));
+        }
     }
 
     #[test]
@@ -0,0 +1,372 @@
+//! Building Callback API requests like VK does, and sending them to a running
+//! [`Bot`](crate::Bot), for end-to-end testing.
+//!
+//! ```no_run
+//! use vk_bot::callback::{EventSender, NewMessage};
+//!
+//! let events = EventSender::new("http://localhost:12345/", 1).secret("very_secure_phrase");
+//!
+//! assert_eq!(events.send(&events.confirmation())?, "f123456");
+//!
+//! let message = NewMessage::new(42, "/keyboard");
+//! assert_eq!(events.send(&events.message_new(&message))?, "ok");
+//! # Ok::<(), vk_bot::callback::SendEventError>(())
+//! ```
+//!
+//! See also `examples/send_event.rs`, a command line tool built on this
+//! module.
+
+use crate::{config::REDACTED, response::AttachmentInformation};
+use reqwest::{blocking::Client, header::CONTENT_TYPE};
+use rvk::objects::Integer;
+use serde_json::{json, Value};
+use std::{
+    fmt::{self, Debug, Display, Formatter},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Builds Callback API requests for a community, and `POST`s them to the URL
+/// of a [`Bot`](crate::Bot).
+///
+/// The [`Debug`] output does not contain the secret.
+pub struct EventSender {
+    url: String,
+    group_id: i32,
+    secret: Option<String>,
+    client: Client,
+    last_id: AtomicU64,
+}
+
+impl EventSender {
+    /// Creates a new [`EventSender`], which sends requests of the community
+    /// `group_id` to `url`, without a secret.
+    pub fn new(url: &str, group_id: i32) -> Self {
+        Self {
+            url: url.into(),
+            group_id,
+            secret: None,
+            client: Client::new(),
+            last_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Sets the secret sent in requests (see
+    /// [`Bot::new`](crate::Bot::new)).
+    pub fn secret(mut self, secret: &str) -> Self {
+        self.secret = Some(secret.into());
+        self
+    }
+
+    /// Returns the URL requests are sent to.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Returns the group ID sent in requests.
+    pub fn group_id(&self) -> i32 {
+        self.group_id
+    }
+
+    /// Builds a request of the given type with `object`, with a new unique
+    /// `event_id`.
+    pub fn request(&self, r#type: &str, object: Value) -> Value {
+        let mut request = json!({
+            "type": r#type,
+            "group_id": self.group_id,
+            "event_id": self.event_id(),
+            "object": object,
+        });
+
+        if let Some(secret) = &self.secret {
+            request["secret"] = secret.as_str().into();
+        }
+
+        request
+    }
+
+    /// Builds a request from `template`, which contains at least `type` (and
+    /// usually `object`). The group ID, secret and a new `event_id` are
+    /// filled in if the template does not contain them.
+    pub fn template(&self, template: &Value) -> Value {
+        let mut request = self.request(
+            template["type"].as_str().unwrap_or_default(),
+            template.get("object").cloned().unwrap_or_else(|| json!({})),
+        );
+
+        if let (Some(request), Some(template)) = (request.as_object_mut(), template.as_object()) {
+            request.extend(template.iter().map(|(k, v)| (k.clone(), v.clone())));
+        }
+
+        request
+    }
+
+    /// Builds a `confirmation` request.
+    pub fn confirmation(&self) -> Value {
+        let mut request = json!({ "type": "confirmation", "group_id": self.group_id });
+        if let Some(secret) = &self.secret {
+            request["secret"] = secret.as_str().into();
+        }
+
+        request
+    }
+
+    /// Builds a `message_new` request.
+    pub fn message_new(&self, message: &NewMessage) -> Value {
+        let id = self.last_id.fetch_add(1, Ordering::SeqCst) + 1;
+        self.request("message_new", message.to_object(id as Integer))
+    }
+
+    /// Builds a `message_allow` request from `user_id`, with the `key` given
+    /// in the subscription link, if any.
+    pub fn message_allow(&self, user_id: Integer, key: Option<&str>) -> Value {
+        let mut object = json!({ "user_id": user_id });
+        if let Some(key) = key {
+            object["key"] = key.into();
+        }
+
+        self.request("message_allow", object)
+    }
+
+    /// Builds a `message_deny` request from `user_id`.
+    pub fn message_deny(&self, user_id: Integer) -> Value {
+        self.request("message_deny", json!({ "user_id": user_id }))
+    }
+
+    /// Builds a `message_typing_state` request: `from_id` is typing a message
+    /// to the community.
+    pub fn message_typing_state(&self, from_id: Integer) -> Value {
+        self.request(
+            "message_typing_state",
+            json!({ "state": "typing", "from_id": from_id, "to_id": -i64::from(self.group_id) }),
+        )
+    }
+
+    /// `POST`s `request` to the URL, and returns the body of the response
+    /// (`ok` or the confirmation token).
+    ///
+    /// # Errors
+    /// - [`SendEventError::HTTP`] if the request failed,
+    /// - [`SendEventError::Status`] if the bot responded with an error status.
+    pub fn send(&self, request: &Value) -> Result<String, SendEventError> {
+        let response = self
+            .client
+            .post(&self.url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(request.to_string())
+            .send()?;
+
+        let status = response.status();
+        let body = response.text()?;
+
+        if status.is_success() {
+            Ok(body)
+        } else {
+            Err(SendEventError::Status(status.as_u16(), body))
+        }
+    }
+
+    fn event_id(&self) -> String {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let n = self.last_id.fetch_add(1, Ordering::SeqCst) + 1;
+
+        format!("{:x}{:x}", nanos, n)
+    }
+}
+
+impl Debug for EventSender {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("EventSender")
+            .field("url", &self.url)
+            .field("group_id", &self.group_id)
+            .field("secret", &self.secret.as_ref().map(|_| REDACTED))
+            .field("client", &self.client)
+            .field("last_id", &self.last_id)
+            .finish()
+    }
+}
+
+/// A message sent to the community, to be sent in a `message_new` request.
+#[derive(Debug, Clone)]
+pub struct NewMessage {
+    peer_id: Integer,
+    from_id: Integer,
+    text: String,
+    payload: Option<String>,
+    attachments: Vec<AttachmentInformation>,
+}
+
+impl NewMessage {
+    /// Creates a new [`NewMessage`] with `text`, sent by the user `peer_id` in
+    /// a private conversation.
+    pub fn new(peer_id: Integer, text: &str) -> Self {
+        Self {
+            peer_id,
+            from_id: peer_id,
+            text: text.into(),
+            payload: None,
+            attachments: Vec::new(),
+        }
+    }
+
+    /// Sets the sender of the message, e.g. for messages in group chats.
+    pub fn from_id(mut self, from_id: Integer) -> Self {
+        self.from_id = from_id;
+        self
+    }
+
+    /// Sets the payload of the message, as if a keyboard button was pressed.
+    pub fn payload(mut self, payload: &str) -> Self {
+        self.payload = Some(payload.into());
+        self
+    }
+
+    /// Adds an attachment to the message.
+    pub fn attach(mut self, attachment: AttachmentInformation) -> Self {
+        self.attachments.push(attachment);
+        self
+    }
+
+    /// Returns the message object with message ID `id`.
+    pub fn to_object(&self, id: Integer) -> Value {
+        let date = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        let attachments: Vec<Value> = self
+            .attachments
+            .iter()
+            .map(|attachment| {
+                let r#type = attachment.r#type().to_string();
+                let mut object = json!({
+                    "id": attachment.resource_id(),
+                    "owner_id": attachment.owner_id(),
+                });
+                if let Some(access_key) = attachment.access_key() {
+                    object["access_key"] = access_key.as_str().into();
+                }
+
+                json!({ "type": r#type, r#type: object })
+            })
+            .collect();
+
+        let mut object = json!({
+            "id": id,
+            "conversation_message_id": id,
+            "date": date,
+            "peer_id": self.peer_id,
+            "from_id": self.from_id,
+            "text": self.text,
+            "attachments": attachments,
+        });
+        if let Some(payload) = &self.payload {
+            object["payload"] = payload.as_str().into();
+        }
+
+        object
+    }
+}
+
+/// Error type for [`EventSender::send`].
+#[derive(Debug)]
+pub enum SendEventError {
+    /// The HTTP request failed.
+    HTTP(reqwest::Error),
+    /// The bot responded with the given status code and body.
+    Status(u16, String),
+}
+
+impl Display for SendEventError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            SendEventError::HTTP(e) => write!(f, "request failed: {}", e),
+            SendEventError::Status(status, body) => {
+                write!(f, "bot responded with status {}: {}", status, body)
+            }
+        }
+    }
+}
+
+impl From<reqwest::Error> for SendEventError {
+    fn from(e: reqwest::Error) -> Self {
+        SendEventError::HTTP(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{request::CallbackAPIRequest, response::AttachmentType, test_server::serve};
+
+    #[test]
+    fn message_new() {
+        let events = EventSender::new("http://localhost/", 5).secret("secret");
+        let message =
+            NewMessage::new(42, "hi")
+                .payload(r#"{"a":"b"}"#)
+                .attach(AttachmentInformation::new(
+                    AttachmentType::Photo,
+                    1,
+                    2,
+                    Some("key".into()),
+                ));
+
+        let request = events.message_new(&message);
+        assert_eq!(request["secret"], "secret");
+
+        let req: CallbackAPIRequest = serde_json::from_value(request).unwrap();
+        assert_eq!(req.group_id(), 5);
+        assert_eq!(req.r#type(), "message_new");
+        assert!(req.event_id().is_some());
+
+        let msg = req.object().message().expect("not a message");
+        assert_eq!(msg.text(), "hi");
+        assert_eq!(msg.peer_id(), Some(42));
+        assert_eq!(msg.payload().as_deref(), Some(r#"{"a":"b"}"#));
+        assert_eq!(msg.attachments().len(), 1);
+    }
+
+    #[test]
+    fn other_events() {
+        let events = EventSender::new("http://localhost/", 5);
+
+        assert_eq!(
+            events.confirmation(),
+            json!({"type": "confirmation", "group_id": 5})
+        );
+        assert!(events.message_allow(1, None).get("secret").is_none());
+        assert_eq!(events.message_allow(1, Some("k"))["object"]["key"], "k");
+        assert_eq!(events.message_deny(1)["object"]["user_id"], 1);
+        assert_eq!(events.message_typing_state(1)["object"]["to_id"], -5);
+
+        let request = events.template(&json!({"type": "wall_reply_new", "object": {"id": 3}}));
+        assert_eq!(request["group_id"], 5);
+        assert_eq!(request["object"]["id"], 3);
+        assert_ne!(
+            request["event_id"],
+            events.template(&json!({"type": "x"}))["event_id"]
+        );
+    }
+
+    #[test]
+    fn send() -> Result<(), SendEventError> {
+        let (url, server) = serve("/", "ok");
+
+        let events = EventSender::new(&url, 1).secret("secret");
+        assert_eq!(events.send(&events.message_deny(7))?, "ok");
+
+        let received = server.join().expect("server panicked");
+        assert!(received
+            .headers
+            .contains(&"content-type: application/json".to_string()));
+        let body: Value = serde_json::from_str(&received.body).unwrap();
+        assert_eq!(body["type"], "message_deny");
+        assert_eq!(body["secret"], "secret");
+
+        Ok(())
+    }
+}
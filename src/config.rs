@@ -0,0 +1,598 @@
+//! [`BotConfig`], the settings of a [`Bot`](crate::Bot), which can be loaded
+//! from a TOML file or environment variables.
+//!
+//! ```toml
+//! vk_token = "..."
+//! confirmation_token = "f123456"
+//! group_id = 1
+//! secret = "very_secure_phrase"  # optional
+//! port = 12345                   # optional, 8000 by default
+//! address = "127.0.0.1"          # optional, 0.0.0.0 by default
+//! path = "/bots/1"               # optional, / by default
+//! tls_certs = "certs.pem"        # optional, requires the `tls` feature
+//! tls_key = "key.pem"            # optional, requires the `tls` feature
+//! ```
+//!
+//! The respective environment variables are `VK_TOKEN`,
+//! `VK_CONFIRMATION_TOKEN`, `VK_GROUP_ID`, `VK_SECRET`, `VK_PORT`,
+//! `VK_ADDRESS`, `VK_PATH`, `VK_TLS_CERTS` and `VK_TLS_KEY`.
+//!
+//! ```no_run
+//! use vk_bot::{config::BotConfig, Bot, Core};
+//!
+//! let config = BotConfig::from_file("bot.toml")?.with_env()?;
+//! let bot = Bot::builder().config(config).core(Core::new()).build()?;
+//! # Ok::<(), vk_bot::config::ConfigError>(())
+//! ```
+
+use crate::{
+    bot::{Bot, ServerConfig},
+    core::Core,
+};
+use serde_derive::Deserialize;
+use std::{
+    env,
+    fmt::{self, Debug, Display, Formatter},
+    fs, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// Port a [`Bot`](crate::Bot) listens on if none is configured.
+pub const DEFAULT_PORT: u16 = 8000;
+
+/// What is shown instead of secrets in [`Debug`] output.
+pub(crate) const REDACTED: &str = "<redacted>";
+
+/// Settings of a [`Bot`](crate::Bot). See the
+/// [module-level documentation](self).
+///
+/// The [`Debug`] output does not contain the VK token, confirmation token and
+/// secret.
+#[derive(Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BotConfig {
+    #[serde(default)]
+    vk_token: String,
+    #[serde(default)]
+    confirmation_token: String,
+    #[serde(default)]
+    group_id: i32,
+    secret: Option<String>,
+    #[serde(default = "default_port")]
+    port: u16,
+    address: Option<IpAddr>,
+    path: Option<String>,
+    tls_certs: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+}
+
+fn default_port() -> u16 {
+    DEFAULT_PORT
+}
+
+impl Default for BotConfig {
+    fn default() -> Self {
+        Self {
+            vk_token: String::new(),
+            confirmation_token: String::new(),
+            group_id: 0,
+            secret: None,
+            port: DEFAULT_PORT,
+            address: None,
+            path: None,
+            tls_certs: None,
+            tls_key: None,
+        }
+    }
+}
+
+impl BotConfig {
+    /// Creates a new [`BotConfig`] with the required settings.
+    pub fn new(vk_token: &str, confirmation_token: &str, group_id: i32) -> Self {
+        Self {
+            vk_token: vk_token.into(),
+            confirmation_token: confirmation_token.into(),
+            group_id,
+            ..Default::default()
+        }
+    }
+
+    /// Parses a [`BotConfig`] from TOML.
+    ///
+    /// # Errors
+    /// - [`ConfigError::TOML`] if `toml` is not a valid configuration.
+    pub fn from_toml(toml: &str) -> Result<Self, ConfigError> {
+        toml::from_str(toml).map_err(ConfigError::TOML)
+    }
+
+    /// Reads a [`BotConfig`] from the TOML file at `path`.
+    ///
+    /// # Errors
+    /// - [`ConfigError::IO`] if the file could not be read,
+    /// - [`ConfigError::TOML`] if it is not a valid configuration.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        Self::from_toml(&fs::read_to_string(path)?)
+    }
+
+    /// Reads a [`BotConfig`] from environment variables.
+    ///
+    /// # Errors
+    /// - [`ConfigError::Env`] if a variable has an invalid value.
+    pub fn from_env() -> Result<Self, ConfigError> {
+        Self::default().with_env()
+    }
+
+    /// Overrides the settings for which environment variables are set.
+    ///
+    /// # Errors
+    /// - [`ConfigError::Env`] if a variable has an invalid value.
+    pub fn with_env(self) -> Result<Self, ConfigError> {
+        self.with_vars(|name| env::var(name).ok())
+    }
+
+    /// Overrides the settings for which `var` returns a value.
+    fn with_vars<F>(mut self, var: F) -> Result<Self, ConfigError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        fn parse<T: FromStr>(name: &'static str, value: String) -> Result<T, ConfigError> {
+            value.parse().map_err(|_| ConfigError::Env(name, value))
+        }
+
+        if let Some(value) = var("VK_TOKEN") {
+            self.vk_token = value;
+        }
+        if let Some(value) = var("VK_CONFIRMATION_TOKEN") {
+            self.confirmation_token = value;
+        }
+        if let Some(value) = var("VK_GROUP_ID") {
+            self.group_id = parse("VK_GROUP_ID", value)?;
+        }
+        if let Some(value) = var("VK_SECRET") {
+            self.secret = Some(value);
+        }
+        if let Some(value) = var("VK_PORT") {
+            self.port = parse("VK_PORT", value)?;
+        }
+        if let Some(value) = var("VK_ADDRESS") {
+            self.address = Some(parse("VK_ADDRESS", value)?);
+        }
+        if let Some(value) = var("VK_PATH") {
+            self.path = Some(value);
+        }
+        if let Some(value) = var("VK_TLS_CERTS") {
+            self.tls_certs = Some(value.into());
+        }
+        if let Some(value) = var("VK_TLS_KEY") {
+            self.tls_key = Some(value.into());
+        }
+
+        Ok(self)
+    }
+
+    /// Sets the VK token.
+    pub fn vk_token(mut self, vk_token: &str) -> Self {
+        self.vk_token = vk_token.into();
+        self
+    }
+
+    /// Sets the confirmation token (from Callback API settings).
+    pub fn confirmation_token(mut self, confirmation_token: &str) -> Self {
+        self.confirmation_token = confirmation_token.into();
+        self
+    }
+
+    /// Sets the group ID.
+    pub fn group_id(mut self, group_id: i32) -> Self {
+        self.group_id = group_id;
+        self
+    }
+
+    /// Sets the secret (from Callback API settings).
+    pub fn secret(mut self, secret: &str) -> Self {
+        self.secret = Some(secret.into());
+        self
+    }
+
+    /// Sets the port to listen on.
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Sets the address to listen on, see [`ServerConfig::address`].
+    pub fn address(mut self, address: IpAddr) -> Self {
+        self.address = Some(address);
+        self
+    }
+
+    /// Sets the path of the Callback API route, see [`ServerConfig::path`].
+    pub fn path(mut self, path: &str) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    /// Sets the TLS certificate chain and private key files, see
+    /// `ServerConfig::tls` (requires the `tls` feature).
+    pub fn tls<C: Into<PathBuf>, K: Into<PathBuf>>(mut self, certs: C, key: K) -> Self {
+        self.tls_certs = Some(certs.into());
+        self.tls_key = Some(key.into());
+        self
+    }
+
+    /// Checks that the configuration is complete and valid, including that
+    /// the TLS files (if any) exist.
+    ///
+    /// # Errors
+    /// - [`ConfigError::Invalid`] describing the first problem found.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |message: &str| Err(ConfigError::Invalid(message.into()));
+
+        if self.vk_token.trim().is_empty() {
+            return invalid("`vk_token` is missing");
+        }
+        if self.confirmation_token.trim().is_empty() {
+            return invalid("`confirmation_token` is missing");
+        }
+        if self.group_id <= 0 {
+            return invalid("`group_id` is missing or not positive");
+        }
+        if self.secret.as_deref() == Some("") {
+            return invalid("`secret` is empty");
+        }
+        if self.port == 0 {
+            return invalid("`port` is 0");
+        }
+
+        match (&self.tls_certs, &self.tls_key) {
+            (None, None) => Ok(()),
+            (Some(_), Some(_)) if !cfg!(feature = "tls") => {
+                invalid("TLS requires the `tls` feature")
+            }
+            (Some(certs), Some(key)) => {
+                for file in &[certs, key] {
+                    if !file.is_file() {
+                        return invalid(&format!("TLS file `{}` does not exist", file.display()));
+                    }
+                }
+                Ok(())
+            }
+            _ => invalid("both `tls_certs` and `tls_key` are required for TLS"),
+        }
+    }
+
+    /// Returns the [`ServerConfig`] described by this configuration.
+    pub fn server_config(&self) -> ServerConfig {
+        let mut server = ServerConfig::new(self.port);
+        if let Some(address) = self.address {
+            server = server.address(address);
+        }
+        if let Some(path) = &self.path {
+            server = server.path(path);
+        }
+
+        #[cfg(feature = "tls")]
+        {
+            if let (Some(certs), Some(key)) = (&self.tls_certs, &self.tls_key) {
+                server = server.tls(certs.clone(), key.clone());
+            }
+        }
+
+        server
+    }
+}
+
+impl Debug for BotConfig {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("BotConfig")
+            .field("vk_token", &REDACTED)
+            .field("confirmation_token", &REDACTED)
+            .field("group_id", &self.group_id)
+            .field("secret", &self.secret.as_ref().map(|_| REDACTED))
+            .field("port", &self.port)
+            .field("address", &self.address)
+            .field("path", &self.path)
+            .field("tls_certs", &self.tls_certs)
+            .field("tls_key", &self.tls_key)
+            .finish()
+    }
+}
+
+/// Builds a [`Bot`] with named settings, see [`Bot::builder`].
+///
+/// ```no_run
+/// use vk_bot::{Bot, Core};
+///
+/// let bot = Bot::builder()
+///     .vk_token("...")
+///     .confirmation_token("f123456")
+///     .group_id(1)
+///     .secret("very_secure_phrase")
+///     .port(12345)
+///     .core(Core::new())
+///     .build()?;
+/// # Ok::<(), vk_bot::config::ConfigError>(())
+/// ```
+#[derive(Debug, Default)]
+pub struct BotBuilder {
+    config: BotConfig,
+    core: Core,
+}
+
+impl BotBuilder {
+    /// Creates a new [`BotBuilder`] with a default [`BotConfig`] and an empty
+    /// [`Core`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces all settings with `config`, e.g. one loaded with
+    /// [`BotConfig::from_file`].
+    pub fn config(mut self, config: BotConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Sets the [`Core`] the bot hands received requests to.
+    pub fn core(mut self, core: Core) -> Self {
+        self.core = core;
+        self
+    }
+
+    /// See [`BotConfig::vk_token`].
+    pub fn vk_token(self, vk_token: &str) -> Self {
+        self.map_config(|config| config.vk_token(vk_token))
+    }
+
+    /// See [`BotConfig::confirmation_token`].
+    pub fn confirmation_token(self, confirmation_token: &str) -> Self {
+        self.map_config(|config| config.confirmation_token(confirmation_token))
+    }
+
+    /// See [`BotConfig::group_id`].
+    pub fn group_id(self, group_id: i32) -> Self {
+        self.map_config(|config| config.group_id(group_id))
+    }
+
+    /// See [`BotConfig::secret`].
+    pub fn secret(self, secret: &str) -> Self {
+        self.map_config(|config| config.secret(secret))
+    }
+
+    /// See [`BotConfig::port`].
+    pub fn port(self, port: u16) -> Self {
+        self.map_config(|config| config.port(port))
+    }
+
+    /// See [`BotConfig::address`].
+    pub fn address(self, address: IpAddr) -> Self {
+        self.map_config(|config| config.address(address))
+    }
+
+    /// See [`BotConfig::path`].
+    pub fn path(self, path: &str) -> Self {
+        self.map_config(|config| config.path(path))
+    }
+
+    /// See [`BotConfig::tls`].
+    pub fn tls<C: Into<PathBuf>, K: Into<PathBuf>>(self, certs: C, key: K) -> Self {
+        self.map_config(|config| config.tls(certs, key))
+    }
+
+    /// Validates the settings, and creates the [`Bot`].
+    ///
+    /// # Errors
+    /// - [`ConfigError::Invalid`] if the settings are incomplete or invalid,
+    ///   see [`BotConfig::validate`] and [`ServerConfig::validate`].
+    pub fn build(self) -> Result<Bot, ConfigError> {
+        let config = self.config;
+        config.validate()?;
+        let server = config.server_config();
+        server.validate()?;
+
+        Ok(Bot::new(
+            &config.vk_token,
+            &config.confirmation_token,
+            config.group_id,
+            config.secret.clone(),
+            config.port,
+            self.core,
+        )
+        .with_server_config(server))
+    }
+
+    fn map_config<F: FnOnce(BotConfig) -> BotConfig>(mut self, f: F) -> Self {
+        self.config = f(self.config);
+        self
+    }
+}
+
+/// Error type for loading and validating a [`BotConfig`].
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The configuration file could not be read.
+    IO(io::Error),
+    /// The configuration file is not valid.
+    TOML(toml::de::Error),
+    /// The environment variable with the given name has an invalid value.
+    Env(&'static str, String),
+    /// The configuration is incomplete or invalid.
+    Invalid(String),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            ConfigError::IO(e) => write!(f, "failed to read configuration: {}", e),
+            ConfigError::TOML(e) => write!(f, "invalid configuration: {}", e),
+            ConfigError::Env(name, value) => write!(f, "invalid `{}`: `{}`", name, value),
+            ConfigError::Invalid(message) => write!(f, "invalid configuration: {}", message),
+        }
+    }
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(e: io::Error) -> Self {
+        ConfigError::IO(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        api::{MockAPI, VkAPI},
+        callback::EventSender,
+        response::Response,
+    };
+    use rvk::{error::Error, Params};
+    use serde_json::Value;
+    use std::collections::HashMap;
+
+    /// A [`VkAPI`] which prints its token in its [`Debug`] output, like
+    /// `rvk::APIClient`.
+    #[derive(Debug)]
+    struct TokenAPI(#[allow(dead_code)] &'static str, MockAPI);
+
+    impl VkAPI for TokenAPI {
+        fn call_method(&self, method: &str, params: Params) -> Result<Value, Error> {
+            self.1.call_method(method, params)
+        }
+    }
+
+    #[test]
+    fn from_toml() -> Result<(), ConfigError> {
+        let config = BotConfig::from_toml(
+            r#"
+            vk_token = "token"
+            confirmation_token = "f123456"
+            group_id = 1
+            secret = "secret"
+            address = "::1"
+            path = "bots/1"
+            "#,
+        )?;
+
+        config.validate()?;
+        assert_eq!(config.vk_token, "token");
+        assert_eq!(config.secret.as_deref(), Some("secret"));
+        assert_eq!(
+            config.server_config(),
+            ServerConfig::new(DEFAULT_PORT)
+                .address("::1".parse().unwrap())
+                .path("/bots/1")
+        );
+
+        assert!(matches!(
+            BotConfig::from_toml("token = 1"),
+            Err(ConfigError::TOML(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn with_vars() -> Result<(), ConfigError> {
+        let vars: HashMap<_, _> = vec![
+            ("VK_TOKEN", "env_token"),
+            ("VK_GROUP_ID", "2"),
+            ("VK_PORT", "8080"),
+        ]
+        .into_iter()
+        .collect();
+        let var = |name: &str| vars.get(name).map(|value| value.to_string());
+
+        let config = BotConfig::new("token", "f123456", 1).with_vars(var)?;
+        assert_eq!(config.vk_token, "env_token");
+        assert_eq!(config.confirmation_token, "f123456");
+        assert_eq!(config.group_id, 2);
+        assert_eq!(config.port, 8080);
+
+        assert!(matches!(
+            BotConfig::default().with_vars(|_| Some("x".into())),
+            Err(ConfigError::Env("VK_GROUP_ID", _))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn validate() {
+        let valid = BotConfig::new("token", "f123456", 1);
+        assert!(valid.validate().is_ok());
+
+        for invalid in &[
+            BotConfig::default(),
+            valid.clone().vk_token(" "),
+            valid.clone().group_id(-1),
+            valid.clone().secret(""),
+            valid.clone().port(0),
+        ] {
+            assert!(matches!(invalid.validate(), Err(ConfigError::Invalid(_))));
+        }
+
+        // Any existing files will do.
+        let file = concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml");
+        let mut tls = valid.clone().tls(file, file);
+        assert_eq!(tls.validate().is_ok(), cfg!(feature = "tls"));
+        tls.tls_key = None;
+        assert!(tls.validate().is_err());
+        assert!(valid.tls(file, "missing.pem").validate().is_err());
+    }
+
+    #[test]
+    fn builder() {
+        let bot = Bot::builder()
+            .vk_token("token")
+            .confirmation_token("f123456")
+            .group_id(1)
+            .secret("secret")
+            .path("bots/1")
+            .build()
+            .unwrap();
+
+        assert_eq!(bot.confirmation_token(), "f123456");
+        assert_eq!(bot.secret().as_deref(), Some("secret"));
+        assert_eq!(
+            bot.server_config(),
+            &ServerConfig::new(DEFAULT_PORT).path("/bots/1")
+        );
+
+        assert!(matches!(
+            Bot::builder().vk_token("token").build(),
+            Err(ConfigError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn debug_redacts_secrets() {
+        let config =
+            BotConfig::new("vk_token_value", "confirmation_value", 1).secret("secret_value");
+        let debug = format!("{:?}", config);
+
+        assert!(!debug.contains("_value"), "{}", debug);
+        assert!(debug.contains("group_id: 1"));
+
+        let bot = Bot::builder()
+            .config(config)
+            .build()
+            .unwrap()
+            .with_api(TokenAPI("vk_token_value", MockAPI::new()));
+        let debug = format!("{:?}", bot);
+        assert!(!debug.contains("_value"), "{}", debug);
+
+        let sender = bot.sender();
+        let debug = format!("{:?}", sender);
+        assert!(!debug.contains("_value"), "{}", debug);
+
+        let mut response = Response::new();
+        response.set_message("hi");
+        let sent = sender.send(2, &response).unwrap();
+        let debug = format!("{:?}", sent);
+        assert!(!debug.contains("_value"), "{}", debug);
+
+        let events = EventSender::new("http://localhost/", 1).secret("secret_value");
+        let debug = format!("{:?}", events);
+        assert!(!debug.contains("_value"), "{}", debug);
+    }
+}
@@ -1,42 +1,75 @@
 //! The [`Context`] struct.
 
 use crate::{
+    api::VkAPI,
     core::Event,
+    message::{Coordinates, Geo, Message, Place},
+    queue::{Priority, Queue, Queued},
     request::{CallbackAPIRequest, Object},
-    response::Response,
+    response::{AttachmentInformation, LongMessage, Response, MAX_ATTACHMENTS, MAX_MESSAGE_LENGTH},
+    upload::{self, UploadError},
 };
-use rvk::{error::Error, methods::messages, objects::Integer, APIClient, Params};
+use rvk::{error::Error, objects::Integer, Params};
+use serde_json::Value;
+use std::{
+    cell::Cell,
+    collections::{HashMap, HashSet},
+    fmt::{self, Debug, Display, Formatter},
+};
+
+/// Maximum number of recipients of one `messages.send` call with `peer_ids`.
+pub const MAX_PEER_IDS: usize = 100;
 
 /// Stores information necessary for handlers, allows to send the resulting
 /// message.
-#[derive(Debug)]
 pub struct Context<'api> {
     group_id: i32,
     event: Event,
     object: Object,
-    api: &'api APIClient,
-    peer_id: Integer,
+    api: &'api dyn VkAPI,
+    queue: Option<&'api Queue>,
+    peer_id: Option<Integer>,
     response: Response,
+    random_ids: RandomIds,
 }
 
 impl<'api> Context<'api> {
     /// Creates a new [`Context`].
     ///
-    /// # Panics
-    /// - no user_id on object (only [`Event::MessageAllow`])
-    /// - no from_id on object ([`Event::MessageTypingState`])
-    /// - no peer_id on object (other events)
-    pub fn new(event: Event, req: &CallbackAPIRequest, api: &'api APIClient) -> Self {
+    /// The peer to reply to is taken from:
+    /// - `user_id` for [`Event::MessageAllow`] and [`Event::MessageDeny`],
+    /// - `from_id` for [`Event::MessageTypingState`],
+    /// - `peer_id` for other events.
+    ///
+    /// If the respective field is missing, the [`Context`] has no peer, and
+    /// [`Context::send`] will return [`SendError::NoPeer`].
+    ///
+    /// The `random_id` seed (see [`Context::set_random_id_seed`]) is the
+    /// `event_id` of the request, or, if it is missing, the event type, group
+    /// ID, peer ID and `conversation_message_id` of the message.
+    pub fn new(event: Event, req: &CallbackAPIRequest, api: &'api dyn VkAPI) -> Self {
         let object = req.object();
 
-        let peer_id = match event {
-            Event::MessageAllow => object
-                .user_id()
-                .expect("no user_id on message_allow object"),
-            Event::MessageTypingState => object
-                .get_from_id()
-                .expect("no from_id on message_typing_state object"),
-            _ => object.peer_id().expect("no peer_id on object"),
+        let peer_id = event_peer_id(event, object);
+
+        if peer_id.is_none() {
+            debug!("no peer to reply to for event `{}`", event);
+        }
+
+        let seed = match req.event_id() {
+            Some(event_id) => Some(format!("event:{}", event_id)),
+            None => object.message().and_then(|msg| {
+                match (msg.peer_id(), msg.conversation_message_id()) {
+                    (Some(peer_id), Some(id)) => Some(format!(
+                        "message:{}:{}:{}:{}",
+                        event,
+                        req.group_id(),
+                        peer_id,
+                        id
+                    )),
+                    _ => None,
+                }
+            }),
         };
 
         Self {
@@ -44,8 +77,10 @@ impl<'api> Context<'api> {
             event,
             object: object.clone(),
             api,
+            queue: None,
             peer_id,
             response: Response::new(),
+            random_ids: RandomIds::new(seed),
         }
     }
 
@@ -65,9 +100,92 @@ impl<'api> Context<'api> {
         &self.object
     }
 
-    /// Returns the global [`rvk::APIClient`] which is used in this bot.
-    pub fn api(&self) -> &APIClient {
-        &self.api
+    /// Returns the typed message associated with the event, if the event is
+    /// about a message. Alias for `self.object().message()`.
+    pub fn message(&self) -> Option<&Message> {
+        self.object.message()
+    }
+
+    /// Returns the location attached to the message, if any (e.g. when a
+    /// [`Button::location`](crate::keyboard::Button::location) was pressed).
+    pub fn geo(&self) -> Option<&Geo> {
+        self.message().and_then(|msg| msg.geo().as_ref())
+    }
+
+    /// Returns the coordinates of the location attached to the message, if
+    /// any.
+    pub fn coordinates(&self) -> Option<Coordinates> {
+        self.geo().map(Geo::coordinates)
+    }
+
+    /// Returns the place information of the location attached to the message,
+    /// if present.
+    pub fn place(&self) -> Option<&Place> {
+        self.geo().and_then(|geo| geo.place().as_ref())
+    }
+
+    /// Returns the ID of the peer that responses will be sent to, if there is
+    /// one.
+    pub fn peer_id(&self) -> Option<Integer> {
+        self.peer_id
+    }
+
+    /// Returns the [`VkAPI`] which is used in this bot (an
+    /// [`rvk::APIClient`], unless another one was given).
+    pub fn api(&self) -> &'api dyn VkAPI {
+        self.api
+    }
+
+    /// Returns the outbound [`Queue`] VK API calls are made through, if any.
+    pub fn queue(&self) -> Option<&'api Queue> {
+        self.queue
+    }
+
+    /// Sets the outbound [`Queue`] to make VK API calls (sending, editing,
+    /// deleting and pinning messages, and uploading files) through, instead
+    /// of making them immediately.
+    pub fn set_queue(&mut self, queue: &'api Queue) {
+        self.queue = Some(queue);
+    }
+
+    /// Returns the seed `random_id`s of messages sent from this [`Context`]
+    /// are derived from, if any.
+    pub fn random_id_seed(&self) -> Option<&str> {
+        self.random_ids.seed()
+    }
+
+    /// Sets the seed `random_id`s of messages sent from this [`Context`] are
+    /// derived from (see [`random_id`]), or makes them random if `seed` is
+    /// [`None`].
+    ///
+    /// By default, the seed is derived from the event (see [`Context::new`]),
+    /// so that if VK redelivers an event, the messages sent in response to it
+    /// have the same `random_id`s as the first time, and VK does not send them
+    /// again. The `n`th message sent from this [`Context`] (counting from `0`,
+    /// and counting each part of a split response) gets
+    /// `random_id(seed, n)`.
+    pub fn set_random_id_seed(&mut self, seed: Option<String>) {
+        self.random_ids = RandomIds::new(seed);
+    }
+
+    /// Returns a [`Sender`] which can send messages to arbitrary peers (through
+    /// the [`Queue`] of this [`Context`], if any).
+    ///
+    /// Messages sent by the [`Sender`] get random `random_id`s, unless a seed
+    /// is set via [`Sender::set_random_id_seed`]; use [`Context::send_to`] and
+    /// [`Context::send_many`] to use the seed of this [`Context`].
+    pub fn sender(&self) -> Sender<'api> {
+        Sender {
+            api: self.api,
+            queue: self.queue,
+            random_ids: Default::default(),
+        }
+    }
+
+    /// Returns a [`VkAPI`] which makes calls through the [`Queue`] of this
+    /// [`Context`], if any.
+    fn queued(&self) -> Queued<'api> {
+        Queued::new(self.api, self.queue)
     }
 
     /// Returns the current pending response object (mutable).
@@ -75,49 +193,1400 @@ impl<'api> Context<'api> {
         &mut self.response
     }
 
-    /// Sends the response.
+    /// Uploads a photo for the peer of this [`Context`], which can then be
+    /// [attached](Response::attach) to the response.
+    ///
+    /// See [`upload::upload_photo`].
+    pub fn upload_photo(
+        &self,
+        file_name: &str,
+        bytes: Vec<u8>,
+    ) -> Result<AttachmentInformation, UploadError> {
+        upload::upload_photo(&self.queued(), self.peer_id, file_name, bytes)
+    }
+
+    /// Uploads a document for the peer of this [`Context`], which can then be
+    /// [attached](Response::attach) to the response.
+    ///
+    /// See [`upload::upload_document`].
+    pub fn upload_document(
+        &self,
+        file_name: &str,
+        bytes: Vec<u8>,
+        title: Option<&str>,
+    ) -> Result<AttachmentInformation, UploadError> {
+        let peer_id = self.peer_id.ok_or(UploadError::NoPeer)?;
+        upload::upload_document(&self.queued(), peer_id, file_name, bytes, title)
+    }
+
+    /// Uploads a voice message for the peer of this [`Context`], which can
+    /// then be [attached](Response::attach) to the response.
+    ///
+    /// See [`upload::upload_audio_message`].
+    pub fn upload_audio_message(
+        &self,
+        file_name: &str,
+        bytes: Vec<u8>,
+    ) -> Result<AttachmentInformation, UploadError> {
+        let peer_id = self.peer_id.ok_or(UploadError::NoPeer)?;
+        upload::upload_audio_message(&self.queued(), peer_id, file_name, bytes)
+    }
+
+    /// Sends the response, and returns a handle to the sent message, which can
+    /// be used to edit, delete or pin it.
     ///
     /// This does not erase the response object. You can send multiple messages.
     ///
-    /// This method currently blocks until the [`rvk::APIClient`] is available,
-    /// so only one message is being sent at a given time. This behavior may
-    /// change.
-    pub fn send(&self) -> Result<(), Error> {
-        let mut params = Params::new();
+    /// If the response has more than [`MAX_ATTACHMENTS`] attachments, or its
+    /// message is longer than [`MAX_MESSAGE_LENGTH`] (and
+    /// [`LongMessage::Split`] is used), it is split into several messages.
+    /// Parts of the text are sent first, then the attachments; the keyboard is
+    /// sent with the last message.
+    ///
+    /// If this [`Context`] has a [`Queue`] (which is the case for handlers
+    /// run by [`Bot`](crate::Bot)), the messages are sent through it with the
+    /// response's [`Priority`], and this method blocks until they are sent.
+    ///
+    /// # Errors
+    /// - [`SendError::NoPeer`] if there is nobody to reply to (see
+    ///   [`Context::new`]),
+    /// - [`SendError::MessageTooLong`] if the message is too long, and
+    ///   [`LongMessage::Fail`] is used,
+    /// - [`SendError::API`] if VK API returned an error. If the response was
+    ///   split into several messages, the remaining ones are not sent.
+    pub fn send(&self) -> Result<SentMessage<'api>, SendError> {
+        let sender = self.sender();
+        let priority = self.response.priority();
+        self.send_with(|params| sender.call(priority, params))
+    }
+
+    /// Sends the response, using `send` to call `messages.send`.
+    fn send_with<F>(&self, send: F) -> Result<SentMessage<'api>, SendError>
+    where
+        F: FnMut(Params) -> Result<Value, Error>,
+    {
+        let peer_id = self.peer_id.ok_or(SendError::NoPeer)?;
+        send_with(
+            self.api,
+            self.queue,
+            peer_id,
+            &self.response,
+            &self.random_ids,
+            send,
+        )
+    }
+
+    /// Sends `response` (instead of the pending one) to `peer_id` (instead of
+    /// the peer of this [`Context`]). See [`Sender::send`].
+    pub fn send_to(
+        &self,
+        peer_id: Integer,
+        response: &Response,
+    ) -> Result<SentMessage<'api>, SendError> {
+        let sender = self.sender();
+        let priority = response.priority();
+        send_with(
+            self.api,
+            self.queue,
+            peer_id,
+            response,
+            &self.random_ids,
+            |params| sender.call(priority, params),
+        )
+    }
+
+    /// Sends the pending response to each of `peer_ids` (instead of the peer
+    /// of this [`Context`]). See [`Sender::send_many`].
+    pub fn send_many(&self, peer_ids: &[Integer]) -> Result<Multicast<'api>, SendError> {
+        let sender = self.sender();
+        let priority = self.response.priority();
+        send_many_with(
+            self.api,
+            self.queue,
+            peer_ids,
+            &self.response,
+            &self.random_ids,
+            |params| sender.call(priority, params),
+        )
+    }
+}
+
+impl Debug for Context<'_> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        // The API is left out, as `rvk::APIClient` would print its token.
+        f.debug_struct("Context")
+            .field("group_id", &self.group_id)
+            .field("event", &self.event)
+            .field("object", &self.object)
+            .field("queue", &self.queue)
+            .field("peer_id", &self.peer_id)
+            .field("response", &self.response)
+            .field("random_ids", &self.random_ids)
+            .finish()
+    }
+}
+
+/// Returns the ID of the peer an event is related to (see [`Context::new`]).
+pub(crate) fn event_peer_id(event: Event, object: &Object) -> Option<Integer> {
+    match event {
+        Event::MessageAllow | Event::MessageDeny => *object.user_id(),
+        Event::MessageTypingState => *object.get_from_id(),
+        _ => *object.peer_id(),
+    }
+}
+
+/// Sends [`Response`]s to arbitrary peers, both from handlers (see
+/// [`Context::sender`]) and outside of them (see
+/// [`Bot::sender`](crate::Bot::sender)).
+///
+/// Responses are split into several messages the same way as in
+/// [`Context::send`], and sent with their [`Priority`] through the [`Queue`]
+/// of the [`Sender`], if any.
+#[derive(Clone)]
+pub struct Sender<'api> {
+    api: &'api dyn VkAPI,
+    queue: Option<&'api Queue>,
+    random_ids: RandomIds,
+}
+
+impl<'api> Sender<'api> {
+    /// Creates a new [`Sender`] which sends messages immediately using `api`.
+    pub fn new(api: &'api dyn VkAPI) -> Self {
+        Self {
+            api,
+            queue: None,
+            random_ids: Default::default(),
+        }
+    }
 
-        params.insert("peer_id".into(), format!("{}", self.peer_id));
+    /// Creates a new [`Sender`] which sends messages through `queue`.
+    ///
+    /// Sent messages are also edited, deleted and pinned through `queue`.
+    pub fn with_queue(api: &'api dyn VkAPI, queue: &'api Queue) -> Self {
+        Self {
+            api,
+            queue: Some(queue),
+            random_ids: Default::default(),
+        }
+    }
+
+    /// Returns the [`VkAPI`] used by this [`Sender`].
+    pub fn api(&self) -> &'api dyn VkAPI {
+        self.api
+    }
+
+    /// Returns the [`Queue`] used by this [`Sender`], if any.
+    pub fn queue(&self) -> Option<&'api Queue> {
+        self.queue
+    }
 
-        let res = &self.response;
-        let msg = res.message();
-        let attachments = res.attachments();
-        let kbd = res.keyboard();
+    /// Returns the seed `random_id`s of messages sent by this [`Sender`] are
+    /// derived from, if any.
+    pub fn random_id_seed(&self) -> Option<&str> {
+        self.random_ids.seed()
+    }
 
-        if !msg.is_empty() {
-            params.insert("message".into(), msg.clone());
+    /// Sets the seed `random_id`s of messages sent by this [`Sender`] are
+    /// derived from (see [`random_id`]), or makes them random (the default) if
+    /// `seed` is [`None`].
+    ///
+    /// With a seed, sending the same messages again (e.g. after a restart)
+    /// with a new [`Sender`] with the same seed does not deliver them twice.
+    pub fn set_random_id_seed(&mut self, seed: Option<String>) {
+        self.random_ids = RandomIds::new(seed);
+    }
+
+    /// Calls `messages.send`, through the queue if there is one.
+    fn call(&self, priority: Priority, params: Params) -> Result<Value, Error> {
+        match self.queue {
+            Some(queue) => queue.call(priority, params),
+            None => self.api.call_method("messages.send", params),
         }
+    }
+
+    /// Sends `response` to `peer_id`, and returns a handle to the sent
+    /// message.
+    ///
+    /// # Errors
+    /// See [`Context::send`] (except [`SendError::NoPeer`]).
+    pub fn send(
+        &self,
+        peer_id: Integer,
+        response: &Response,
+    ) -> Result<SentMessage<'api>, SendError> {
+        let priority = response.priority();
+        send_with(
+            self.api,
+            self.queue,
+            peer_id,
+            response,
+            &self.random_ids,
+            |params| self.call(priority, params),
+        )
+    }
+
+    /// Sends `response` to each of `peer_ids`, using the `peer_ids` parameter
+    /// of `messages.send`, so that only one call per [`MAX_PEER_IDS`]
+    /// recipients is made for each message.
+    ///
+    /// Delivery to some peers may fail (e.g. if they did not allow messages
+    /// from the community, or if VK API returned an error for the whole call
+    /// to their chunk); they are listed in [`Multicast::failed`], and the
+    /// remaining messages (if the response is split) are not sent to them.
+    /// Other calls are still made.
+    ///
+    /// # Errors
+    /// - [`SendError::MessageTooLong`] if the message is too long, and
+    ///   [`LongMessage::Fail`] is used.
+    pub fn send_many(
+        &self,
+        peer_ids: &[Integer],
+        response: &Response,
+    ) -> Result<Multicast<'api>, SendError> {
+        let priority = response.priority();
+        send_many_with(
+            self.api,
+            self.queue,
+            peer_ids,
+            response,
+            &self.random_ids,
+            |params| self.call(priority, params),
+        )
+    }
+}
 
-        if !attachments.is_empty() {
-            params.insert(
-                "attachment".into(),
-                attachments
+impl Debug for Sender<'_> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        // The API is left out, as `rvk::APIClient` would print its token.
+        f.debug_struct("Sender")
+            .field("queue", &self.queue)
+            .field("random_ids", &self.random_ids)
+            .finish()
+    }
+}
+
+/// Generates `random_id`s for consecutive messages, either derived from a seed
+/// or random.
+#[derive(Debug, Clone, Default)]
+struct RandomIds {
+    seed: Option<String>,
+    next: Cell<u32>,
+}
+
+impl RandomIds {
+    fn new(seed: Option<String>) -> Self {
+        Self {
+            seed,
+            next: Cell::new(0),
+        }
+    }
+
+    fn seed(&self) -> Option<&str> {
+        self.seed.as_deref()
+    }
+
+    /// Returns the `random_id` for the next message.
+    fn next(&self) -> i32 {
+        let index = self.next.get();
+        self.next.set(index.wrapping_add(1));
+
+        match &self.seed {
+            Some(seed) => random_id(seed, index),
+            None => rand::random(),
+        }
+    }
+}
+
+/// Derives the `random_id` of the `index`th message sent for `seed`.
+///
+/// The result is always positive (VK ignores `random_id` if it is `0`), and
+/// stays the same across runs and builds.
+pub fn random_id(seed: &str, index: u32) -> i32 {
+    // 64-bit FNV-1a.
+    let hash = seed
+        .bytes()
+        .chain(b":".iter().copied())
+        .chain(index.to_string().bytes())
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+        });
+
+    match ((hash ^ (hash >> 32)) & 0x7fff_ffff) as i32 {
+        0 => 1,
+        id => id,
+    }
+}
+
+/// Sends `res` to `peer_id`, using `send` to call `messages.send`.
+fn send_with<'api, F>(
+    api: &'api dyn VkAPI,
+    queue: Option<&'api Queue>,
+    peer_id: Integer,
+    res: &Response,
+    random_ids: &RandomIds,
+    mut send: F,
+) -> Result<SentMessage<'api>, SendError>
+where
+    F: FnMut(Params) -> Result<Value, Error>,
+{
+    let mut message_ids = Vec::new();
+
+    for mut params in message_params(res)? {
+        params.insert("peer_id".into(), format!("{}", peer_id));
+        params.insert("random_id".into(), format!("{}", random_ids.next()));
+
+        trace!("sending message {:#?}", params);
+
+        let id = send(params)?;
+        message_ids.push(id.as_i64().ok_or(SendError::UnexpectedResponse(id))?);
+    }
+
+    Ok(SentMessage {
+        api,
+        queue,
+        peer_id,
+        message_ids,
+    })
+}
+
+/// Sends `res` to each of `peer_ids`, using `send` to call `messages.send`.
+fn send_many_with<'api, F>(
+    api: &'api dyn VkAPI,
+    queue: Option<&'api Queue>,
+    peer_ids: &[Integer],
+    res: &Response,
+    random_ids: &RandomIds,
+    mut send: F,
+) -> Result<Multicast<'api>, SendError>
+where
+    F: FnMut(Params) -> Result<Value, Error>,
+{
+    let all_params = message_params(res)?;
+
+    let mut seen = HashSet::new();
+    let peer_ids: Vec<_> = peer_ids.iter().filter(|id| seen.insert(**id)).collect();
+
+    let mut message_ids: HashMap<Integer, Vec<Integer>> = HashMap::new();
+    let mut failed = Vec::new();
+
+    for params in all_params {
+        let failed_ids: HashSet<_> = failed.iter().map(|(id, _)| *id).collect();
+        let active: Vec<Integer> = peer_ids
+            .iter()
+            .map(|id| **id)
+            .filter(|id| !failed_ids.contains(id))
+            .collect();
+
+        for chunk in active.chunks(MAX_PEER_IDS) {
+            let mut params = params.clone();
+            params.insert("peer_ids".into(), join(chunk));
+            params.insert("random_id".into(), format!("{}", random_ids.next()));
+
+            trace!("sending message {:#?}", params);
+
+            let value = match send(params) {
+                Ok(value) => value,
+                Err(e) => {
+                    debug!("failed to send message to {:?}: {:?}", chunk, e);
+                    let error = error_value(&e);
+                    failed.extend(chunk.iter().map(|&peer_id| (peer_id, error.clone())));
+                    continue;
+                }
+            };
+            let results = match value.as_array() {
+                Some(results) => results,
+                None => {
+                    debug!("unexpected response to messages.send: {}", value);
+                    failed.extend(chunk.iter().map(|&peer_id| (peer_id, value.clone())));
+                    continue;
+                }
+            };
+
+            for &peer_id in chunk {
+                let result = results
                     .iter()
-                    .map(ToString::to_string)
-                    .fold(String::new(), |acc, v| acc + "," + &v),
+                    .find(|result| result["peer_id"].as_i64() == Some(peer_id));
+
+                match result.and_then(|result| result["message_id"].as_i64()) {
+                    Some(id) => message_ids.entry(peer_id).or_default().push(id),
+                    None => {
+                        let error = result.map_or(Value::Null, |result| result["error"].clone());
+                        debug!("failed to send message to {}: {}", peer_id, error);
+                        failed.push((peer_id, error));
+                    }
+                }
+            }
+        }
+    }
+
+    let sent = peer_ids
+        .into_iter()
+        .filter_map(|&peer_id| {
+            message_ids.remove(&peer_id).map(|message_ids| SentMessage {
+                api,
+                queue,
+                peer_id,
+                message_ids,
+            })
+        })
+        .collect();
+
+    Ok(Multicast { sent, failed })
+}
+
+/// Converts an error of a whole `messages.send` call to the JSON form of the
+/// per-peer errors it returns (`{"code": ..., "description": ...}`).
+fn error_value(error: &Error) -> Value {
+    match error {
+        Error::API(e) => serde_json::json!({"code": e.code(), "description": e.msg()}),
+        e => serde_json::json!({"description": format!("{:?}", e)}),
+    }
+}
+
+/// Results of [`Sender::send_many`].
+#[derive(Debug, Clone)]
+pub struct Multicast<'api> {
+    sent: Vec<SentMessage<'api>>,
+    failed: Vec<(Integer, Value)>,
+}
+
+impl<'api> Multicast<'api> {
+    /// Returns the messages which were delivered, in the order of their peers
+    /// in `peer_ids`. If only some parts of a split response were delivered to
+    /// a peer, it is listed here with their IDs, and also in
+    /// [`Multicast::failed`].
+    pub fn sent(&self) -> &Vec<SentMessage<'api>> {
+        &self.sent
+    }
+
+    /// Returns the peers the message (or a part of it) was not delivered to,
+    /// together with the errors returned by VK API for them (or `null` if
+    /// there was none). If a whole call failed, each of its peers gets the
+    /// error of the call (`{"code": ..., "description": ...}`), or the
+    /// unexpected response.
+    pub fn failed(&self) -> &Vec<(Integer, Value)> {
+        &self.failed
+    }
+}
+
+/// A handle to a message sent via [`Context::send`] or [`Sender`], which allows
+/// to edit, delete or pin it.
+///
+/// If the response was split into several messages, this handle refers to all
+/// of them: [`SentMessage::delete`] deletes every part, while
+/// [`SentMessage::edit`] and [`SentMessage::pin`] work with the last one
+/// (which contains the keyboard).
+///
+/// If the message was sent through a [`Queue`], these calls are made through
+/// it too.
+#[derive(Clone)]
+pub struct SentMessage<'api> {
+    api: &'api dyn VkAPI,
+    queue: Option<&'api Queue>,
+    peer_id: Integer,
+    message_ids: Vec<Integer>,
+}
+
+impl<'api> SentMessage<'api> {
+    /// Returns the ID of the peer the message was sent to.
+    pub fn peer_id(&self) -> Integer {
+        self.peer_id
+    }
+
+    /// Returns the ID of the message (the last part, if the response was split
+    /// into several messages).
+    pub fn message_id(&self) -> Integer {
+        *self
+            .message_ids
+            .last()
+            .expect("at least one message is always sent")
+    }
+
+    /// Returns the IDs of all parts of the message, in the order they were
+    /// sent.
+    pub fn message_ids(&self) -> &Vec<Integer> {
+        &self.message_ids
+    }
+
+    /// Calls `method`, through the queue if there is one.
+    fn call_method(&self, method: &str, params: Params) -> Result<Value, Error> {
+        Queued::new(self.api, self.queue).call_method(method, params)
+    }
+
+    /// Replaces the text, attachments and keyboard of the message with ones of
+    /// `response` (`messages.edit`).
+    ///
+    /// # Errors
+    /// - [`SendError::NotSingleMessage`] if `response` does not fit into one
+    ///   message,
+    /// - [`SendError::API`] if VK API returned an error.
+    pub fn edit(&self, response: &Response) -> Result<(), SendError> {
+        let params = edit_params(self.peer_id, self.message_id(), response)?;
+
+        trace!("editing message {:#?}", params);
+
+        self.call_method("messages.edit", params)?;
+        Ok(())
+    }
+
+    /// Deletes the message (all parts of it) for everyone (`messages.delete`).
+    pub fn delete(&self) -> Result<(), SendError> {
+        let mut params = Params::new();
+        params.insert("message_ids".into(), join(&self.message_ids));
+        params.insert("delete_for_all".into(), "1".into());
+
+        self.call_method("messages.delete", params)?;
+        Ok(())
+    }
+
+    /// Pins the message in the conversation (`messages.pin`).
+    pub fn pin(&self) -> Result<(), SendError> {
+        let mut params = Params::new();
+        params.insert("peer_id".into(), self.peer_id.to_string());
+        params.insert("message_id".into(), self.message_id().to_string());
+
+        self.call_method("messages.pin", params)?;
+        Ok(())
+    }
+}
+
+impl Debug for SentMessage<'_> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        // The API is left out, as `rvk::APIClient` would print its token.
+        f.debug_struct("SentMessage")
+            .field("queue", &self.queue)
+            .field("peer_id", &self.peer_id)
+            .field("message_ids", &self.message_ids)
+            .finish()
+    }
+}
+
+/// Converts a [`Response`] to parameters of a `messages.edit` call.
+fn edit_params(peer_id: Integer, message_id: Integer, res: &Response) -> Result<Params, SendError> {
+    let mut all = message_params(res)?;
+    if all.len() != 1 {
+        return Err(SendError::NotSingleMessage);
+    }
+
+    let mut params = all.remove(0);
+
+    // Not supported by `messages.edit`.
+    params.remove("reply_to");
+    params.remove("forward_messages");
+    params.remove("forward");
+
+    params.insert("peer_id".into(), peer_id.to_string());
+    params.insert("message_id".into(), message_id.to_string());
+
+    Ok(params)
+}
+
+/// Joins IDs with commas.
+fn join(ids: &[Integer]) -> String {
+    ids.iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Converts a [`Response`] to parameters (without `peer_id` and `random_id`)
+/// of one or more `messages.send` calls, so that each message has at most
+/// [`MAX_MESSAGE_LENGTH`] characters and [`MAX_ATTACHMENTS`] attachments.
+///
+/// Parts of a split text are sent first, the last one together with the first
+/// [`MAX_ATTACHMENTS`] attachments. Replies and forwarded messages are sent
+/// with the first message, and the keyboard with the last one.
+fn message_params(res: &Response) -> Result<Vec<Params>, SendError> {
+    let texts = message_texts(res)?;
+    let chunks: Vec<_> = res.attachments().chunks(MAX_ATTACHMENTS).collect();
+
+    let text_only = texts.len().saturating_sub(1);
+    let count = text_only + chunks.len().max(1);
+
+    Ok((0..count)
+        .map(|i| {
+            let mut params = Params::new();
+
+            if i == 0 {
+                if let Some(reply_to) = res.reply_to() {
+                    params.insert("reply_to".into(), reply_to.to_string());
+                }
+
+                if !res.forward_messages().is_empty() {
+                    params.insert("forward_messages".into(), join(res.forward_messages()));
+                }
+
+                if let Some(forward) = res.forward() {
+                    params.insert(
+                        "forward".into(),
+                        serde_json::to_string(forward)
+                            .expect("failed to serialize forward to String"),
+                    );
+                }
+            }
+
+            if let Some(text) = texts.get(i) {
+                params.insert("message".into(), text.clone());
+            }
+
+            if let Some(chunk) = i.checked_sub(text_only).and_then(|i| chunks.get(i)) {
+                params.insert(
+                    "attachment".into(),
+                    chunk
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>()
+                        .join(","),
+                );
+            }
+
+            if i == count - 1 {
+                if let Some(kbd) = res.keyboard() {
+                    params.insert(
+                        "keyboard".into(),
+                        serde_json::to_string(&kbd)
+                            .expect("failed to serialize keyboard to String"),
+                    );
+                }
+            }
+
+            params
+        })
+        .collect())
+}
+
+/// Returns the text of each message to send for a [`Response`], according to
+/// its [`LongMessage`] setting.
+fn message_texts(res: &Response) -> Result<Vec<String>, SendError> {
+    let msg = res.message();
+    let length = msg.chars().count();
+
+    if msg.is_empty() {
+        Ok(vec![])
+    } else if length <= MAX_MESSAGE_LENGTH {
+        Ok(vec![msg.clone()])
+    } else {
+        match res.long_message() {
+            LongMessage::Split => Ok(split_text(msg, MAX_MESSAGE_LENGTH)),
+            LongMessage::Truncate => Ok(vec![truncate_text(msg, MAX_MESSAGE_LENGTH)]),
+            LongMessage::Fail => Err(SendError::MessageTooLong(length)),
+        }
+    }
+}
+
+/// Splits `text` into parts of at most `max` characters, preferring paragraph,
+/// line, sentence and word boundaries (in that order).
+fn split_text(text: &str, max: usize) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut rest = text;
+
+    while rest.chars().count() > max {
+        let limit = byte_index(rest, max);
+        let chunk = &rest[..limit];
+
+        // Do not make parts shorter than a half of the limit just to split on
+        // a "better" boundary.
+        let at = find_boundary(chunk, byte_index(chunk, max / 2)).unwrap_or(limit);
+
+        let part = rest[..at].trim_end();
+        if !part.is_empty() {
+            parts.push(part.to_string());
+        }
+
+        rest = rest[at..].trim_start();
+    }
+
+    if !rest.is_empty() {
+        parts.push(rest.to_string());
+    }
+
+    parts
+}
+
+/// Returns the byte index (not before `min`) right after the last paragraph,
+/// line, sentence or word boundary in `chunk`, trying them in that order.
+fn find_boundary(chunk: &str, min: usize) -> Option<usize> {
+    let paragraph = chunk.rfind("\n\n").map(|i| i + 2);
+    let line = chunk.rfind('\n').map(|i| i + 1);
+
+    let mut sentence = None;
+    let mut word = None;
+    let mut next_is_whitespace = false;
+
+    for (i, c) in chunk.char_indices().rev() {
+        let end = i + c.len_utf8();
+
+        if word.is_none() && c.is_whitespace() {
+            word = Some(end);
+        }
+
+        if next_is_whitespace && (c == '.' || c == '!' || c == '?' || c == '…') {
+            sentence = Some(end);
+            break;
+        }
+
+        next_is_whitespace = c.is_whitespace();
+    }
+
+    vec![paragraph, line, sentence, word]
+        .into_iter()
+        .flatten()
+        .find(|&i| i >= min)
+}
+
+/// Cuts `text` to at most `max` characters, ending it with `…` if it was cut.
+fn truncate_text(text: &str, max: usize) -> String {
+    if text.chars().count() <= max {
+        return text.into();
+    }
+
+    let mut truncated = text[..byte_index(text, max - 1)].to_string();
+    truncated.push('…');
+    truncated
+}
+
+/// Returns the byte index of the `n`th character of `s`, or the length of `s`
+/// if it is shorter.
+fn byte_index(s: &str, n: usize) -> usize {
+    s.char_indices().nth(n).map_or(s.len(), |(i, _)| i)
+}
+
+/// Error type for [`Context::send`] and [`Sender`].
+#[derive(Debug)]
+pub enum SendError {
+    /// There is no peer to send the response to, because the event that caused
+    /// the handler to run did not contain one.
+    NoPeer,
+    /// The message is longer than [`MAX_MESSAGE_LENGTH`] (contains the actual
+    /// length), and [`LongMessage::Fail`] is used.
+    MessageTooLong(usize),
+    /// The response does not fit into a single message, so it can not be used
+    /// to edit one (see [`SentMessage::edit`]).
+    NotSingleMessage,
+    /// VK API returned an unexpected response (contains the response).
+    UnexpectedResponse(Value),
+    /// VK API returned an error.
+    API(Error),
+}
+
+impl Display for SendError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            SendError::NoPeer => f.write_str("no peer to send the response to"),
+            SendError::MessageTooLong(length) => write!(
+                f,
+                "message is too long ({} characters, at most {} allowed)",
+                length, MAX_MESSAGE_LENGTH
+            ),
+            SendError::NotSingleMessage => {
+                f.write_str("response does not fit into a single message")
+            }
+            SendError::UnexpectedResponse(value) => {
+                write!(f, "unexpected VK API response: {}", value)
+            }
+            SendError::API(e) => write!(f, "VK API error: {:?}", e),
+        }
+    }
+}
+
+impl From<Error> for SendError {
+    fn from(e: Error) -> Self {
+        SendError::API(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::MockAPI;
+
+    fn context(event: Event, obj: Object, api: &dyn VkAPI) -> Context<'_> {
+        Context::new(
+            event,
+            &CallbackAPIRequest::new(Some("secret".into()), 1, &event.to_string(), obj),
+            api,
+        )
+    }
+
+    #[test]
+    fn peer_from_event_specific_field() {
+        let api = MockAPI::new();
+
+        let obj = Object::new(
+            Some(2),            // from_id
+            Some(3),            // peer_id
+            Some(4),            // user_id
+            None,               // text
+            None,               // payload
+            None,               // action
+            Default::default(), // extra fields
+        );
+
+        assert_eq!(
+            context(Event::MessageNew, obj.clone(), &api).peer_id(),
+            Some(3)
+        );
+        assert_eq!(
+            context(Event::MessageTypingState, obj.clone(), &api).peer_id(),
+            Some(2)
+        );
+        assert_eq!(
+            context(Event::MessageAllow, obj.clone(), &api).peer_id(),
+            Some(4)
+        );
+        assert_eq!(context(Event::MessageDeny, obj, &api).peer_id(), Some(4));
+    }
+
+    #[test]
+    fn send_without_peer_returns_error() {
+        let api = MockAPI::new();
+        let ctx = context(Event::MessageAllow, Default::default(), &api);
+
+        assert_eq!(ctx.peer_id(), None);
+        match ctx.send() {
+            Err(SendError::NoPeer) => {}
+            other => panic!("expected `SendError::NoPeer`, got {:?}", other),
+        }
+    }
+
+    mod send {
+        use super::*;
+        use crate::{
+            keyboard::Keyboard,
+            response::{AttachmentType, Forward},
+        };
+
+        /// Sends the response of `ctx` to a mock `messages.send`, returning
+        /// the parameters of each call.
+        fn mock_send(ctx: &Context) -> Vec<Params> {
+            let mut calls = Vec::new();
+
+            ctx.send_with(|params| {
+                calls.push(params);
+                Ok(Value::from(calls.len()))
+            })
+            .expect("failed to send");
+
+            calls
+        }
+
+        fn context_with_attachments(api: &dyn VkAPI, count: i64) -> Context<'_> {
+            let mut ctx = context(
+                Event::MessageNew,
+                Object::new(
+                    None,               // from_id
+                    Some(1),            // peer_id
+                    None,               // user_id
+                    None,               // text
+                    None,               // payload
+                    None,               // action
+                    Default::default(), // extra fields
+                ),
+                api,
             );
+
+            ctx.response().set_message("text");
+            ctx.response().set_keyboard(Keyboard::new(vec![], true));
+            for id in 0..count {
+                ctx.response().attach(AttachmentInformation::new(
+                    AttachmentType::Photo,
+                    1,
+                    id,
+                    None,
+                ));
+            }
+
+            ctx
+        }
+
+        #[test]
+        fn without_attachments() {
+            let api = MockAPI::new();
+            let calls = mock_send(&context_with_attachments(&api, 0));
+
+            assert_eq!(calls.len(), 1);
+            assert_eq!(calls[0]["peer_id"], "1");
+            assert_eq!(calls[0]["message"], "text");
+            assert!(!calls[0].contains_key("attachment"));
+            assert!(calls[0].contains_key("keyboard"));
+        }
+
+        #[test]
+        fn attachments_are_joined() {
+            let api = MockAPI::new();
+            let calls = mock_send(&context_with_attachments(&api, 3));
+
+            assert_eq!(calls.len(), 1);
+            assert_eq!(calls[0]["attachment"], "photo1_0,photo1_1,photo1_2");
         }
 
-        if let Some(kbd) = kbd {
-            params.insert(
-                "keyboard".into(),
-                serde_json::to_string(&kbd).expect("failed to serialize keyboard to String"),
+        #[test]
+        fn attachments_are_split() {
+            let api = MockAPI::new();
+            let calls = mock_send(&context_with_attachments(&api, 23));
+
+            assert_eq!(calls.len(), 3);
+
+            let counts: Vec<_> = calls
+                .iter()
+                .map(|params| params["attachment"].split(',').count())
+                .collect();
+            assert_eq!(counts, vec![10, 10, 3]);
+
+            assert_eq!(calls[0]["message"], "text");
+            assert!(!calls[1].contains_key("message"));
+            assert!(!calls[2].contains_key("message"));
+
+            assert!(!calls[0].contains_key("keyboard"));
+            assert!(!calls[1].contains_key("keyboard"));
+            assert!(calls[2].contains_key("keyboard"));
+
+            assert!(calls.iter().all(|params| params["peer_id"] == "1"));
+            assert_ne!(calls[0]["random_id"], calls[1]["random_id"]);
+        }
+
+        #[test]
+        fn long_message_is_split() {
+            let api = MockAPI::new();
+            let mut ctx = context_with_attachments(&api, 13);
+
+            let paragraph = "word ".repeat(600);
+            let text = format!("{}\n\n{}", paragraph.trim_end(), paragraph.trim_end());
+            ctx.response().set_message(&text);
+
+            let calls = mock_send(&ctx);
+            assert_eq!(calls.len(), 3);
+
+            assert_eq!(calls[0]["message"], paragraph.trim_end());
+            assert!(!calls[0].contains_key("attachment"));
+
+            assert_eq!(calls[1]["message"], paragraph.trim_end());
+            assert_eq!(calls[1]["attachment"].split(',').count(), 10);
+
+            assert!(!calls[2].contains_key("message"));
+            assert_eq!(calls[2]["attachment"].split(',').count(), 3);
+            assert!(calls[2].contains_key("keyboard"));
+        }
+
+        #[test]
+        fn long_message_is_truncated() {
+            let api = MockAPI::new();
+            let mut ctx = context_with_attachments(&api, 0);
+            ctx.response().set_message(&"a".repeat(5000));
+            ctx.response().set_long_message(LongMessage::Truncate);
+
+            let calls = mock_send(&ctx);
+            assert_eq!(calls.len(), 1);
+            assert_eq!(calls[0]["message"].chars().count(), MAX_MESSAGE_LENGTH);
+            assert!(calls[0]["message"].ends_with('…'));
+        }
+
+        #[test]
+        fn long_message_fails() {
+            let api = MockAPI::new();
+            let mut ctx = context_with_attachments(&api, 0);
+            ctx.response().set_message(&"a".repeat(5000));
+            ctx.response().set_long_message(LongMessage::Fail);
+
+            let result = ctx.send_with(|_| panic!("message should not be sent"));
+            assert!(matches!(result, Err(SendError::MessageTooLong(5000))));
+        }
+
+        #[test]
+        fn returns_sent_message() {
+            let api = MockAPI::new();
+            let ctx = context_with_attachments(&api, 13);
+
+            let mut next_id = 100;
+            let sent = ctx
+                .send_with(|_| {
+                    next_id += 1;
+                    Ok(Value::from(next_id))
+                })
+                .expect("failed to send");
+
+            assert_eq!(sent.peer_id(), 1);
+            assert_eq!(sent.message_ids(), &vec![101, 102]);
+            assert_eq!(sent.message_id(), 102);
+        }
+
+        #[test]
+        fn unexpected_response() {
+            let api = MockAPI::new();
+            let ctx = context_with_attachments(&api, 0);
+
+            let result = ctx.send_with(|_| Ok(Value::Null));
+            assert!(matches!(
+                result,
+                Err(SendError::UnexpectedResponse(Value::Null))
+            ));
+        }
+
+        #[test]
+        fn reply_and_forward_with_first_message() {
+            let api = MockAPI::new();
+            let mut ctx = context_with_attachments(&api, 13);
+            ctx.response().set_reply_to(5);
+            ctx.response().set_forward_messages(vec![6, 7]);
+            ctx.response().set_forward(Forward::reply(1, 8));
+
+            let calls = mock_send(&ctx);
+            assert_eq!(calls.len(), 2);
+
+            assert_eq!(calls[0]["reply_to"], "5");
+            assert_eq!(calls[0]["forward_messages"], "6,7");
+            assert_eq!(
+                serde_json::from_str::<Value>(&calls[0]["forward"]).unwrap(),
+                serde_json::json!({"peer_id": 1, "conversation_message_ids": [8], "is_reply": true})
             );
+
+            assert!(!calls[1].contains_key("reply_to"));
+            assert!(!calls[1].contains_key("forward_messages"));
+            assert!(!calls[1].contains_key("forward"));
         }
 
-        let random_id: i32 = rand::random();
-        params.insert("random_id".into(), format!("{}", random_id));
+        #[test]
+        fn edit() {
+            let mut res = Response::new();
+            res.set_message("progress: 50%");
+            res.set_reply_to(5);
 
-        trace!("sending message {:#?}", params);
+            let params = edit_params(1, 2, &res).expect("failed to build params");
+            assert_eq!(params["peer_id"], "1");
+            assert_eq!(params["message_id"], "2");
+            assert_eq!(params["message"], "progress: 50%");
+            assert!(!params.contains_key("reply_to"));
+            assert!(!params.contains_key("random_id"));
+
+            res.set_message(&"a ".repeat(3000));
+            assert!(matches!(
+                edit_params(1, 2, &res),
+                Err(SendError::NotSingleMessage)
+            ));
+        }
+
+        #[test]
+        fn through_queue() {
+            use crate::{api::UPLOAD, queue::QueueConfig};
+            use std::sync::Arc;
+
+            let api = MockAPI::new();
+            let queued_api = Arc::new(MockAPI::new());
+            let queue = Queue::new(QueueConfig::new(), Arc::clone(&queued_api));
+
+            let mut ctx = context_with_attachments(&api, 13);
+            ctx.set_queue(&queue);
+
+            let sent = ctx.send().expect("failed to send");
+            assert_eq!(sent.message_ids().len(), 2);
+            sent.pin().expect("failed to pin");
+            sent.delete().expect("failed to delete");
+            ctx.upload_photo("photo.png", vec![1, 2, 3])
+                .expect("failed to upload");
+
+            let methods: Vec<_> = queued_api
+                .calls()
+                .iter()
+                .map(|call| call.method().to_string())
+                .collect();
+            assert_eq!(
+                methods,
+                [
+                    "messages.send",
+                    "messages.send",
+                    "messages.pin",
+                    "messages.delete",
+                    "photos.getMessagesUploadServer",
+                    "photos.saveMessagesPhoto",
+                ]
+            );
+            assert_eq!(queued_api.calls()[0].param("peer_id"), Some("1"));
+            assert_eq!(queue.metrics().sent(), 6);
+
+            // Only the file itself is uploaded directly.
+            let direct: Vec<_> = api
+                .calls()
+                .into_iter()
+                .map(|call| call.method().to_string())
+                .collect();
+            assert_eq!(direct, [UPLOAD]);
+        }
+
+        #[test]
+        fn send_many_is_chunked() {
+            let api = MockAPI::new();
+            let ctx = context_with_attachments(&api, 13);
+
+            let peer_ids: Vec<Integer> = (1..=150).chain(vec![1, 2]).collect();
+            let mut calls = Vec::new();
+            let multicast = send_many_with(
+                &api,
+                None,
+                &peer_ids,
+                &ctx.response,
+                &ctx.random_ids,
+                |params| {
+                    let results: Vec<_> = params["peer_ids"]
+                        .split(',')
+                        .map(|id| id.parse::<Integer>().unwrap())
+                        .map(|id| match id {
+                            7 => serde_json::json!({"peer_id": 7, "error": {"code": 901}}),
+                            id => serde_json::json!({"peer_id": id, "message_id": id * 10}),
+                        })
+                        .collect();
+
+                    calls.push(params);
+                    Ok(Value::from(results))
+                },
+            )
+            .expect("failed to send");
+
+            let chunk_sizes: Vec<_> = calls
+                .iter()
+                .map(|params| params["peer_ids"].split(',').count())
+                .collect();
+            assert_eq!(chunk_sizes, vec![100, 50, 100, 49]);
+            assert!(calls.iter().all(|params| params.get("peer_id").is_none()));
+            assert!(!calls[2]["peer_ids"].split(',').any(|id| id == "7"));
+
+            assert_eq!(multicast.sent().len(), 149);
+            assert_eq!(multicast.sent()[0].peer_id(), 1);
+            assert_eq!(multicast.sent()[0].message_ids(), &vec![10, 10]);
+            assert_eq!(
+                multicast.failed(),
+                &vec![(7, serde_json::json!({"code": 901}))]
+            );
+        }
+
+        #[test]
+        fn send_many_keeps_going_after_errors() {
+            use rvk::error::APIError;
+
+            let api = MockAPI::new();
+            let ctx = context_with_attachments(&api, 13);
+
+            let peer_ids: Vec<Integer> = (1..=150).collect();
+            let mut calls = 0;
+            let multicast = send_many_with(
+                &api,
+                None,
+                &peer_ids,
+                &ctx.response,
+                &ctx.random_ids,
+                |params| {
+                    calls += 1;
+                    let ids: Vec<Integer> = params["peer_ids"]
+                        .split(',')
+                        .map(|id| id.parse().unwrap())
+                        .collect();
+
+                    if ids.contains(&101) {
+                        return Err(Error::API(APIError::new(
+                            9,
+                            "flood control".into(),
+                            Default::default(),
+                        )));
+                    }
+
+                    let results: Vec<_> = ids
+                        .into_iter()
+                        .map(|id| match (calls, id) {
+                            (3, 5) => serde_json::json!({"peer_id": 5, "error": {"code": 901}}),
+                            (_, id) => serde_json::json!({"peer_id": id, "message_id": id * 10}),
+                        })
+                        .collect();
+                    Ok(Value::from(results))
+                },
+            )
+            .expect("failed to send");
+
+            assert_eq!(calls, 3);
+            assert_eq!(multicast.sent().len(), 100);
+            assert_eq!(multicast.sent()[0].message_ids(), &vec![10, 10]);
+            // The first part was delivered to peer 5.
+            assert_eq!(multicast.sent()[4].peer_id(), 5);
+            assert_eq!(multicast.sent()[4].message_ids(), &vec![50]);
+
+            let failed = multicast.failed();
+            assert_eq!(failed.len(), 51);
+            assert_eq!(
+                failed[0],
+                (
+                    101,
+                    serde_json::json!({"code": 9, "description": "flood control"})
+                )
+            );
+            assert_eq!(failed[50], (5, serde_json::json!({"code": 901})));
+        }
+
+        #[test]
+        fn send_many_without_peers() {
+            let api = MockAPI::new();
+            let ctx = context_with_attachments(&api, 0);
 
-        messages::send(self.api, params).map(|_| ())
+            let multicast = send_many_with(&api, None, &[], &ctx.response, &ctx.random_ids, |_| {
+                panic!("message should not be sent")
+            })
+            .expect("failed to send");
+            assert!(multicast.sent().is_empty());
+            assert!(multicast.failed().is_empty());
+        }
+
+        fn random_ids(ctx: &Context) -> Vec<String> {
+            mock_send(ctx)
+                .into_iter()
+                .map(|params| params["random_id"].clone())
+                .collect()
+        }
+
+        #[test]
+        fn random_id_from_event_id() {
+            let api = MockAPI::new();
+            let req = CallbackAPIRequest::new(None, 1, "message_typing_state", {
+                Object::new(Some(2), None, None, None, None, None, Default::default())
+            })
+            .with_event_id("a1b2c3");
+
+            let mut first = Context::new(Event::MessageTypingState, &req, &api);
+            let mut redelivered = Context::new(Event::MessageTypingState, &req, &api);
+            assert_eq!(first.random_id_seed(), Some("event:a1b2c3"));
+
+            for ctx in &mut [&mut first, &mut redelivered] {
+                ctx.response().set_message("typing...");
+                for id in 0..13 {
+                    ctx.response().attach(AttachmentInformation::new(
+                        AttachmentType::Photo,
+                        1,
+                        id,
+                        None,
+                    ));
+                }
+            }
+
+            let ids = random_ids(&first);
+            assert_eq!(ids, random_ids(&redelivered));
+            assert_eq!(ids[0], random_id("event:a1b2c3", 0).to_string());
+            assert_eq!(ids[1], random_id("event:a1b2c3", 1).to_string());
+
+            // Messages sent later by the same handler continue the sequence.
+            assert_eq!(
+                random_ids(&first)[0],
+                random_id("event:a1b2c3", 2).to_string()
+            );
+        }
+
+        #[test]
+        fn random_id_from_message() {
+            let api = MockAPI::new();
+            let obj: Object = serde_json::from_value(serde_json::json!({
+                "date": 1_500_000_000,
+                "peer_id": 2,
+                "from_id": 2,
+                "conversation_message_id": 7,
+                "text": "hi",
+            }))
+            .unwrap();
+
+            let ctx = context(Event::MessageNew, obj, &api);
+            assert_eq!(ctx.random_id_seed(), Some("message:message_new:1:2:7"));
+        }
+
+        #[test]
+        fn random_id_seed_override() {
+            let api = MockAPI::new();
+            let mut ctx = context_with_attachments(&api, 0);
+            assert_eq!(ctx.random_id_seed(), None);
+
+            ctx.set_random_id_seed(Some("newsletter".into()));
+            assert_eq!(
+                random_ids(&ctx),
+                vec![random_id("newsletter", 0).to_string()]
+            );
+
+            let mut sender = ctx.sender();
+            assert_eq!(sender.random_id_seed(), None);
+            sender.set_random_id_seed(Some("newsletter".into()));
+            assert_eq!(sender.random_id_seed(), Some("newsletter"));
+        }
+
+        #[test]
+        fn random_id_is_stable() {
+            assert_eq!(random_id("event:a1b2c3", 0), random_id("event:a1b2c3", 0));
+            assert_ne!(random_id("event:a1b2c3", 0), random_id("event:a1b2c3", 1));
+            assert_ne!(random_id("event:a1b2c3", 0), random_id("event:a1b2c4", 0));
+            assert!(random_id("", 0) > 0);
+
+            // Must not change between versions, or redelivered events would be
+            // answered twice after an upgrade.
+            assert_eq!(random_id("event:a1b2c3", 0), 24_643_642);
+        }
+
+        #[test]
+        fn stops_on_error() {
+            let api = MockAPI::new();
+            let ctx = context_with_attachments(&api, 23);
+
+            let mut calls = 0;
+            let result = ctx.send_with(|_| {
+                calls += 1;
+                Err(Error::Other("mock error".into()))
+            });
+
+            assert!(matches!(result, Err(SendError::API(_))));
+            assert_eq!(calls, 1);
+        }
+    }
+
+    mod split {
+        use super::*;
+
+        #[test]
+        fn short() {
+            assert_eq!(split_text("short text", 20), vec!["short text"]);
+        }
+
+        #[test]
+        fn on_paragraph() {
+            assert_eq!(
+                split_text("First paragraph.\n\nSecond one. Yes.", 30),
+                vec!["First paragraph.", "Second one. Yes."]
+            );
+        }
+
+        #[test]
+        fn on_sentence() {
+            assert_eq!(
+                split_text("First sentence. Second sentence here.", 30),
+                vec!["First sentence.", "Second sentence here."]
+            );
+        }
+
+        #[test]
+        fn on_word() {
+            assert_eq!(
+                split_text("lorem ipsum dolor sit amet", 15),
+                vec!["lorem ipsum", "dolor sit amet"]
+            );
+        }
+
+        #[test]
+        fn hard() {
+            assert_eq!(split_text("абвгдеёжзи", 4), vec!["абвг", "деёж", "зи"]);
+        }
+
+        #[test]
+        fn parts_are_not_too_short() {
+            // The paragraph boundary is too early, so a word boundary is used.
+            assert_eq!(
+                split_text("A.\n\nlorem ipsum dolor sit amet", 20),
+                vec!["A.\n\nlorem ipsum", "dolor sit amet"]
+            );
+        }
+
+        #[test]
+        fn truncate() {
+            assert_eq!(truncate_text("абвгд", 5), "абвгд");
+            assert_eq!(truncate_text("абвгде", 5), "абвг…");
+        }
     }
 }
@@ -1,9 +1,12 @@
 //! The [`Core`] struct, supported [`Event`]s, and
 //! handler / tester types.
 
-use crate::{context::Context, request::CallbackAPIRequest};
+use crate::{
+    api::VkAPI, attachment::Attachment, context::Context, message::Message, queue::Queue,
+    request::CallbackAPIRequest,
+};
 use regex::Regex;
-use rvk::APIClient;
+use rvk::objects::Integer;
 use serde_json::Value;
 use std::{
     collections::{hash_map::Entry, HashMap},
@@ -182,7 +185,7 @@ impl Debug for Tester {
 /// [`Core::on`] docs) is called for a given message.
 ///
 /// Works like a builder.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct Core {
     cmd_prefix: Option<String>,
     event_handlers: HashMap<Event, Handler>,
@@ -190,19 +193,12 @@ pub struct Core {
     dyn_payload_handlers: Vec<(Tester, Handler)>,
     command_handlers: HashMap<String, Handler>,
     regex_handlers: Vec<(Regex, Handler)>,
-}
-
-impl Default for Core {
-    fn default() -> Self {
-        Self {
-            cmd_prefix: None,
-            event_handlers: Default::default(),
-            static_payload_handlers: Default::default(),
-            dyn_payload_handlers: Default::default(),
-            command_handlers: Default::default(),
-            regex_handlers: Default::default(),
-        }
-    }
+    photo_handler: Option<Handler>,
+    voice_handler: Option<Handler>,
+    sticker_handlers: HashMap<Integer, Handler>,
+    document_handlers: HashMap<Option<String>, Handler>,
+    geo_handler: Option<Handler>,
+    forward_handler: Option<Handler>,
 }
 
 impl Core {
@@ -230,12 +226,15 @@ impl Core {
     /// \# | cause | action
     /// ---|---|---
     /// 1 | `action` field on object | [`Event::ServiceAction`]
-    /// 2 | special `{"command": "start"}` payload | [`Event::Start`]
-    /// 3 | exact payload match set up via [`Core::payload`] | respective handler
-    /// 4 | 'dynamic' payload match set up via [`Core::dyn_payload`] | respective handler
-    /// 5 | command handlers ([`Core::cmd_prefix`] and [`Core::cmd`]) | respective handler
-    /// 6 | regex handlers ([`Core::regex`]) | respective handler
-    /// 7 | anything except [`Event::MessageReply`] and [`Event::NoMatch`] | [`Event::NoMatch`]
+    /// 2 | `geo` field on message (e.g. location button pressed), if [`Core::on_geo`] is set | respective handler
+    /// 3 | special `{"command": "start"}` payload | [`Event::Start`]
+    /// 4 | exact payload match set up via [`Core::payload`] | respective handler
+    /// 5 | 'dynamic' payload match set up via [`Core::dyn_payload`] | respective handler
+    /// 6 | command handlers ([`Core::cmd_prefix`] and [`Core::cmd`]) | respective handler
+    /// 7 | regex handlers ([`Core::regex`]) | respective handler
+    /// 8 | attachment handlers ([`Core::on_photo`], [`Core::on_voice`], [`Core::on_sticker`], [`Core::on_document`]), for the first attachment that has one | respective handler
+    /// 9 | forwarded messages handler ([`Core::on_forward`]) | respective handler
+    /// 10 | anything except [`Event::MessageReply`] and [`Event::NoMatch`] | [`Event::NoMatch`]
     pub fn on(mut self, event: Event, handler: Handler) -> Self {
         let entry = self.event_handlers.entry(event);
 
@@ -303,13 +302,119 @@ impl Core {
         self
     }
 
+    /// Sets the handler for messages with a photo attachment.
+    pub fn on_photo(mut self, handler: Handler) -> Self {
+        Self::set_once(&mut self.photo_handler, handler, "photo attachments");
+        self
+    }
+
+    /// Sets the handler for voice messages (`audio_message` attachments).
+    pub fn on_voice(mut self, handler: Handler) -> Self {
+        Self::set_once(&mut self.voice_handler, handler, "voice messages");
+        self
+    }
+
+    /// Adds a new handler for a sticker with the given ID to this [`Core`].
+    pub fn on_sticker(mut self, sticker_id: Integer, handler: Handler) -> Self {
+        let entry = self.sticker_handlers.entry(sticker_id);
+        match entry {
+            Entry::Occupied(_) => panic!(
+                "attempt to set up duplicate handler for sticker `{}`",
+                sticker_id
+            ),
+            Entry::Vacant(entry) => entry.insert(handler),
+        };
+
+        self
+    }
+
+    /// Adds a new handler for document attachments to this [`Core`].
+    ///
+    /// `ext` is the file extension to match (e.g. `"pdf"`, compared
+    /// case-insensitively, without the leading dot). VK does not send MIME
+    /// types of documents, so the extension is the only available filter.
+    /// Use [`None`] to match a document with any extension; handlers for a
+    /// specific extension take precedence over it.
+    pub fn on_document(mut self, ext: Option<&str>, handler: Handler) -> Self {
+        let ext = ext.map(|ext| ext.trim_start_matches('.').to_lowercase());
+        let entry = self.document_handlers.entry(ext);
+        match entry {
+            Entry::Occupied(entry) => panic!(
+                "attempt to set up duplicate handler for documents with extension {:?}",
+                entry.key()
+            ),
+            Entry::Vacant(entry) => entry.insert(handler),
+        };
+
+        self
+    }
+
+    /// Sets the handler for messages with a location (`geo`), e.g. sent using
+    /// a [`Button::location`](crate::keyboard::Button::location).
+    ///
+    /// This handler takes precedence over payload handlers, as a location
+    /// button sends its payload along with the location. Use
+    /// [`Context::geo`], [`Context::coordinates`] and [`Context::place`] to
+    /// get the location.
+    pub fn on_geo(mut self, handler: Handler) -> Self {
+        Self::set_once(&mut self.geo_handler, handler, "locations");
+        self
+    }
+
+    /// Sets the handler for messages with forwarded messages.
+    pub fn on_forward(mut self, handler: Handler) -> Self {
+        Self::set_once(&mut self.forward_handler, handler, "forwarded messages");
+        self
+    }
+
+    /// Sets a handler which may only be set once.
+    fn set_once(slot: &mut Option<Handler>, handler: Handler, what: &str) {
+        if slot.is_some() {
+            panic!("attempt to set up duplicate handler for {}", what);
+        }
+
+        *slot = Some(handler);
+    }
+
     /// Handles a request by telling the appropriate [`Handler`] to do so.
-    pub fn handle(&self, req: &CallbackAPIRequest, api: &APIClient) {
+    ///
+    /// # Errors
+    /// - if the request's type is not a supported [`Event`].
+    pub fn handle(
+        &self,
+        req: &CallbackAPIRequest,
+        api: &dyn VkAPI,
+    ) -> Result<(), EventFromStrError> {
+        self.handle_inner(req, api, None)
+    }
+
+    /// Like [`Core::handle`], but handlers send messages through `queue`.
+    pub fn handle_with_queue(
+        &self,
+        req: &CallbackAPIRequest,
+        api: &dyn VkAPI,
+        queue: &Queue,
+    ) -> Result<(), EventFromStrError> {
+        self.handle_inner(req, api, Some(queue))
+    }
+
+    /// Handles a request, setting the queue of the [`Context`] if given.
+    fn handle_inner(
+        &self,
+        req: &CallbackAPIRequest,
+        api: &dyn VkAPI,
+        queue: Option<&Queue>,
+    ) -> Result<(), EventFromStrError> {
         trace!("handling {:#?}", req);
 
-        let event: Event = req.r#type().parse().expect("error while handling request");
+        let event: Event = req.r#type().parse()?;
         let mut ctx = Context::new(event, req, api);
+        if let Some(queue) = queue {
+            ctx.set_queue(queue);
+        }
         self.handle_event(event, &mut ctx);
+
+        Ok(())
     }
 
     /// Handles an event.
@@ -338,9 +443,10 @@ impl Core {
     }
 
     /// Handles the [`Event::MessageNew`], trying to detect
-    /// [`Event::ServiceAction`] first, and then: [`Core::try_handle_payload`]
+    /// [`Event::ServiceAction`] first, and then: [`Core::try_handle_geo`] ->
+    /// [`Core::try_handle_payload`]
     /// -> [`Core::try_handle_command`] -> [`Core::try_handle_regex`] ->
-    /// [`Event::NoMatch`].
+    /// [`Core::try_handle_attachments`] -> [`Event::NoMatch`].
     fn handle_message_new(&self, ctx: &mut Context) {
         if ctx.object().action().is_some() {
             trace!("calling `service_action` handler for {:#?}", ctx);
@@ -348,9 +454,11 @@ impl Core {
             return;
         }
 
-        if !self.try_handle_payload(ctx)
+        if !self.try_handle_geo(ctx)
+            && !self.try_handle_payload(ctx)
             && !self.try_handle_command(ctx)
             && !self.try_handle_regex(ctx)
+            && !self.try_handle_attachments(ctx)
         {
             trace!(
                 "calling `no_match` (as `message_new` failed to match) handler for {:#?}",
@@ -360,6 +468,18 @@ impl Core {
         }
     }
 
+    /// Tries to handle this message using the location handler. Returns `true`
+    /// if that was successful, `false` otherwise.
+    fn try_handle_geo(&self, ctx: &mut Context) -> bool {
+        match &self.geo_handler {
+            Some(handler) if ctx.geo().is_some() => {
+                handler(ctx);
+                true
+            }
+            _ => false,
+        }
+    }
+
     /// Tries to handle this message using a payload handler. Returns `true` if
     /// that was successful, `false` otherwise.
     fn try_handle_payload(&self, ctx: &mut Context) -> bool {
@@ -415,7 +535,7 @@ impl Core {
                 ))
                 .expect("invalid regex");
 
-                if re.is_match(&text) {
+                if re.is_match(text) {
                     self.command_handlers[command](ctx);
                     return true;
                 }
@@ -430,7 +550,7 @@ impl Core {
     fn try_handle_regex(&self, ctx: &mut Context) -> bool {
         if let Some(text) = ctx.object().text() {
             for (re, handler) in self.regex_handlers.iter() {
-                if re.is_match(&text) {
+                if re.is_match(text) {
                     handler(ctx);
                     return true;
                 }
@@ -439,6 +559,50 @@ impl Core {
 
         false
     }
+
+    /// Tries to handle this message using an attachment or forwarded messages
+    /// handler. Returns `true` if that was successful,
+    /// `false` otherwise.
+    fn try_handle_attachments(&self, ctx: &mut Context) -> bool {
+        let handler = match ctx.message() {
+            Some(msg) => self.find_attachment_handler(msg),
+            None => return false,
+        };
+
+        match handler {
+            Some(handler) => {
+                handler(ctx);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Finds the handler for the first attachment of the message that has
+    /// one, then for its forwarded messages.
+    fn find_attachment_handler(&self, msg: &Message) -> Option<&Handler> {
+        let attachment_handler = msg
+            .attachments()
+            .iter()
+            .find_map(|attachment| match attachment {
+                Attachment::Photo(_) => self.photo_handler.as_ref(),
+                Attachment::AudioMessage(_) => self.voice_handler.as_ref(),
+                Attachment::Sticker(sticker) => self.sticker_handlers.get(&sticker.sticker_id()),
+                Attachment::Doc(doc) => self
+                    .document_handlers
+                    .get(&Some(doc.ext().to_lowercase()))
+                    .or_else(|| self.document_handlers.get(&None)),
+                _ => None,
+            });
+
+        attachment_handler.or_else(|| {
+            if msg.fwd_messages().is_empty() {
+                None
+            } else {
+                self.forward_handler.as_ref()
+            }
+        })
+    }
 }
 
 #[cfg(test)]
@@ -451,7 +615,7 @@ mod tests {
         fn test_display_parse(expected_str: &str, expected_event: Event) {
             let event: Event = expected_str
                 .parse()
-                .expect(&format!("could not parse event: `{}`", expected_str));
+                .unwrap_or_else(|_| panic!("could not parse event: `{}`", expected_str));
             assert_eq!(event, expected_event);
             let str = format!("{}", event);
             assert_eq!(str, expected_str);
@@ -481,7 +645,8 @@ mod tests {
 
     mod wiring {
         use super::*;
-        use crate::request::Object;
+        use crate::{api::MockAPI, request::Object};
+        use serde_json::json;
         use std::sync::{mpsc, Mutex};
 
         #[derive(Clone, Copy, PartialEq, Debug)]
@@ -492,6 +657,13 @@ mod tests {
             DynPayload,
             Command,
             Regex,
+            Photo,
+            Voice,
+            Sticker,
+            Document,
+            AnyDocument,
+            Geo,
+            Forward,
             NoMatch,
         }
 
@@ -510,7 +682,7 @@ mod tests {
             let (tx, rx) = mpsc::sync_channel(1);
             let tx = Arc::new(Mutex::new(tx));
 
-            let api = APIClient::new("vk_token");
+            let api = MockAPI::new();
 
             let mut ctx = Context::new(
                 Event::MessageNew,
@@ -540,6 +712,13 @@ mod tests {
                     Regex::new(r#"\d"#).unwrap(),
                     wiring_sender(&tx, Wiring::Regex),
                 )
+                .on_photo(wiring_sender(&tx, Wiring::Photo))
+                .on_voice(wiring_sender(&tx, Wiring::Voice))
+                .on_sticker(9, wiring_sender(&tx, Wiring::Sticker))
+                .on_document(Some(".PDF"), wiring_sender(&tx, Wiring::Document))
+                .on_document(None, wiring_sender(&tx, Wiring::AnyDocument))
+                .on_geo(wiring_sender(&tx, Wiring::Geo))
+                .on_forward(wiring_sender(&tx, Wiring::Forward))
                 .on(Event::NoMatch, wiring_sender(&tx, Wiring::NoMatch))
                 .handle_event(Event::MessageNew, &mut ctx);
 
@@ -657,5 +836,116 @@ mod tests {
                 Wiring::NoMatch
             );
         }
+
+        fn message(fields: Value) -> Object {
+            let mut obj = json!({"id": 1, "date": 1, "peer_id": 1, "from_id": 1, "text": ""});
+            obj.as_object_mut()
+                .unwrap()
+                .extend(fields.as_object().unwrap().clone());
+
+            serde_json::from_value(obj).expect("invalid message object")
+        }
+
+        #[test]
+        fn photo() {
+            assert_eq!(
+                test_wiring(message(json!({
+                    "attachments": [{"type": "photo", "photo": {"id": 1, "owner_id": 1}}],
+                }))),
+                Wiring::Photo
+            );
+        }
+
+        #[test]
+        fn voice() {
+            assert_eq!(
+                test_wiring(message(json!({
+                    "attachments": [{"type": "audio_message", "audio_message": {"id": 1, "owner_id": 1}}],
+                }))),
+                Wiring::Voice
+            );
+        }
+
+        #[test]
+        fn sticker() {
+            assert_eq!(
+                test_wiring(message(json!({
+                    "attachments": [{"type": "sticker", "sticker": {"product_id": 1, "sticker_id": 9}}],
+                }))),
+                Wiring::Sticker
+            );
+        }
+
+        #[test]
+        fn unknown_sticker() {
+            assert_eq!(
+                test_wiring(message(json!({
+                    "attachments": [{"type": "sticker", "sticker": {"product_id": 1, "sticker_id": 10}}],
+                }))),
+                Wiring::NoMatch
+            );
+        }
+
+        #[test]
+        fn document() {
+            assert_eq!(
+                test_wiring(message(json!({
+                    "attachments": [{"type": "doc", "doc": {"id": 1, "owner_id": 1, "ext": "pdf"}}],
+                }))),
+                Wiring::Document
+            );
+        }
+
+        #[test]
+        fn any_document() {
+            assert_eq!(
+                test_wiring(message(json!({
+                    "attachments": [{"type": "doc", "doc": {"id": 1, "owner_id": 1, "ext": "txt"}}],
+                }))),
+                Wiring::AnyDocument
+            );
+        }
+
+        #[test]
+        fn geo() {
+            assert_eq!(
+                test_wiring(message(json!({
+                    "geo": {"type": "point", "coordinates": {"latitude": 1.0, "longitude": 2.0}},
+                }))),
+                Wiring::Geo
+            );
+        }
+
+        #[test]
+        fn forward() {
+            assert_eq!(
+                test_wiring(message(json!({
+                    "fwd_messages": [{"date": 1, "from_id": 2, "text": "forwarded"}],
+                }))),
+                Wiring::Forward
+            );
+        }
+
+        #[test]
+        fn text_before_attachments() {
+            assert_eq!(
+                test_wiring(message(json!({
+                    "text": "1337",
+                    "attachments": [{"type": "photo", "photo": {"id": 1, "owner_id": 1}}],
+                }))),
+                Wiring::Regex
+            );
+        }
+
+        #[test]
+        fn location_button_before_payload() {
+            assert_eq!(
+                test_wiring(message(json!({
+                    "payload": r#"{"other": "payload"}"#,
+                    "geo": {"type": "point", "coordinates": {"latitude": 1.0, "longitude": 2.0}},
+                }))),
+                Wiring::Geo
+            );
+        }
     }
 }
@@ -0,0 +1,327 @@
+//! Deduplication of Callback API events redelivered by VK.
+//!
+//! VK retries delivering an event if the bot did not respond with `ok` in
+//! time, so the same event (with the same `event_id`) may be received several
+//! times. [`Bot`](crate::Bot) remembers `event_id`s it has seen in a
+//! [`SeenSet`], and drops events it has already seen.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Default number of `event_id`s remembered by a [`SeenSet`].
+pub const DEFAULT_CAPACITY: usize = 10_000;
+
+/// Default time `event_id`s are remembered for by a [`SeenSet`] (one hour).
+pub const DEFAULT_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// A set of `event_id`s which were already received.
+pub trait SeenSet: Debug + Send + Sync {
+    /// Records that the event with `event_id` was received at `now`.
+    ///
+    /// Returns `true` if the event was not seen before (or was forgotten), and
+    /// `false` if it is a duplicate.
+    fn insert(&self, event_id: &str, now: SystemTime) -> bool;
+}
+
+/// An in-memory [`SeenSet`], which remembers at most `capacity` `event_id`s,
+/// each for at most `ttl`.
+#[derive(Debug)]
+pub struct MemorySeenSet {
+    capacity: usize,
+    ttl: Duration,
+    seen: Mutex<Seen>,
+}
+
+/// `event_id`s and the times they were seen at, oldest first.
+#[derive(Debug, Default)]
+struct Seen {
+    times: HashMap<String, SystemTime>,
+    order: VecDeque<(String, SystemTime)>,
+}
+
+impl Default for MemorySeenSet {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY, DEFAULT_TTL)
+    }
+}
+
+impl MemorySeenSet {
+    /// Creates a new empty [`MemorySeenSet`].
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            seen: Default::default(),
+        }
+    }
+
+    /// Returns the maximum number of `event_id`s remembered.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns the time `event_id`s are remembered for.
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Returns the number of `event_id`s currently remembered (including
+    /// expired ones which were not forgotten yet).
+    pub fn len(&self) -> usize {
+        self.seen.lock().unwrap().order.len()
+    }
+
+    /// Returns `true` if no `event_id`s are remembered.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns remembered `event_id`s and the times they were seen at, oldest
+    /// first.
+    fn entries(&self) -> Vec<(String, SystemTime)> {
+        self.seen.lock().unwrap().order.iter().cloned().collect()
+    }
+
+    /// Forgets the `event_id`s which were seen at least `ttl` before `now`.
+    fn expire(&self, seen: &mut Seen, now: SystemTime) {
+        while let Some((_, time)) = seen.order.front() {
+            match now.duration_since(*time) {
+                Ok(age) if age >= self.ttl => seen.pop_front(),
+                _ => break,
+            }
+        }
+    }
+}
+
+impl Seen {
+    fn pop_front(&mut self) {
+        if let Some((id, _)) = self.order.pop_front() {
+            self.times.remove(&id);
+        }
+    }
+}
+
+impl SeenSet for MemorySeenSet {
+    fn insert(&self, event_id: &str, now: SystemTime) -> bool {
+        let mut seen = self.seen.lock().unwrap();
+        self.expire(&mut seen, now);
+
+        if seen.times.contains_key(event_id) {
+            return false;
+        }
+
+        if self.capacity == 0 {
+            return true;
+        }
+
+        while seen.order.len() >= self.capacity {
+            seen.pop_front();
+        }
+
+        seen.times.insert(event_id.into(), now);
+        seen.order.push_back((event_id.into(), now));
+        true
+    }
+}
+
+/// A [`SeenSet`] which is also stored in a file, so that it survives
+/// restarts.
+///
+/// Each seen `event_id` is appended to the file; the file is rewritten with
+/// only the remembered `event_id`s when it is opened, and when it grows to
+/// twice the capacity. I/O errors are logged, and do not affect
+/// deduplication in memory.
+#[derive(Debug)]
+pub struct FileSeenSet {
+    path: PathBuf,
+    memory: MemorySeenSet,
+    file: Mutex<(File, usize)>,
+}
+
+impl FileSeenSet {
+    /// Opens (or creates) a [`FileSeenSet`] stored in the file at `path`,
+    /// loading the `event_id`s seen less than `ttl` ago.
+    ///
+    /// # Errors
+    /// - if the file could not be read or written.
+    pub fn open<P: AsRef<Path>>(path: P, capacity: usize, ttl: Duration) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let memory = MemorySeenSet::new(capacity, ttl);
+
+        match File::open(&path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    if let Some((event_id, time)) = parse_line(&line?) {
+                        memory.insert(&event_id, time);
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        // Forget the `event_id`s which expired while the bot was not running.
+        memory.expire(&mut memory.seen.lock().unwrap(), SystemTime::now());
+
+        let file = rewrite(&path, &memory)?;
+
+        Ok(Self {
+            path,
+            memory,
+            file: Mutex::new((file, 0)),
+        })
+    }
+
+    /// Returns the path of the file this set is stored in.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl SeenSet for FileSeenSet {
+    fn insert(&self, event_id: &str, now: SystemTime) -> bool {
+        if !self.memory.insert(event_id, now) {
+            return false;
+        }
+
+        let mut file = self.file.lock().unwrap();
+        let (ref mut handle, ref mut appended) = *file;
+
+        let result = if *appended >= self.memory.capacity().max(1) {
+            rewrite(&self.path, &self.memory).map(|new| {
+                *handle = new;
+                *appended = 0;
+            })
+        } else {
+            writeln!(handle, "{}", format_line(event_id, now)).map(|()| *appended += 1)
+        };
+
+        if let Err(e) = result {
+            warn!("failed to store seen event ID in {:?}: {}", self.path, e);
+        }
+
+        true
+    }
+}
+
+/// Writes the remembered `event_id`s of `memory` to a new file at `path`, and
+/// returns it opened for appending.
+fn rewrite(path: &Path, memory: &MemorySeenSet) -> io::Result<File> {
+    let tmp = path.with_extension("tmp");
+
+    {
+        let mut file = File::create(&tmp)?;
+        for (event_id, time) in memory.entries() {
+            writeln!(file, "{}", format_line(&event_id, time))?;
+        }
+        file.sync_all()?;
+    }
+
+    fs::rename(&tmp, path)?;
+    OpenOptions::new().append(true).open(path)
+}
+
+/// Formats a line of a [`FileSeenSet`] file: the time in milliseconds since
+/// the Unix epoch, and the `event_id`.
+fn format_line(event_id: &str, time: SystemTime) -> String {
+    let millis = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    format!("{} {}", millis, event_id)
+}
+
+/// Parses a line written by [`format_line`].
+fn parse_line(line: &str) -> Option<(String, SystemTime)> {
+    let mut parts = line.splitn(2, ' ');
+    let millis: u64 = parts.next()?.parse().ok()?;
+    let event_id = parts.next().filter(|id| !id.is_empty())?;
+
+    Some((event_id.into(), UNIX_EPOCH + Duration::from_millis(millis)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_600_000_000 + secs)
+    }
+
+    #[test]
+    fn drops_duplicates() {
+        let seen = MemorySeenSet::default();
+
+        assert!(seen.insert("a", at(0)));
+        assert!(seen.insert("b", at(1)));
+        assert!(!seen.insert("a", at(2)));
+        assert!(!seen.insert("b", at(3)));
+    }
+
+    #[test]
+    fn forgets_after_ttl() {
+        let seen = MemorySeenSet::new(10, Duration::from_secs(60));
+
+        assert!(seen.insert("a", at(0)));
+        assert!(!seen.insert("a", at(59)));
+        assert!(seen.insert("a", at(60)));
+        assert_eq!(seen.len(), 1);
+    }
+
+    #[test]
+    fn bounded() {
+        let seen = MemorySeenSet::new(2, DEFAULT_TTL);
+
+        assert!(seen.insert("a", at(0)));
+        assert!(seen.insert("b", at(1)));
+        assert!(seen.insert("c", at(2)));
+        assert_eq!(seen.len(), 2);
+
+        // The oldest one was forgotten.
+        assert!(seen.insert("a", at(3)));
+        assert!(!seen.insert("c", at(4)));
+    }
+
+    #[test]
+    fn file_survives_restarts() -> io::Result<()> {
+        let path = std::env::temp_dir().join(format!("vk-bot-seen-{}.txt", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let now = SystemTime::now();
+        {
+            let seen = FileSeenSet::open(&path, 2, DEFAULT_TTL)?;
+            assert!(seen.insert("a", now));
+            assert!(seen.insert("b", now));
+            assert!(seen.insert("c", now));
+            assert!(!seen.insert("c", now));
+        }
+
+        let seen = FileSeenSet::open(&path, 2, DEFAULT_TTL)?;
+        assert!(!seen.insert("b", now));
+        assert!(!seen.insert("c", now));
+        assert!(seen.insert("d", now));
+
+        // Expired ones are not loaded.
+        let seen = FileSeenSet::open(&path, 10, Duration::from_secs(0))?;
+        assert!(seen.insert("d", SystemTime::now()));
+
+        fs::remove_file(&path)
+    }
+
+    #[test]
+    fn lines() {
+        assert_eq!(
+            parse_line(&format_line("a b", at(0))),
+            Some(("a b".to_string(), at(0)))
+        );
+        assert_eq!(parse_line("garbage"), None);
+        assert_eq!(parse_line("123 "), None);
+    }
+}
@@ -0,0 +1,237 @@
+//! The [`Dispatcher`], which handles Callback API requests in the background,
+//! one at a time per peer, and in parallel across peers.
+
+use crate::{
+    api::VkAPI,
+    context,
+    core::{Core, Event, EventFromStrError},
+    pool::{PoolConfig, SubmitError, WorkerPool},
+    request::CallbackAPIRequest,
+};
+use std::{
+    fmt::{self, Debug, Display, Formatter},
+    sync::Arc,
+};
+
+/// Function which handles a request, usually by calling [`Core::handle`].
+pub type RequestHandler =
+    Arc<dyn Fn(&CallbackAPIRequest) -> Result<(), EventFromStrError> + Send + Sync>;
+
+/// Hands requests to a [`RequestHandler`] run by a [`WorkerPool`], keyed by
+/// the ID of the peer each event is related to (see
+/// [`Context::new`](crate::Context::new)).
+///
+/// Thus events from one conversation are handled one at a time, in the order
+/// they were dispatched, while events from different conversations are handled
+/// in parallel. Ordering can be disabled via [`PoolConfig::ordered`].
+pub struct Dispatcher {
+    handler: RequestHandler,
+    pool: WorkerPool,
+}
+
+impl Dispatcher {
+    /// Creates a new [`Dispatcher`], which handles requests using `handler`.
+    pub fn new<F>(config: PoolConfig, handler: F) -> Self
+    where
+        F: Fn(&CallbackAPIRequest) -> Result<(), EventFromStrError> + Send + Sync + 'static,
+    {
+        Self {
+            handler: Arc::new(handler),
+            pool: WorkerPool::new(config),
+        }
+    }
+
+    /// Creates a new [`Dispatcher`], which handles requests using
+    /// `core.handle(req, &api)`.
+    pub fn with_core<A: VkAPI + 'static>(config: PoolConfig, core: Core, api: A) -> Self {
+        Self::new(config, move |req| core.handle(req, &api))
+    }
+
+    /// Returns the configuration of the [`WorkerPool`] of this dispatcher.
+    pub fn config(&self) -> &PoolConfig {
+        self.pool.config()
+    }
+
+    /// Returns the [`WorkerPool`] of this dispatcher.
+    pub fn pool(&self) -> &WorkerPool {
+        &self.pool
+    }
+
+    /// Submits a request to be handled in the background. Errors which occur
+    /// while handling it are logged.
+    ///
+    /// # Errors
+    /// - [`DispatchError::Event`] if the request's type is not a supported
+    ///   [`Event`],
+    /// - [`DispatchError::Pool`] if the request could not be submitted to the
+    ///   pool (e.g. because it is full).
+    pub fn dispatch(&self, req: CallbackAPIRequest) -> Result<(), DispatchError> {
+        let event: Event = req.r#type().parse().map_err(DispatchError::Event)?;
+        let peer_id = context::event_peer_id(event, req.object());
+
+        let handler = Arc::clone(&self.handler);
+        self.pool
+            .submit(peer_id, move || {
+                if let Err(e) = handler(&req) {
+                    warn!("failed to handle request: {}", e);
+                }
+            })
+            .map_err(DispatchError::Pool)
+    }
+}
+
+impl Debug for Dispatcher {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("Dispatcher")
+            .field("pool", &self.pool)
+            .finish()
+    }
+}
+
+/// Error type for [`Dispatcher::dispatch`] and
+/// [`Bot::dispatch`](crate::Bot::dispatch).
+#[derive(Debug)]
+pub enum DispatchError {
+    /// The request's type is not a supported [`Event`].
+    Event(EventFromStrError),
+    /// The request could not be submitted to the worker pool.
+    Pool(SubmitError),
+}
+
+impl Display for DispatchError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            DispatchError::Event(e) => Display::fmt(e, f),
+            DispatchError::Pool(e) => Display::fmt(e, f),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{api::MockAPI, core::Handler, request::Object};
+    use rvk::objects::Integer;
+    use serde_json::Value;
+    use std::{
+        collections::{HashMap, HashSet},
+        sync::{mpsc, Mutex},
+        thread,
+        time::Duration,
+    };
+
+    /// A `message_typing_state` request from `from_id`, with sequence number
+    /// `seq` in an extra field.
+    fn request(from_id: Integer, seq: usize) -> CallbackAPIRequest {
+        let mut extra = HashMap::new();
+        extra.insert("seq".to_string(), Value::from(seq));
+
+        CallbackAPIRequest::new(
+            None,
+            1,
+            "message_typing_state",
+            Object::new(Some(from_id), None, None, None, None, None, extra),
+        )
+    }
+
+    /// What happened while handling a synthetic event stream.
+    #[derive(Default)]
+    struct Log {
+        active: HashSet<Integer>,
+        max_active: usize,
+        handled: HashMap<Integer, Vec<usize>>,
+    }
+
+    /// Dispatches a synthetic stream of `events` events from `peers` peers,
+    /// interleaved, with some handlers being slower than others.
+    fn run_stream(config: PoolConfig, peers: Integer, events: usize) -> Log {
+        let log = Arc::new(Mutex::new(Log::default()));
+
+        {
+            let log = Arc::clone(&log);
+            let dispatcher = Dispatcher::new(config, move |req| {
+                let peer = req.object().get_from_id().unwrap();
+                let seq = req.object().extra()["seq"].as_u64().unwrap() as usize;
+
+                {
+                    let mut log = log.lock().unwrap();
+                    assert!(
+                        log.active.insert(peer),
+                        "peer {} handled twice at once",
+                        peer
+                    );
+                    log.max_active = log.max_active.max(log.active.len());
+                }
+
+                if (seq * 7 + peer as usize) % 5 == 1 {
+                    thread::sleep(Duration::from_millis(2));
+                }
+
+                let mut log = log.lock().unwrap();
+                log.active.remove(&peer);
+                log.handled.entry(peer).or_default().push(seq);
+                Ok(())
+            });
+
+            for seq in 0..events {
+                dispatcher
+                    .dispatch(request(seq as Integer % peers, seq))
+                    .unwrap();
+            }
+        }
+
+        Arc::try_unwrap(log).ok().unwrap().into_inner().unwrap()
+    }
+
+    #[test]
+    fn ordered_per_peer() {
+        let config = PoolConfig::new().workers(4).capacity(1000);
+        let log = run_stream(config, 10, 500);
+
+        assert_eq!(log.handled.len(), 10);
+        for (peer, seqs) in &log.handled {
+            assert_eq!(seqs.len(), 50);
+            assert!(
+                seqs.windows(2).all(|w| w[0] < w[1]),
+                "events of peer {} handled out of order: {:?}",
+                peer,
+                seqs
+            );
+        }
+
+        assert!(log.max_active > 1, "peers were not handled in parallel");
+    }
+
+    #[test]
+    fn one_peer_is_serial() {
+        let config = PoolConfig::new().workers(4).capacity(100);
+        let log = run_stream(config, 1, 100);
+
+        assert_eq!(log.handled[&0], (0..100).collect::<Vec<_>>());
+        assert_eq!(log.max_active, 1);
+    }
+
+    #[test]
+    fn with_core() {
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let core = Core::new().on(
+            Event::MessageTypingState,
+            Handler::new(move |ctx| tx.lock().unwrap().send(ctx.peer_id()).unwrap()),
+        );
+
+        let dispatcher = Dispatcher::with_core(PoolConfig::new(), core, MockAPI::new());
+        dispatcher.dispatch(request(5, 0)).unwrap();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(Some(5)));
+
+        assert!(matches!(
+            dispatcher.dispatch(CallbackAPIRequest::new(
+                None,
+                1,
+                "unknown",
+                Object::default()
+            )),
+            Err(DispatchError::Event(_))
+        ));
+    }
+}
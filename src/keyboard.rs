@@ -42,21 +42,12 @@ use std::{
 
 /// A keyboard consisting of [`Button`]s that may be shown to the user instead
 /// of the regular keyboard.
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Clone, Default)]
 pub struct Keyboard {
     buttons: Vec<Vec<Button>>,
     one_time: bool,
 }
 
-impl Default for Keyboard {
-    fn default() -> Self {
-        Self {
-            buttons: Vec::new(),
-            one_time: false,
-        }
-    }
-}
-
 impl Keyboard {
     /// Creates a new keyboard.
     ///
@@ -204,12 +195,13 @@ pub enum Action {
 }
 
 /// The color of a button.
-#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, Serialize)]
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, Default, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum Color {
     /// `primary` color, `#5181B8`.
     Primary,
     /// `secondary` color, `#FFFFFF`.
+    #[default]
     Secondary,
     /// `negative` color, `#E64646`.
     Negative,
@@ -217,12 +209,6 @@ pub enum Color {
     Positive,
 }
 
-impl Default for Color {
-    fn default() -> Self {
-        Color::Secondary
-    }
-}
-
 impl Display for Color {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
         f.write_str(match self {
@@ -277,7 +263,7 @@ mod tests {
         fn test_display_parse(expected_str: &str, expected_color: Color) {
             let color: Color = expected_str
                 .parse()
-                .expect(&format!("could not parse color: `{}`", expected_str));
+                .unwrap_or_else(|_| panic!("could not parse color: `{}`", expected_str));
             assert_eq!(color, expected_color);
             let str = format!("{}", color);
             assert_eq!(str, expected_str);
@@ -1,6 +1,8 @@
 //! Crate for creating chat bots for VK (VKontakte) communities.
 //!
-//! > **note:** this crate requires nightly Rust because of Rocket.
+//! > **note:** the Rocket server (the default `rocket` feature) requires
+//! > nightly Rust. Without it, bots can be served on stable Rust with another
+//! > framework, see the [`webhook`] module.
 //!
 //! You can see [`Core`] documentation for information on how to
 //! define bot behavior. In particular, make sure to take a look
@@ -19,13 +21,14 @@
 //! It is not tested as a doc test because [`Bot::start`] never returns.
 //!
 //! ```ignore
-#![doc(include = "../examples/basic.rs")]
+#![cfg_attr(feature = "rocket", doc(include = "../examples/basic.rs"))]
 //! ```
 
-#![feature(external_doc)]
-#![feature(proc_macro_hygiene, decl_macro)]
+#![cfg_attr(feature = "rocket", feature(external_doc))]
+#![cfg_attr(feature = "rocket", feature(proc_macro_hygiene, decl_macro))]
 #![deny(missing_docs)]
 
+#[cfg(feature = "rocket")]
 #[macro_use]
 extern crate rocket;
 
@@ -38,9 +41,26 @@ pub use crate::{
     core::{Core, Event, Handler, Tester},
 };
 
+pub mod api;
+pub mod attachment;
 pub mod bot;
+pub mod callback;
+pub mod config;
 pub mod context;
 pub mod core;
+pub mod dedup;
+pub mod dispatch;
 pub mod keyboard;
+pub mod message;
+pub mod multi;
+pub mod pool;
+pub mod queue;
+pub mod record;
 pub mod request;
 pub mod response;
+pub mod sim;
+#[cfg(test)]
+mod test_server;
+pub mod testing;
+pub mod upload;
+pub mod webhook;
@@ -0,0 +1,302 @@
+//! Typed representation of incoming messages.
+
+use crate::attachment::Attachment;
+use rvk::objects::Integer;
+use serde_derive::Deserialize;
+use std::convert::TryFrom;
+
+/// A private message, as sent by Callback API in `message_*` events.
+///
+/// Also used for forwarded messages and the message being replied to, in
+/// which case some fields may be missing (and are then `0` or empty).
+#[derive(Debug, Deserialize, Clone)]
+pub struct Message {
+    #[serde(default)]
+    id: Integer,
+    conversation_message_id: Option<Integer>,
+    date: Integer,
+    peer_id: Option<Integer>,
+    #[serde(default)]
+    from_id: Integer,
+    #[serde(default)]
+    text: String,
+    payload: Option<String>,
+    #[serde(default)]
+    attachments: Vec<Attachment>,
+    #[serde(default)]
+    fwd_messages: Vec<Message>,
+    reply_message: Option<Box<Message>>,
+    geo: Option<Geo>,
+    #[serde(rename = "ref")]
+    r#ref: Option<String>,
+    ref_source: Option<String>,
+}
+
+impl Message {
+    /// Returns the ID of this message, or `0` if it is not known (e.g. for
+    /// some forwarded messages).
+    pub fn id(&self) -> Integer {
+        self.id
+    }
+
+    /// Returns the ID of this message inside of the conversation, if present.
+    pub fn conversation_message_id(&self) -> Option<Integer> {
+        self.conversation_message_id
+    }
+
+    /// Returns the date this message was sent on (Unix time).
+    pub fn date(&self) -> Integer {
+        self.date
+    }
+
+    /// Returns the peer ID of the conversation this message is in, if present.
+    pub fn peer_id(&self) -> Option<Integer> {
+        self.peer_id
+    }
+
+    /// Returns the ID of the sender of this message.
+    pub fn from_id(&self) -> Integer {
+        self.from_id
+    }
+
+    /// Returns the text of this message.
+    pub fn text(&self) -> &String {
+        &self.text
+    }
+
+    /// Returns the payload of this message, if present.
+    pub fn payload(&self) -> &Option<String> {
+        &self.payload
+    }
+
+    /// Returns the attachments of this message.
+    pub fn attachments(&self) -> &Vec<Attachment> {
+        &self.attachments
+    }
+
+    /// Returns the messages forwarded in this message.
+    pub fn fwd_messages(&self) -> &Vec<Message> {
+        &self.fwd_messages
+    }
+
+    /// Returns the message this message is a reply to, if any.
+    pub fn reply_message(&self) -> Option<&Message> {
+        self.reply_message.as_deref()
+    }
+
+    /// Returns the location attached to this message, if any.
+    pub fn geo(&self) -> &Option<Geo> {
+        &self.geo
+    }
+
+    /// Returns the `ref` parameter of the link the user followed to start the
+    /// conversation, if present.
+    pub fn r#ref(&self) -> &Option<String> {
+        &self.r#ref
+    }
+
+    /// Returns the `ref_source` parameter of the link the user followed to
+    /// start the conversation, if present.
+    pub fn ref_source(&self) -> &Option<String> {
+        &self.ref_source
+    }
+}
+
+/// A location attached to a [`Message`].
+#[derive(Debug, Deserialize, Clone)]
+pub struct Geo {
+    #[serde(rename = "type")]
+    r#type: Option<String>,
+    coordinates: Coordinates,
+    place: Option<Place>,
+}
+
+impl Geo {
+    /// Returns the type of this location (usually `point`), if present.
+    pub fn r#type(&self) -> &Option<String> {
+        &self.r#type
+    }
+
+    /// Returns the coordinates of this location.
+    pub fn coordinates(&self) -> Coordinates {
+        self.coordinates
+    }
+
+    /// Returns the place information of this location, if present.
+    pub fn place(&self) -> &Option<Place> {
+        &self.place
+    }
+}
+
+/// Geographic coordinates.
+///
+/// VK sends these either as an object with `latitude` and `longitude`, or as
+/// a string with both numbers separated by a space; both are accepted.
+#[derive(Debug, Deserialize, Copy, Clone, PartialEq)]
+#[serde(try_from = "RawCoordinates")]
+pub struct Coordinates {
+    latitude: f64,
+    longitude: f64,
+}
+
+impl Coordinates {
+    /// Creates new [`Coordinates`].
+    pub fn new(latitude: f64, longitude: f64) -> Self {
+        Self {
+            latitude,
+            longitude,
+        }
+    }
+
+    /// Returns the latitude.
+    pub fn latitude(&self) -> f64 {
+        self.latitude
+    }
+
+    /// Returns the longitude.
+    pub fn longitude(&self) -> f64 {
+        self.longitude
+    }
+}
+
+/// Representations of [`Coordinates`] as sent by VK.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawCoordinates {
+    Object { latitude: f64, longitude: f64 },
+    String(String),
+}
+
+impl TryFrom<RawCoordinates> for Coordinates {
+    type Error = String;
+
+    fn try_from(raw: RawCoordinates) -> Result<Self, Self::Error> {
+        match raw {
+            RawCoordinates::Object {
+                latitude,
+                longitude,
+            } => Ok(Coordinates::new(latitude, longitude)),
+            RawCoordinates::String(s) => {
+                let mut parts = s.split_whitespace().map(str::parse::<f64>);
+
+                match (parts.next(), parts.next(), parts.next()) {
+                    (Some(Ok(latitude)), Some(Ok(longitude)), None) => {
+                        Ok(Coordinates::new(latitude, longitude))
+                    }
+                    _ => Err(format!("invalid coordinates: `{}`", s)),
+                }
+            }
+        }
+    }
+}
+
+/// Information about the place a [`Geo`] points to.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Place {
+    id: Option<Integer>,
+    title: Option<String>,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    country: Option<String>,
+    city: Option<String>,
+    address: Option<String>,
+}
+
+impl Place {
+    /// Returns the ID of this place, if present.
+    pub fn id(&self) -> Option<Integer> {
+        self.id
+    }
+
+    /// Returns the title of this place, if present.
+    pub fn title(&self) -> &Option<String> {
+        &self.title
+    }
+
+    /// Returns the latitude of this place, if present.
+    pub fn latitude(&self) -> Option<f64> {
+        self.latitude
+    }
+
+    /// Returns the longitude of this place, if present.
+    pub fn longitude(&self) -> Option<f64> {
+        self.longitude
+    }
+
+    /// Returns the country of this place, if present.
+    pub fn country(&self) -> &Option<String> {
+        &self.country
+    }
+
+    /// Returns the city of this place, if present.
+    pub fn city(&self) -> &Option<String> {
+        &self.city
+    }
+
+    /// Returns the address of this place, if present.
+    pub fn address(&self) -> &Option<String> {
+        &self.address
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn full() -> Result<(), serde_json::Error> {
+        let msg: Message = serde_json::from_value(json!({
+            "id": 10,
+            "conversation_message_id": 5,
+            "date": 1_500_000_000,
+            "peer_id": 2_000_000_001,
+            "from_id": 1,
+            "text": "hello",
+            "attachments": [
+                {"type": "sticker", "sticker": {"product_id": 1, "sticker_id": 9}},
+                {"type": "audio_message", "audio_message": {"id": 3, "owner_id": 1, "duration": 2}},
+            ],
+            "fwd_messages": [{"date": 1_400_000_000, "from_id": 2, "text": "forwarded"}],
+            "reply_message": {"id": 9, "date": 1_450_000_000, "from_id": 3, "text": "original"},
+            "geo": {
+                "type": "point",
+                "coordinates": {"latitude": 59.9, "longitude": 30.3},
+                "place": {"country": "Russia", "city": "Saint Petersburg"},
+            },
+            "ref": "promo",
+            "ref_source": "site",
+            "important": false,
+        }))?;
+
+        assert_eq!(msg.id(), 10);
+        assert_eq!(msg.conversation_message_id(), Some(5));
+        assert_eq!(msg.peer_id(), Some(2_000_000_001));
+        assert_eq!(msg.text(), "hello");
+        assert_eq!(msg.attachments().len(), 2);
+        assert_eq!(msg.fwd_messages()[0].text(), "forwarded");
+        assert_eq!(msg.fwd_messages()[0].id(), 0);
+        assert_eq!(msg.reply_message().map(Message::id), Some(9));
+        assert_eq!(msg.r#ref(), &Some("promo".to_string()));
+        assert_eq!(msg.ref_source(), &Some("site".to_string()));
+
+        let geo = msg.geo().as_ref().expect("no geo");
+        assert_eq!(geo.coordinates(), Coordinates::new(59.9, 30.3));
+        assert_eq!(
+            geo.place().as_ref().and_then(|p| p.city().clone()),
+            Some("Saint Petersburg".to_string())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn coordinates_as_string() -> Result<(), serde_json::Error> {
+        let coordinates: Coordinates = serde_json::from_value(json!("59.9 30.3"))?;
+        assert_eq!(coordinates, Coordinates::new(59.9, 30.3));
+
+        assert!(serde_json::from_value::<Coordinates>(json!("59.9")).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,344 @@
+//! Serving several communities from one server with a [`MultiBot`].
+//!
+//! Each community is a separate [`Bot`], with its own VK token, confirmation
+//! token, secret, [`Core`](crate::Core) and options (queue, worker pool,
+//! recorder, ...). Requests are routed to a community by their `group_id`,
+//! or, for communities added with [`MultiBot::community_at`], by the path
+//! they were sent to. The port and [`ServerConfig`] given to each [`Bot`] are
+//! not used, as only the [`MultiBot`] listens.
+//!
+//! Handlers shared between communities can tell which one they serve using
+//! [`Context::group_id`](crate::Context::group_id).
+//!
+//! ```no_run
+//! use vk_bot::{multi::MultiBot, Bot, Core};
+//!
+//! let core = Core::new();
+//! let news = Bot::builder()
+//!     .vk_token("...")
+//!     .confirmation_token("f123456")
+//!     .group_id(1)
+//!     .core(core.clone())
+//!     .build()?;
+//! let shop = Bot::builder()
+//!     .vk_token("...")
+//!     .confirmation_token("a654321")
+//!     .group_id(2)
+//!     .core(core)
+//!     .build()?;
+//!
+//! // Both communities can use `http://host:12345/`, and the shop can also use
+//! // `http://host:12345/shop`.
+//! let multi = MultiBot::new(12345).community(news).community_at("shop", shop);
+//! # #[cfg(feature = "rocket")]
+//! multi.start();
+//! # Ok::<(), vk_bot::config::ConfigError>(())
+//! ```
+
+#[cfg(feature = "rocket")]
+use crate::bot::rocket_response;
+use crate::{
+    bot::{Bot, ServerConfig},
+    webhook::{self, Response, Webhook},
+};
+#[cfg(feature = "rocket")]
+use rocket::{http::Status, Rocket, Route, State};
+#[cfg(feature = "rocket")]
+use rocket_contrib::json::Json;
+use serde_json::Value;
+#[cfg(feature = "rocket")]
+use std::path::PathBuf;
+use std::{collections::HashMap, convert::TryFrom};
+
+/// A server which hands received requests to the [`Bot`] of the community
+/// they are for.
+#[derive(Debug)]
+pub struct MultiBot {
+    server: ServerConfig,
+    bots: HashMap<i32, Bot>,
+    paths: HashMap<String, i32>,
+}
+
+impl MultiBot {
+    /// Creates a new [`MultiBot`] listening on `port`, without communities.
+    pub fn new(port: u16) -> Self {
+        Self {
+            server: ServerConfig::new(port),
+            bots: HashMap::new(),
+            paths: HashMap::new(),
+        }
+    }
+
+    /// Replaces the [`ServerConfig`] of this [`MultiBot`] (including the port
+    /// given to [`MultiBot::new`]). Paths of communities are relative to its
+    /// path.
+    pub fn with_server_config(mut self, server: ServerConfig) -> Self {
+        self.server = server;
+        self
+    }
+
+    /// Adds the community of `bot`, which receives requests with its group
+    /// ID. A community with the same group ID is replaced.
+    pub fn community(mut self, bot: Bot) -> Self {
+        self.bots.insert(bot.group_id(), bot);
+        self
+    }
+
+    /// Adds the community of `bot` like [`MultiBot::community`], and also
+    /// routes requests sent to `path` (relative to the path of the server,
+    /// e.g. `shop` for `/shop`) to it.
+    pub fn community_at(mut self, path: &str, bot: Bot) -> Self {
+        self.paths
+            .insert(path.trim_matches('/').into(), bot.group_id());
+        self.community(bot)
+    }
+
+    /// Returns the [`Bot`] of the community `group_id`, if any.
+    pub fn bot(&self, group_id: i32) -> Option<&Bot> {
+        self.bots.get(&group_id)
+    }
+
+    /// Returns the [`Bot`]s of all communities, in no particular order.
+    pub fn bots(&self) -> impl Iterator<Item = &Bot> {
+        self.bots.values()
+    }
+
+    /// Returns the [`ServerConfig`] of this [`MultiBot`].
+    pub fn server_config(&self) -> &ServerConfig {
+        &self.server
+    }
+}
+
+#[cfg(feature = "rocket")]
+impl MultiBot {
+    /// Starts this [`MultiBot`], consuming `self`.
+    ///
+    /// # Panics
+    /// - if the server configuration is invalid (see
+    ///   [`ServerConfig::validate`](crate::bot::ServerConfig::validate)),
+    /// - if Rocket was not able to launch.
+    pub fn start(self) -> ! {
+        info!("starting bot for {} communities...", self.bots.len());
+
+        let config = self
+            .server
+            .rocket_config()
+            .unwrap_or_else(|e| panic!("{}", e));
+        let path = self.server.mount_point().to_string();
+
+        let err = self.mount(rocket::custom(config), &path).launch();
+
+        panic!("{}", err);
+    }
+
+    /// Returns the routes which handle Callback API requests. They need a
+    /// [`MultiBot`] in Rocket's managed state; see [`MultiBot::mount`].
+    pub fn routes() -> Vec<Route> {
+        routes![post, post_path, get]
+    }
+
+    /// Mounts the routes of this [`MultiBot`] at `path` on `rocket`, an
+    /// existing Rocket application, and hands `self` to it as managed state,
+    /// consuming `self`. See [`Bot::mount`].
+    pub fn mount(self, rocket: Rocket, path: &str) -> Rocket {
+        rocket.mount(path, Self::routes()).manage(self)
+    }
+}
+
+impl Webhook for MultiBot {
+    /// Finds the [`Bot`] for a request sent to `path` (relative to the path
+    /// of the server), or by its group ID if `path` is `/`, and lets it
+    /// respond.
+    fn respond(&self, path: &str, data: &Value) -> Response {
+        let path = path.trim_matches('/');
+
+        let bot = if path.is_empty() {
+            let group_id = match data.get("group_id").and_then(Value::as_i64) {
+                Some(group_id) => group_id,
+                None => {
+                    debug!("received a POST request without `group_id`");
+                    return Response::error(webhook::UNPROCESSABLE_ENTITY);
+                }
+            };
+
+            i32::try_from(group_id)
+                .ok()
+                .and_then(|id| self.bots.get(&id))
+        } else {
+            self.paths.get(path).and_then(|id| self.bots.get(id))
+        };
+
+        match bot {
+            Some(bot) => bot.respond("/", data),
+            None if path.is_empty() => {
+                debug!("received a POST request for an unknown community");
+                Response::error(webhook::FORBIDDEN)
+            }
+            None => {
+                debug!("received a POST request to an unknown path `{}`", path);
+                Response::error(webhook::NOT_FOUND)
+            }
+        }
+    }
+}
+
+/// Handles `GET` requests by returning
+/// [`rocket::http::Status::MethodNotAllowed`].
+#[cfg(feature = "rocket")]
+#[get("/")]
+fn get() -> Status {
+    debug!("received a GET request");
+    Status::MethodNotAllowed
+}
+
+/// Handles `POST` requests, routing them by group ID.
+#[cfg(feature = "rocket")]
+#[post("/", format = "json", data = "<data>")]
+fn post(data: Json<Value>, state: State<MultiBot>) -> Result<String, Status> {
+    rocket_response(state.respond("/", &data))
+}
+
+/// Handles `POST` requests, routing them by path.
+#[cfg(feature = "rocket")]
+#[post("/<path..>", format = "json", data = "<data>", rank = 2)]
+fn post_path(path: PathBuf, data: Json<Value>, state: State<MultiBot>) -> Result<String, Status> {
+    let path: Vec<_> = path.iter().map(|s| s.to_string_lossy()).collect();
+    rocket_response(state.respond(&path.join("/"), &data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{api::MockAPI, core::Handler, Core, Event};
+    use serde_json::json;
+    use std::sync::Arc;
+
+    fn multi_bot(api: &Arc<MockAPI>) -> MultiBot {
+        let core = Core::new().on(
+            Event::MessageTypingState,
+            Handler::new(|ctx| {
+                let text = format!("Community {}", ctx.group_id());
+                ctx.response().set_message(&text);
+                ctx.send().unwrap();
+            }),
+        );
+        let bot = |group_id, secret: &str| {
+            Bot::builder()
+                .vk_token("vk_token")
+                .confirmation_token(&format!("confirmation_{}", group_id))
+                .group_id(group_id)
+                .secret(secret)
+                .core(core.clone())
+                .build()
+                .unwrap()
+                .with_api(Arc::clone(api))
+        };
+
+        MultiBot::new(12345)
+            .community(bot(1, "secret_1"))
+            .community_at("/two/", bot(2, "secret_2"))
+    }
+
+    fn confirmation(group_id: i32, secret: &str) -> Value {
+        json!({"type": "confirmation", "group_id": group_id, "secret": secret})
+    }
+
+    #[test]
+    fn routes_by_group_id() {
+        let api = Arc::new(MockAPI::new());
+        let multi = multi_bot(&api);
+
+        assert_eq!(
+            multi
+                .respond("", &confirmation(1, "secret_1"))
+                .into_result(),
+            Ok("confirmation_1".into())
+        );
+        assert_eq!(
+            multi
+                .respond("/", &confirmation(2, "secret_2"))
+                .into_result(),
+            Ok("confirmation_2".into())
+        );
+        assert_eq!(
+            multi
+                .respond("", &confirmation(1, "secret_2"))
+                .into_result(),
+            Err(webhook::FORBIDDEN)
+        );
+        assert_eq!(
+            multi
+                .respond("", &confirmation(3, "secret_1"))
+                .into_result(),
+            Err(webhook::FORBIDDEN)
+        );
+        assert_eq!(
+            multi
+                .respond("", &json!({"type": "confirmation"}))
+                .into_result(),
+            Err(webhook::UNPROCESSABLE_ENTITY)
+        );
+
+        let typing = |group_id: i32| {
+            json!({
+                "type": "message_typing_state",
+                "group_id": group_id,
+                "secret": format!("secret_{}", group_id),
+                "object": {"state": "typing", "from_id": 7, "to_id": -group_id},
+            })
+        };
+        assert_eq!(multi.respond("", &typing(2)).into_result(), Ok("ok".into()));
+        assert_eq!(multi.respond("", &typing(1)).into_result(), Ok("ok".into()));
+
+        let sent: Vec<_> = api
+            .calls_to("messages.send")
+            .iter()
+            .map(|call| call.param("message").unwrap().to_string())
+            .collect();
+        assert_eq!(sent, ["Community 2", "Community 1"]);
+    }
+
+    #[test]
+    fn routes_by_path() {
+        let multi = multi_bot(&Arc::new(MockAPI::new()));
+
+        assert_eq!(
+            multi
+                .respond("two", &confirmation(2, "secret_2"))
+                .into_result(),
+            Ok("confirmation_2".into())
+        );
+        // The group ID still has to match the community of the path.
+        assert_eq!(
+            multi
+                .respond("two", &confirmation(1, "secret_1"))
+                .into_result(),
+            Err(webhook::FORBIDDEN)
+        );
+        assert_eq!(
+            multi
+                .respond("one", &confirmation(1, "secret_1"))
+                .into_result(),
+            Err(webhook::NOT_FOUND)
+        );
+
+        assert!(multi.bot(2).is_some());
+        assert_eq!(multi.bots().count(), 2);
+    }
+
+    #[test]
+    #[cfg(feature = "rocket")]
+    fn mount() {
+        let multi = multi_bot(&Arc::new(MockAPI::new()));
+
+        let rocket = multi.mount(rocket::ignite(), "/vk");
+        assert_eq!(
+            post_path(
+                PathBuf::from("two"),
+                Json(confirmation(2, "secret_2")),
+                State::from(&rocket).unwrap(),
+            ),
+            Ok("confirmation_2".into())
+        );
+    }
+}
@@ -0,0 +1,481 @@
+//! Background processing of Callback API requests.
+//!
+//! By default, [`Bot`](crate::Bot) handles a request before responding to VK
+//! with `ok`, so a slow handler makes VK time out and deliver the event again.
+//! With a [`WorkerPool`] (see
+//! [`Bot::with_worker_pool`](crate::Bot::with_worker_pool)), requests are only
+//! enqueued, and handled by worker threads after `ok` was sent.
+//!
+//! Each job is submitted with a key (the peer ID, see
+//! [`Dispatcher`](crate::dispatch::Dispatcher)). Jobs with the same key are run
+//! one at a time, in the order they were submitted, while jobs with different
+//! keys are run in parallel by any idle worker, so a slow conversation does not
+//! hold up others.
+
+use rvk::objects::Integer;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::{self, Debug, Display, Formatter},
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex},
+    thread,
+};
+
+/// What to do when a job is submitted to a full [`WorkerPool`].
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, Default)]
+pub enum Backpressure {
+    /// Wait until there is free space, delaying the response to VK.
+    Block,
+    /// Return [`SubmitError::Full`], so that an error is returned to VK, which
+    /// will then deliver the event again later.
+    #[default]
+    Reject,
+}
+
+/// Configuration of a [`WorkerPool`].
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    workers: usize,
+    capacity: usize,
+    backpressure: Backpressure,
+    ordered: bool,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            workers: 4,
+            capacity: 1024,
+            backpressure: Default::default(),
+            ordered: true,
+        }
+    }
+}
+
+impl PoolConfig {
+    /// Creates a new [`PoolConfig`] with default values: 4 workers, up to 1024
+    /// waiting jobs, [`Backpressure::Reject`], and jobs with the same key run
+    /// in order.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Sets the number of worker threads, i.e. how many jobs may run at once.
+    ///
+    /// # Panics
+    /// - if `workers` is `0`.
+    pub fn workers(mut self, workers: usize) -> Self {
+        if workers == 0 {
+            panic!("there must be at least one worker");
+        }
+
+        self.workers = workers;
+        self
+    }
+
+    /// Sets the maximum number of jobs waiting to be run.
+    ///
+    /// # Panics
+    /// - if `capacity` is `0`.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        if capacity == 0 {
+            panic!("capacity must be at least 1");
+        }
+
+        self.capacity = capacity;
+        self
+    }
+
+    /// Sets what to do when the pool is full.
+    pub fn backpressure(mut self, backpressure: Backpressure) -> Self {
+        self.backpressure = backpressure;
+        self
+    }
+
+    /// Sets whether jobs with the same key are run one at a time, in order
+    /// (`true` by default). If `false`, keys are ignored, and all jobs are run
+    /// in parallel.
+    pub fn ordered(mut self, ordered: bool) -> Self {
+        self.ordered = ordered;
+        self
+    }
+}
+
+/// A job run by a [`WorkerPool`].
+type Job = Box<dyn FnOnce() + Send>;
+
+/// The key jobs are ordered by.
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+enum Key {
+    /// Jobs for a peer.
+    Peer(Integer),
+    /// A job which does not need to be ordered with any other (identified by
+    /// a unique number).
+    Unordered(u64),
+}
+
+/// Jobs waiting to be run.
+#[derive(Default)]
+struct State {
+    /// Waiting jobs of each key which is either in `ready` or is being run.
+    jobs: HashMap<Key, VecDeque<Job>>,
+    /// Keys whose next job may be run right away, in the order of their turn.
+    ready: VecDeque<Key>,
+    /// The number of waiting jobs.
+    len: usize,
+    /// The next number for [`Key::Unordered`].
+    next_unordered: u64,
+    closed: bool,
+}
+
+/// State shared between a [`WorkerPool`] and its workers.
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    /// Notified when a key becomes ready, or the pool is closed.
+    ready: Condvar,
+    /// Notified when a job is taken out of the queue.
+    not_full: Condvar,
+}
+
+/// A fixed set of worker threads with a bounded queue of jobs. See the
+/// [module-level documentation](self).
+///
+/// Dropping a [`WorkerPool`] waits until all submitted jobs are run.
+pub struct WorkerPool {
+    config: PoolConfig,
+    shared: Arc<Shared>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    /// Creates a new [`WorkerPool`], starting its worker threads.
+    pub fn new(config: PoolConfig) -> Self {
+        let shared = Arc::new(Shared::default());
+
+        let workers = (0..config.workers)
+            .map(|i| {
+                let shared = Arc::clone(&shared);
+
+                thread::Builder::new()
+                    .name(format!("vk-bot-worker-{}", i))
+                    .spawn(move || work(&shared, i))
+                    .expect("failed to spawn worker thread")
+            })
+            .collect();
+
+        Self {
+            config,
+            shared,
+            workers,
+        }
+    }
+
+    /// Returns the configuration of this pool.
+    pub fn config(&self) -> &PoolConfig {
+        &self.config
+    }
+
+    /// Returns the number of jobs waiting to be run (not including the ones
+    /// being run).
+    pub fn len(&self) -> usize {
+        self.shared.state.lock().unwrap().len
+    }
+
+    /// Returns `true` if no jobs are waiting to be run.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Submits a job to be run by a worker.
+    ///
+    /// Jobs with the same `key` (peer ID) are run one at a time, in the order
+    /// they were submitted (unless disabled via [`PoolConfig::ordered`]). Jobs
+    /// without a key are not ordered.
+    ///
+    /// # Errors
+    /// - [`SubmitError::Full`] if there are already as many waiting jobs as
+    ///   the capacity of the pool, and [`Backpressure::Reject`] is used.
+    pub fn submit<F>(&self, key: Option<Integer>, job: F) -> Result<(), SubmitError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut state = self.shared.state.lock().unwrap();
+
+        while state.len >= self.config.capacity {
+            match self.config.backpressure {
+                Backpressure::Block => state = self.shared.not_full.wait(state).unwrap(),
+                Backpressure::Reject => return Err(SubmitError::Full),
+            }
+        }
+
+        if state.closed {
+            return Err(SubmitError::Stopped);
+        }
+
+        let key = match key {
+            Some(peer_id) if self.config.ordered => Key::Peer(peer_id),
+            _ => {
+                state.next_unordered += 1;
+                Key::Unordered(state.next_unordered)
+            }
+        };
+
+        let State { jobs, ready, .. } = &mut *state;
+        jobs.entry(key)
+            .or_insert_with(|| {
+                // Not ready and not being run yet.
+                ready.push_back(key);
+                VecDeque::new()
+            })
+            .push_back(Box::new(job));
+        state.len += 1;
+
+        self.shared.ready.notify_one();
+        Ok(())
+    }
+}
+
+/// A worker thread of a [`WorkerPool`]: runs jobs until the pool is closed and
+/// there are no more ready keys.
+fn work(shared: &Shared, i: usize) {
+    let mut state = shared.state.lock().unwrap();
+
+    loop {
+        let key = match state.ready.pop_front() {
+            Some(key) => key,
+            None if state.closed => return,
+            None => {
+                state = shared.ready.wait(state).unwrap();
+                continue;
+            }
+        };
+
+        let job = state
+            .jobs
+            .get_mut(&key)
+            .and_then(VecDeque::pop_front)
+            .expect("ready key without jobs");
+        state.len -= 1;
+        shared.not_full.notify_one();
+        drop(state);
+
+        if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+            error!("a job panicked in worker {}", i);
+        }
+
+        state = shared.state.lock().unwrap();
+        if state.jobs[&key].is_empty() {
+            state.jobs.remove(&key);
+        } else {
+            // Let other keys go first.
+            state.ready.push_back(key);
+            shared.ready.notify_one();
+        }
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.ready.notify_all();
+        self.shared.not_full.notify_all();
+
+        for worker in self.workers.drain(..) {
+            if worker.join().is_err() {
+                error!("worker thread panicked");
+            }
+        }
+    }
+}
+
+impl Debug for WorkerPool {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("WorkerPool")
+            .field("config", &self.config)
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+/// Error type for [`WorkerPool::submit`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SubmitError {
+    /// The pool is full.
+    Full,
+    /// The pool has stopped.
+    Stopped,
+}
+
+impl Display for SubmitError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            SubmitError::Full => f.write_str("worker pool is full"),
+            SubmitError::Stopped => f.write_str("worker pool has stopped"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::{mpsc, Barrier},
+        time::Duration,
+    };
+
+    #[test]
+    fn same_key_in_order() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+
+        {
+            let pool = WorkerPool::new(PoolConfig::new().workers(3).capacity(300));
+
+            for i in 0..100 {
+                let log = Arc::clone(&log);
+                pool.submit(Some(i % 5), move || {
+                    if i % 7 == 0 {
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                    log.lock().unwrap().push((i % 5, i));
+                })
+                .unwrap();
+            }
+        }
+
+        let log = log.lock().unwrap();
+        assert_eq!(log.len(), 100);
+
+        for key in 0..5 {
+            let order: Vec<_> = log
+                .iter()
+                .filter(|(k, _)| *k == key)
+                .map(|(_, i)| *i)
+                .collect();
+            let mut sorted = order.clone();
+            sorted.sort();
+            assert_eq!(order, sorted);
+        }
+    }
+
+    #[test]
+    fn different_keys_in_parallel() {
+        let pool = WorkerPool::new(PoolConfig::new().workers(2));
+        let barrier = Arc::new(Barrier::new(3));
+
+        for key in 0..2 {
+            let barrier = Arc::clone(&barrier);
+            pool.submit(Some(key), move || {
+                barrier.wait();
+            })
+            .unwrap();
+        }
+
+        // Would deadlock if the jobs were run one at a time.
+        barrier.wait();
+    }
+
+    #[test]
+    fn slow_key_does_not_block_others() {
+        let pool = WorkerPool::new(PoolConfig::new().workers(2));
+        let (resume_tx, resume_rx) = mpsc::channel::<()>();
+        let (tx, rx) = mpsc::channel();
+
+        // Keys 0 and 2 would be assigned to the same worker by hashing.
+        pool.submit(Some(0), move || resume_rx.recv().unwrap())
+            .unwrap();
+        for i in 0..3 {
+            let tx = tx.clone();
+            pool.submit(Some(2), move || tx.send(i).unwrap()).unwrap();
+        }
+
+        for i in 0..3 {
+            assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(i));
+        }
+        resume_tx.send(()).unwrap();
+    }
+
+    #[test]
+    fn unordered() {
+        let pool = WorkerPool::new(PoolConfig::new().workers(2).ordered(false));
+        let barrier = Arc::new(Barrier::new(3));
+
+        for _ in 0..2 {
+            let barrier = Arc::clone(&barrier);
+            pool.submit(Some(1), move || {
+                barrier.wait();
+            })
+            .unwrap();
+        }
+
+        // Would deadlock if the jobs were run one at a time.
+        barrier.wait();
+    }
+
+    #[test]
+    fn block_when_full() {
+        let pool = Arc::new(WorkerPool::new(
+            PoolConfig::new()
+                .workers(1)
+                .capacity(1)
+                .backpressure(Backpressure::Block),
+        ));
+        let (resume_tx, resume_rx) = mpsc::channel::<()>();
+        let (started_tx, started_rx) = mpsc::channel();
+
+        pool.submit(None, move || {
+            started_tx.send(()).unwrap();
+            resume_rx.recv().unwrap();
+        })
+        .unwrap();
+        started_rx.recv().unwrap();
+        pool.submit(None, || {}).unwrap();
+
+        let (submitted_tx, submitted_rx) = mpsc::channel();
+        let submitter = {
+            let pool = Arc::clone(&pool);
+            thread::spawn(move || {
+                pool.submit(None, || {}).unwrap();
+                submitted_tx.send(()).unwrap();
+            })
+        };
+
+        assert!(submitted_rx
+            .recv_timeout(Duration::from_millis(50))
+            .is_err());
+        resume_tx.send(()).unwrap();
+        submitted_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        submitter.join().unwrap();
+    }
+
+    #[test]
+    fn reject_when_full() {
+        let pool = WorkerPool::new(PoolConfig::new().workers(1).capacity(1));
+        let (started_tx, started_rx) = mpsc::channel();
+        let (resume_tx, resume_rx) = mpsc::channel::<()>();
+
+        pool.submit(None, move || {
+            started_tx.send(()).unwrap();
+            resume_rx.recv().unwrap();
+        })
+        .unwrap();
+        started_rx.recv().unwrap();
+
+        pool.submit(None, || {}).unwrap();
+        assert_eq!(pool.submit(None, || {}), Err(SubmitError::Full));
+
+        resume_tx.send(()).unwrap();
+    }
+
+    #[test]
+    fn survives_panics() {
+        let (tx, rx) = mpsc::channel();
+        let pool = WorkerPool::new(PoolConfig::new().workers(1));
+
+        pool.submit(None, || panic!("handler panicked")).unwrap();
+        pool.submit(None, move || tx.send(()).unwrap()).unwrap();
+
+        rx.recv_timeout(Duration::from_secs(5))
+            .expect("the worker should keep running");
+    }
+}
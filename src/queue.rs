@@ -0,0 +1,712 @@
+//! Outbound message queue, which keeps the bot within VK API rate limits.
+//!
+//! VK allows communities to make about 20 requests per second. A [`Queue`]
+//! makes VK API calls (mostly `messages.send`) from one worker thread, waiting
+//! for a token of a token bucket before each call, and retrying calls which
+//! failed with [`RETRY_ERROR_CODES`] with exponential backoff. Calls with a
+//! higher [`Priority`] are made first.
+//!
+//! [`Bot`](crate::Bot) owns a [`Queue`], which is used by
+//! [`Context`](crate::Context) and [`Bot::sender`](crate::Bot::sender) for
+//! every VK API call: sending messages, editing, deleting and pinning them via
+//! [`SentMessage`](crate::context::SentMessage), and getting upload servers
+//! and saving files when uploading. Only the file uploads themselves (which
+//! are not VK API calls) are not queued. Calls made directly through
+//! [`Context::api`](crate::Context::api) or [`Bot::api`](crate::Bot::api)
+//! bypass the queue.
+
+use crate::{api::VkAPI, upload::UploadError};
+use rvk::{error::Error, Params};
+use serde_json::Value;
+use std::{
+    collections::VecDeque,
+    fmt::{self, Debug, Formatter},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// VK API error codes after which a call is retried: too many requests per
+/// second (`6`), flood control (`9`), and internal server error (`10`).
+pub const RETRY_ERROR_CODES: [u64; 3] = [6, 9, 10];
+
+/// Function which calls the VK API method with the given name.
+pub type CallFn = Box<dyn FnMut(&str, Params) -> Result<Value, Error> + Send>;
+
+/// Priority of an outgoing message. Messages with a higher priority are sent
+/// before ones with a lower priority; messages with the same priority are sent
+/// in order.
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Priority {
+    /// For messages which should not wait for others, e.g. replies to users.
+    High,
+    /// The default priority.
+    #[default]
+    Normal,
+    /// For messages which may wait, e.g. mailings.
+    Low,
+}
+
+/// Configuration of a [`Queue`].
+#[derive(Debug, Clone)]
+pub struct QueueConfig {
+    requests_per_second: f64,
+    burst: u32,
+    max_retries: u32,
+    backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            requests_per_second: 20.0,
+            burst: 20,
+            max_retries: 5,
+            backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl QueueConfig {
+    /// Creates a new [`QueueConfig`] with default values: 20 requests per
+    /// second with bursts of up to 20 requests, and up to 5 retries with
+    /// backoff starting at 500 milliseconds and doubling up to 30 seconds.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Sets the sustained rate of requests per second.
+    ///
+    /// # Panics
+    /// - if `requests_per_second` is not positive.
+    pub fn requests_per_second(mut self, requests_per_second: f64) -> Self {
+        if requests_per_second <= 0.0 {
+            panic!("requests per second must be positive");
+        }
+
+        self.requests_per_second = requests_per_second;
+        self
+    }
+
+    /// Sets the maximum number of requests which may be made at once after the
+    /// queue was idle (the capacity of the token bucket).
+    ///
+    /// # Panics
+    /// - if `burst` is `0`.
+    pub fn burst(mut self, burst: u32) -> Self {
+        if burst == 0 {
+            panic!("burst must be at least 1");
+        }
+
+        self.burst = burst;
+        self
+    }
+
+    /// Sets how many times a call is retried after failing with one of
+    /// [`RETRY_ERROR_CODES`].
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Sets the delay before the first retry, which doubles after each
+    /// subsequent one, up to `max_backoff`.
+    pub fn backoff(mut self, backoff: Duration, max_backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// Returns the delay before retry number `retry` (starting at `0`).
+    fn backoff_for(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry.min(16));
+        self.backoff
+            .checked_mul(factor)
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff))
+    }
+}
+
+/// A snapshot of [`Queue`] metrics.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct QueueMetrics {
+    enqueued: u64,
+    sent: u64,
+    failed: u64,
+    retried: u64,
+    throttled: u64,
+    pending: u64,
+}
+
+impl QueueMetrics {
+    /// Returns the number of calls enqueued since the queue was created.
+    pub fn enqueued(&self) -> u64 {
+        self.enqueued
+    }
+
+    /// Returns the number of calls which succeeded.
+    pub fn sent(&self) -> u64 {
+        self.sent
+    }
+
+    /// Returns the number of calls which failed (after all retries).
+    pub fn failed(&self) -> u64 {
+        self.failed
+    }
+
+    /// Returns the number of retries made.
+    pub fn retried(&self) -> u64 {
+        self.retried
+    }
+
+    /// Returns how many times the queue had to wait for the rate limiter.
+    pub fn throttled(&self) -> u64 {
+        self.throttled
+    }
+
+    /// Returns the number of calls waiting in the queue.
+    pub fn pending(&self) -> u64 {
+        self.pending
+    }
+}
+
+/// Outbound message queue. See the [module-level documentation](self).
+///
+/// Dropping a [`Queue`] waits until all enqueued messages are sent.
+pub struct Queue {
+    config: QueueConfig,
+    shared: Arc<Shared>,
+    worker: Option<thread::JoinHandle<()>>,
+}
+
+/// State shared between a [`Queue`] and its worker thread.
+#[derive(Default)]
+struct Shared {
+    lanes: Mutex<Lanes>,
+    available: Condvar,
+    enqueued: AtomicU64,
+    sent: AtomicU64,
+    failed: AtomicU64,
+    retried: AtomicU64,
+    throttled: AtomicU64,
+}
+
+/// Enqueued calls, one lane per [`Priority`].
+#[derive(Default)]
+struct Lanes {
+    high: VecDeque<Job>,
+    normal: VecDeque<Job>,
+    low: VecDeque<Job>,
+    closed: bool,
+}
+
+impl Lanes {
+    fn lane(&mut self, priority: Priority) -> &mut VecDeque<Job> {
+        match priority {
+            Priority::High => &mut self.high,
+            Priority::Normal => &mut self.normal,
+            Priority::Low => &mut self.low,
+        }
+    }
+
+    fn pop(&mut self) -> Option<Job> {
+        self.high
+            .pop_front()
+            .or_else(|| self.normal.pop_front())
+            .or_else(|| self.low.pop_front())
+    }
+
+    fn len(&self) -> usize {
+        self.high.len() + self.normal.len() + self.low.len()
+    }
+}
+
+/// An enqueued VK API call.
+struct Job {
+    method: String,
+    params: Params,
+    reply: Option<mpsc::Sender<Result<Value, Error>>>,
+}
+
+impl Queue {
+    /// Creates a new [`Queue`] which makes calls using `api`.
+    pub fn new<A: VkAPI + 'static>(config: QueueConfig, api: A) -> Self {
+        Self::with_call_fn(
+            config,
+            Box::new(move |method, params| api.call_method(method, params)),
+        )
+    }
+
+    /// Creates a new [`Queue`] which makes calls using `call` (e.g. a fake
+    /// one, in tests).
+    pub fn with_call_fn(config: QueueConfig, call: CallFn) -> Self {
+        let shared = Arc::new(Shared::default());
+
+        let worker = {
+            let shared = Arc::clone(&shared);
+            let config = config.clone();
+            thread::Builder::new()
+                .name("vk-bot-queue".into())
+                .spawn(move || work(&shared, &config, call))
+                .expect("failed to spawn queue worker thread")
+        };
+
+        Self {
+            config,
+            shared,
+            worker: Some(worker),
+        }
+    }
+
+    /// Returns the configuration of this queue.
+    pub fn config(&self) -> &QueueConfig {
+        &self.config
+    }
+
+    /// Enqueues a `messages.send` call, and returns immediately. Errors are
+    /// logged and counted in [`QueueMetrics::failed`].
+    pub fn enqueue(&self, priority: Priority, params: Params) {
+        self.push(
+            priority,
+            Job {
+                method: "messages.send".into(),
+                params,
+                reply: None,
+            },
+        );
+    }
+
+    /// Enqueues a `messages.send` call, and blocks until it is made (including
+    /// retries), returning its result.
+    pub fn call(&self, priority: Priority, params: Params) -> Result<Value, Error> {
+        self.call_method(priority, "messages.send", params)
+    }
+
+    /// Enqueues a call of the VK API method named `method`, and blocks until
+    /// it is made (including retries), returning its result.
+    pub fn call_method(
+        &self,
+        priority: Priority,
+        method: &str,
+        params: Params,
+    ) -> Result<Value, Error> {
+        let (tx, rx) = mpsc::channel();
+        self.push(
+            priority,
+            Job {
+                method: method.into(),
+                params,
+                reply: Some(tx),
+            },
+        );
+
+        rx.recv()
+            .unwrap_or_else(|_| Err(Error::Other("queue worker stopped".into())))
+    }
+
+    /// Returns a snapshot of the metrics of this queue.
+    pub fn metrics(&self) -> QueueMetrics {
+        let shared = &self.shared;
+
+        QueueMetrics {
+            enqueued: shared.enqueued.load(Ordering::Relaxed),
+            sent: shared.sent.load(Ordering::Relaxed),
+            failed: shared.failed.load(Ordering::Relaxed),
+            retried: shared.retried.load(Ordering::Relaxed),
+            throttled: shared.throttled.load(Ordering::Relaxed),
+            pending: shared.lanes.lock().unwrap().len() as u64,
+        }
+    }
+
+    fn push(&self, priority: Priority, job: Job) {
+        self.shared
+            .lanes
+            .lock()
+            .unwrap()
+            .lane(priority)
+            .push_back(job);
+        self.shared.enqueued.fetch_add(1, Ordering::Relaxed);
+        self.shared.available.notify_one();
+    }
+}
+
+impl Drop for Queue {
+    fn drop(&mut self) {
+        self.shared.lanes.lock().unwrap().closed = true;
+        self.shared.available.notify_one();
+
+        if let Some(worker) = self.worker.take() {
+            if worker.join().is_err() {
+                error!("queue worker thread panicked");
+            }
+        }
+    }
+}
+
+impl Debug for Queue {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("Queue")
+            .field("config", &self.config)
+            .field("metrics", &self.metrics())
+            .finish()
+    }
+}
+
+/// The worker thread of a [`Queue`]: makes enqueued calls until the queue is
+/// closed and empty.
+fn work(shared: &Shared, config: &QueueConfig, mut call: CallFn) {
+    let mut limiter = RateLimiter::new(config.requests_per_second, config.burst);
+
+    loop {
+        let job = {
+            let mut lanes = shared.lanes.lock().unwrap();
+            loop {
+                match lanes.pop() {
+                    Some(job) => break job,
+                    None if lanes.closed => return,
+                    None => lanes = shared.available.wait(lanes).unwrap(),
+                }
+            }
+        };
+
+        let mut retry = 0;
+        let result = loop {
+            if let Some(wait) = limiter.acquire(Instant::now()) {
+                shared.throttled.fetch_add(1, Ordering::Relaxed);
+                thread::sleep(wait);
+                limiter.acquire(Instant::now());
+            }
+
+            match call(&job.method, job.params.clone()) {
+                Err(Error::API(e))
+                    if RETRY_ERROR_CODES.contains(&e.code()) && retry < config.max_retries =>
+                {
+                    let backoff = config.backoff_for(retry);
+                    debug!(
+                        "retrying `{}` in {:?} after error {}",
+                        job.method,
+                        backoff,
+                        e.code()
+                    );
+
+                    shared.retried.fetch_add(1, Ordering::Relaxed);
+                    retry += 1;
+                    thread::sleep(backoff);
+                }
+                result => break result,
+            }
+        };
+
+        match &result {
+            Ok(_) => shared.sent.fetch_add(1, Ordering::Relaxed),
+            Err(e) => {
+                if job.reply.is_none() {
+                    warn!("enqueued `{}` call failed: {:?}", job.method, e);
+                }
+                shared.failed.fetch_add(1, Ordering::Relaxed)
+            }
+        };
+
+        if let Some(reply) = job.reply {
+            // The caller may have gone away, which is fine.
+            let _ = reply.send(result);
+        }
+    }
+}
+
+/// A [`VkAPI`] which makes calls through a [`Queue`] (with
+/// [`Priority::Normal`]) if there is one, and directly otherwise. Files are
+/// always uploaded directly.
+#[derive(Clone, Copy)]
+pub(crate) struct Queued<'a> {
+    api: &'a dyn VkAPI,
+    queue: Option<&'a Queue>,
+}
+
+impl<'a> Queued<'a> {
+    pub(crate) fn new(api: &'a dyn VkAPI, queue: Option<&'a Queue>) -> Self {
+        Self { api, queue }
+    }
+}
+
+impl VkAPI for Queued<'_> {
+    fn call_method(&self, method: &str, params: Params) -> Result<Value, Error> {
+        match self.queue {
+            Some(queue) => queue.call_method(Priority::Normal, method, params),
+            None => self.api.call_method(method, params),
+        }
+    }
+
+    fn upload_file(
+        &self,
+        url: &str,
+        field: &str,
+        file_name: &str,
+        bytes: Vec<u8>,
+    ) -> Result<Value, UploadError> {
+        self.api.upload_file(url, field, file_name, bytes)
+    }
+}
+
+impl Debug for Queued<'_> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("Queued")
+            .field("queue", &self.queue)
+            .finish()
+    }
+}
+
+/// Token bucket rate limiter.
+#[derive(Debug)]
+struct RateLimiter {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Option<Instant>,
+}
+
+impl RateLimiter {
+    fn new(rate: f64, capacity: u32) -> Self {
+        Self {
+            rate,
+            capacity: f64::from(capacity),
+            tokens: f64::from(capacity),
+            last: None,
+        }
+    }
+
+    /// Takes a token at `now`. If there is none, returns how long to wait
+    /// until there will be one; the token is then taken by calling this method
+    /// again after waiting.
+    fn acquire(&mut self, now: Instant) -> Option<Duration> {
+        if let Some(last) = self.last {
+            let elapsed = now.saturating_duration_since(last).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        }
+        self.last = Some(now);
+
+        // Allow for floating point errors when called right after waiting.
+        if self.tokens >= 1.0 - 1e-9 {
+            self.tokens = (self.tokens - 1.0).max(0.0);
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rvk::error::APIError;
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    /// A fake `messages.send` endpoint, which records the `message` parameter
+    /// of each call.
+    fn fake_send(calls: Arc<Mutex<Vec<String>>>) -> CallFn {
+        Box::new(move |_, params| {
+            let mut calls = calls.lock().unwrap();
+            calls.push(params["message"].clone());
+            Ok(Value::from(calls.len()))
+        })
+    }
+
+    fn params(message: &str) -> Params {
+        let mut params = Params::new();
+        params.insert("message".into(), message.into());
+        params
+    }
+
+    fn api_error(code: u64) -> Error {
+        Error::API(APIError::new(code, "mock error".into(), HashMap::new()))
+    }
+
+    fn fast() -> QueueConfig {
+        QueueConfig::new()
+            .requests_per_second(1000.0)
+            .backoff(Duration::from_millis(1), Duration::from_millis(4))
+    }
+
+    #[test]
+    fn limiter() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(10.0, 2);
+
+        assert_eq!(limiter.acquire(start), None);
+        assert_eq!(limiter.acquire(start), None);
+
+        let wait = limiter.acquire(start).expect("bucket should be empty");
+        assert!((wait.as_secs_f64() - 0.1).abs() < 1e-6);
+
+        assert_eq!(limiter.acquire(start + wait), None);
+        assert!(limiter.acquire(start + wait).is_some());
+
+        // The bucket never holds more than its capacity.
+        let later = start + Duration::from_secs(10);
+        assert_eq!(limiter.acquire(later), None);
+        assert_eq!(limiter.acquire(later), None);
+        assert!(limiter.acquire(later).is_some());
+    }
+
+    #[test]
+    fn backoff_doubles() {
+        let config = QueueConfig::new().backoff(Duration::from_secs(1), Duration::from_secs(5));
+
+        assert_eq!(config.backoff_for(0), Duration::from_secs(1));
+        assert_eq!(config.backoff_for(1), Duration::from_secs(2));
+        assert_eq!(config.backoff_for(2), Duration::from_secs(4));
+        assert_eq!(config.backoff_for(3), Duration::from_secs(5));
+        assert_eq!(config.backoff_for(100), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn call_returns_result() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let queue = Queue::with_call_fn(fast(), fake_send(Arc::clone(&calls)));
+
+        assert_eq!(queue.call(Priority::Normal, params("a")).unwrap(), json!(1));
+        assert_eq!(queue.call(Priority::Normal, params("b")).unwrap(), json!(2));
+
+        let metrics = queue.metrics();
+        assert_eq!(metrics.enqueued(), 2);
+        assert_eq!(metrics.sent(), 2);
+        assert_eq!(metrics.pending(), 0);
+    }
+
+    #[test]
+    fn call_method_uses_method() {
+        let queue = Queue::with_call_fn(
+            fast(),
+            Box::new(|method, _| Ok(Value::from(method.to_string()))),
+        );
+
+        assert_eq!(
+            queue
+                .call_method(Priority::Normal, "messages.pin", Params::new())
+                .unwrap(),
+            json!("messages.pin")
+        );
+        assert_eq!(
+            queue.call(Priority::Normal, params("a")).unwrap(),
+            json!("messages.send")
+        );
+        assert_eq!(queue.metrics().sent(), 2);
+    }
+
+    #[test]
+    fn priority_lanes() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let (started_tx, started_rx) = mpsc::channel();
+        let (resume_tx, resume_rx) = mpsc::channel::<()>();
+
+        let queue = {
+            let calls = Arc::clone(&calls);
+            let mut first = true;
+            Queue::with_call_fn(
+                fast(),
+                Box::new(move |_, params| {
+                    // Block on the first call, so that the rest are enqueued
+                    // before any of them is sent.
+                    if first {
+                        first = false;
+                        started_tx.send(()).unwrap();
+                        resume_rx.recv().unwrap();
+                    }
+
+                    calls.lock().unwrap().push(params["message"].clone());
+                    Ok(Value::from(1))
+                }),
+            )
+        };
+
+        queue.enqueue(Priority::Normal, params("first"));
+        started_rx.recv().unwrap();
+
+        queue.enqueue(Priority::Low, params("low"));
+        queue.enqueue(Priority::Normal, params("normal 1"));
+        queue.enqueue(Priority::High, params("high"));
+        queue.enqueue(Priority::Normal, params("normal 2"));
+        assert_eq!(queue.metrics().pending(), 4);
+
+        resume_tx.send(()).unwrap();
+        drop(queue);
+
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec!["first", "high", "normal 1", "normal 2", "low"]
+        );
+    }
+
+    #[test]
+    fn rate_limited() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let config = QueueConfig::new().requests_per_second(50.0).burst(1);
+        let queue = Queue::with_call_fn(config, fake_send(Arc::clone(&calls)));
+
+        let start = Instant::now();
+        for _ in 0..5 {
+            queue.enqueue(Priority::Normal, params("spam"));
+        }
+        // Returns after all previous messages are sent.
+        queue.call(Priority::Normal, params("spam")).unwrap();
+        let metrics = queue.metrics();
+
+        assert!(start.elapsed() >= Duration::from_millis(90));
+        assert_eq!(calls.lock().unwrap().len(), 6);
+        assert!(metrics.throttled() >= 1);
+    }
+
+    #[test]
+    fn retries_rate_limit_errors() {
+        let mut attempts = 0;
+        let queue = Queue::with_call_fn(
+            fast(),
+            Box::new(move |_, _| {
+                attempts += 1;
+                match attempts {
+                    1 => Err(api_error(6)),
+                    2 => Err(api_error(10)),
+                    _ => Ok(Value::from(attempts)),
+                }
+            }),
+        );
+
+        assert_eq!(queue.call(Priority::High, params("a")).unwrap(), json!(3));
+        assert_eq!(queue.metrics().retried(), 2);
+        assert_eq!(queue.metrics().sent(), 1);
+    }
+
+    #[test]
+    fn gives_up() {
+        let mut attempts = 0;
+        let queue = Queue::with_call_fn(
+            fast().max_retries(2),
+            Box::new(move |_, _| {
+                attempts += 1;
+                if attempts == 1 {
+                    Err(api_error(9))
+                } else {
+                    Err(api_error(901))
+                }
+            }),
+        );
+
+        // Other errors are not retried.
+        assert!(matches!(
+            queue.call(Priority::Normal, params("a")),
+            Err(Error::API(e)) if e.code() == 901
+        ));
+
+        let queue = Queue::with_call_fn(fast().max_retries(2), Box::new(|_, _| Err(api_error(9))));
+        assert!(queue.call(Priority::Normal, params("a")).is_err());
+
+        let metrics = queue.metrics();
+        assert_eq!(metrics.retried(), 2);
+        assert_eq!(metrics.failed(), 1);
+    }
+}
@@ -0,0 +1,295 @@
+//! Recording Callback API requests received by [`Bot`](crate::Bot), and
+//! replaying them.
+//!
+//! A [`Recorder`] (see [`Bot::with_recorder`](crate::Bot::with_recorder))
+//! appends every request which passed the `secret` and group ID checks to a
+//! JSONL file, one [`Record`] per line:
+//!
+//! ```text
+//! {"time":1600000000000,"request":{"type":"message_new","group_id":1,...}}
+//! ```
+//!
+//! The `secret` is not recorded. A recorded file can then be replayed through
+//! a [`Core`] (usually against a [`MockAPI`](crate::api::MockAPI)) with
+//! [`replay_file`], e.g. to reproduce an incident locally, or to keep it as a
+//! regression test fixture. See also `examples/replay.rs`.
+
+use crate::{api::VkAPI, core::Core, request::CallbackAPIRequest};
+use serde::de::Deserialize;
+use serde_derive::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    fmt::{self, Display, Formatter},
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Appends received requests to a JSONL file.
+#[derive(Debug)]
+pub struct Recorder {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl Recorder {
+    /// Opens (or creates) the file at `path` to append records to.
+    ///
+    /// # Errors
+    /// - if the file could not be opened.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Returns the path of the file records are appended to.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends a record of the raw JSON `request`, received at `time`,
+    /// without its `secret`. I/O errors are logged.
+    pub fn record(&self, request: &Value, time: SystemTime) {
+        let mut request = request.clone();
+        if let Some(request) = request.as_object_mut() {
+            request.remove("secret");
+        }
+
+        let line = Record::new(time, request).to_line();
+        if let Err(e) = writeln!(self.file.lock().unwrap(), "{}", line) {
+            warn!("failed to record request to {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/// A recorded request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    time: SystemTime,
+    request: Value,
+}
+
+impl Record {
+    /// Creates a new [`Record`] of the raw JSON `request`, received at `time`.
+    pub fn new(time: SystemTime, request: Value) -> Self {
+        Self { time, request }
+    }
+
+    /// Returns the time the request was received at (with millisecond
+    /// precision).
+    pub fn time(&self) -> SystemTime {
+        self.time
+    }
+
+    /// Returns the raw JSON of the request.
+    pub fn request(&self) -> &Value {
+        &self.request
+    }
+
+    /// Parses the request.
+    ///
+    /// # Errors
+    /// - if it is not a valid [`CallbackAPIRequest`].
+    pub fn parse(&self) -> Result<CallbackAPIRequest, serde_json::Error> {
+        CallbackAPIRequest::deserialize(&self.request)
+    }
+
+    fn to_line(&self) -> String {
+        let millis = self
+            .time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+
+        json!({ "time": millis, "request": self.request }).to_string()
+    }
+
+    fn from_line(line: &str) -> Result<Self, serde_json::Error> {
+        #[derive(Deserialize)]
+        struct Line {
+            time: u64,
+            request: Value,
+        }
+
+        let line: Line = serde_json::from_str(line)?;
+        Ok(Self::new(
+            UNIX_EPOCH + Duration::from_millis(line.time),
+            line.request,
+        ))
+    }
+}
+
+/// Reads the records of a file written by a [`Recorder`], skipping empty
+/// lines.
+///
+/// # Errors
+/// - [`ReplayError::IO`] if the file could not be read,
+/// - [`ReplayError::Line`] if a line is not a valid record.
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<Record>, ReplayError> {
+    let mut records = Vec::new();
+
+    for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let record = Record::from_line(&line).map_err(|e| ReplayError::Line(i + 1, e))?;
+        records.push(record);
+    }
+
+    Ok(records)
+}
+
+/// Handles `records` in order using `core.handle(req, api)`, and returns the
+/// number of handled requests. Requests of unsupported types (e.g.
+/// `confirmation`) are skipped.
+///
+/// # Errors
+/// - [`ReplayError::Record`] if a record is not a valid
+///   [`CallbackAPIRequest`]. The following records are not handled.
+pub fn replay(records: &[Record], core: &Core, api: &dyn VkAPI) -> Result<usize, ReplayError> {
+    let mut handled = 0;
+
+    for (i, record) in records.iter().enumerate() {
+        let req = record.parse().map_err(|e| ReplayError::Record(i + 1, e))?;
+
+        match core.handle(&req, api) {
+            Ok(()) => handled += 1,
+            Err(e) => debug!("skipping record {}: {}", i + 1, e),
+        }
+    }
+
+    Ok(handled)
+}
+
+/// Loads the records of the file at `path`, and replays them. See [`load`]
+/// and [`replay`].
+pub fn replay_file<P: AsRef<Path>>(
+    path: P,
+    core: &Core,
+    api: &dyn VkAPI,
+) -> Result<usize, ReplayError> {
+    replay(&load(path)?, core, api)
+}
+
+/// Error type for [`load`] and [`replay`].
+#[derive(Debug)]
+pub enum ReplayError {
+    /// The file could not be read.
+    IO(io::Error),
+    /// The line with the given (1-based) number of the file is not a valid
+    /// record (returned by [`load`]).
+    Line(usize, serde_json::Error),
+    /// The record with the given (1-based) index in the records is not a
+    /// valid [`CallbackAPIRequest`] (returned by [`replay`]).
+    Record(usize, serde_json::Error),
+}
+
+impl Display for ReplayError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            ReplayError::IO(e) => write!(f, "failed to read records: {}", e),
+            ReplayError::Line(n, e) => write!(f, "invalid record on line {}: {}", n, e),
+            ReplayError::Record(n, e) => write!(f, "invalid record {}: {}", n, e),
+        }
+    }
+}
+
+impl From<io::Error> for ReplayError {
+    fn from(e: io::Error) -> Self {
+        ReplayError::IO(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        api::MockAPI,
+        core::{Event, Handler},
+    };
+    use std::fs;
+
+    fn path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("vk-bot-{}-{}.jsonl", name, std::process::id()))
+    }
+
+    fn request(r#type: &str, text: &str) -> Value {
+        json!({
+            "type": r#type,
+            "group_id": 1,
+            "secret": "secret",
+            "event_id": text,
+            "object": {"id": 1, "date": 1, "peer_id": 5, "from_id": 5, "text": text},
+        })
+    }
+
+    #[test]
+    fn record_and_replay() -> Result<(), ReplayError> {
+        let path = path("record");
+        let _ = fs::remove_file(&path);
+
+        let time = UNIX_EPOCH + Duration::from_millis(1_600_000_000_123);
+        {
+            let recorder = Recorder::create(&path)?;
+            recorder.record(&request("confirmation", ""), time);
+            recorder.record(&request("message_new", "a"), time);
+            recorder.record(&request("message_new", "b"), time);
+        }
+
+        let records = load(&path)?;
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[1].time(), time);
+        assert_eq!(records[1].request()["object"]["text"], "a");
+        assert!(records[1].request().get("secret").is_none());
+
+        let core = Core::new().on(
+            Event::NoMatch,
+            Handler::new(|ctx| {
+                let text = ctx.message().unwrap().text().to_uppercase();
+                ctx.response().set_message(&text);
+                ctx.send().unwrap();
+            }),
+        );
+        let api = MockAPI::new();
+
+        assert_eq!(replay_file(&path, &core, &api)?, 2);
+
+        let sent: Vec<_> = api
+            .calls_to("messages.send")
+            .iter()
+            .map(|call| call.param("message").unwrap().to_string())
+            .collect();
+        assert_eq!(sent, ["A", "B"]);
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_records() {
+        let path = path("invalid");
+        fs::write(&path, "{\"time\": 1, \"request\": {}}\n\nnot json\n").unwrap();
+
+        // The empty line is skipped, but still counted.
+        assert!(matches!(load(&path), Err(ReplayError::Line(3, _))));
+        fs::remove_file(&path).unwrap();
+
+        let records = vec![
+            Record::new(UNIX_EPOCH, json!({"type": "confirmation", "group_id": 1})),
+            Record::new(UNIX_EPOCH, json!({})),
+        ];
+        assert!(matches!(
+            replay(&records, &Core::new(), &MockAPI::new()),
+            Err(ReplayError::Record(2, _))
+        ));
+    }
+}
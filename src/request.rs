@@ -1,8 +1,9 @@
 //! Structs for storing request information.
 
+use crate::message::Message;
 use rvk::objects::Integer;
 use serde_derive::Deserialize;
-use serde_json::Value;
+use serde_json::{Map, Value};
 use std::collections::HashMap;
 
 /// A request received from Callback API.
@@ -14,6 +15,7 @@ pub struct CallbackAPIRequest {
     r#type: String,
     #[serde(default)]
     object: Object,
+    event_id: Option<String>,
 }
 
 impl CallbackAPIRequest {
@@ -24,9 +26,16 @@ impl CallbackAPIRequest {
             group_id,
             r#type: r#type.into(),
             object,
+            event_id: None,
         }
     }
 
+    /// Sets the unique event ID of this request (sent by VK as `event_id`).
+    pub fn with_event_id(mut self, event_id: &str) -> Self {
+        self.event_id = Some(event_id.into());
+        self
+    }
+
     /// Returns the secret sent in this request, if present.
     pub fn secret(&self) -> Option<String> {
         self.secret.clone()
@@ -46,10 +55,17 @@ impl CallbackAPIRequest {
     pub fn object(&self) -> &Object {
         &self.object
     }
+
+    /// Returns the unique ID of the event sent in this request, if present.
+    /// It stays the same when VK redelivers the event.
+    pub fn event_id(&self) -> Option<&str> {
+        self.event_id.as_deref()
+    }
 }
 
 /// An object of a [`CallbackAPIRequest`].
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(from = "RawObject")]
 pub struct Object {
     from_id: Option<Integer>,
     peer_id: Option<Integer>,
@@ -57,27 +73,43 @@ pub struct Object {
     text: Option<String>,
     payload: Option<String>,
     action: Option<Value>,
+    extra: HashMap<String, Value>,
+    message: Option<Message>,
+}
+
+/// The fields of an [`Object`] as they are sent by Callback API.
+#[derive(Deserialize)]
+struct RawObject {
+    from_id: Option<Integer>,
+    peer_id: Option<Integer>,
+    user_id: Option<Integer>,
+    text: Option<String>,
+    payload: Option<String>,
+    action: Option<Value>,
 
     #[serde(flatten)]
     extra: HashMap<String, Value>,
 }
 
-impl Default for Object {
-    fn default() -> Self {
-        Self {
-            from_id: None,
-            peer_id: None,
-            user_id: None,
-            text: None,
-            payload: None,
-            action: None,
-            extra: Default::default(),
-        }
+impl From<RawObject> for Object {
+    fn from(raw: RawObject) -> Self {
+        Object::new(
+            raw.from_id,
+            raw.peer_id,
+            raw.user_id,
+            raw.text,
+            raw.payload,
+            raw.action,
+            raw.extra,
+        )
     }
 }
 
 impl Object {
     /// Creates a new [`Object`].
+    ///
+    /// If the fields (including `extra`) describe a message, it is parsed and
+    /// available via [`Object::message`].
     pub fn new(
         from_id: Option<Integer>,
         peer_id: Option<Integer>,
@@ -87,7 +119,7 @@ impl Object {
         action: Option<Value>,
         extra: HashMap<String, Value>,
     ) -> Self {
-        Self {
+        let mut object = Self {
             from_id,
             peer_id,
             user_id,
@@ -95,6 +127,44 @@ impl Object {
             payload,
             action,
             extra,
+            message: None,
+        };
+
+        object.message = object.parse_message();
+        object
+    }
+
+    /// Tries to parse a [`Message`] from the fields of this [`Object`].
+    /// Returns [`None`] if this object is not a message.
+    fn parse_message(&self) -> Option<Message> {
+        // Objects of events which are not messages never have a date.
+        if !self.extra.contains_key("date") {
+            return None;
+        }
+
+        let mut fields: Map<String, Value> = self
+            .extra
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+
+        let mut insert = |key: &str, value: Option<Value>| {
+            if let Some(value) = value {
+                fields.insert(key.into(), value);
+            }
+        };
+
+        insert("from_id", self.from_id.map(Value::from));
+        insert("peer_id", self.peer_id.map(Value::from));
+        insert("text", self.text.clone().map(Value::from));
+        insert("payload", self.payload.clone().map(Value::from));
+
+        match serde_json::from_value(Value::Object(fields)) {
+            Ok(message) => Some(message),
+            Err(e) => {
+                warn!("failed to parse message object: {}", e);
+                None
+            }
         }
     }
 
@@ -132,4 +202,66 @@ impl Object {
     pub fn extra(&self) -> &HashMap<String, Value> {
         &self.extra
     }
+
+    /// Returns the typed [`Message`] this [`Object`] describes, if it is a
+    /// message (as in `message_new`, `message_reply` and `message_edit`
+    /// events).
+    pub fn message(&self) -> Option<&Message> {
+        self.message.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn message_object() -> Result<(), serde_json::Error> {
+        let obj: Object = serde_json::from_value(json!({
+            "id": 1,
+            "date": 1_500_000_000,
+            "peer_id": 2,
+            "from_id": 2,
+            "text": "hi",
+            "attachments": [{"type": "sticker", "sticker": {"product_id": 1, "sticker_id": 9}}],
+        }))?;
+
+        assert_eq!(obj.text(), &Some("hi".to_string()));
+
+        let msg = obj.message().expect("no message");
+        assert_eq!(msg.id(), 1);
+        assert_eq!(msg.peer_id(), Some(2));
+        assert_eq!(msg.text(), "hi");
+        assert_eq!(msg.attachments().len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn event_id() -> Result<(), serde_json::Error> {
+        let req: CallbackAPIRequest = serde_json::from_value(json!({
+            "type": "message_typing_state",
+            "group_id": 1,
+            "event_id": "a1b2c3",
+            "object": {"from_id": 2},
+        }))?;
+        assert_eq!(req.event_id(), Some("a1b2c3"));
+
+        let req: CallbackAPIRequest =
+            serde_json::from_value(json!({"type": "confirmation", "group_id": 1}))?;
+        assert_eq!(req.event_id(), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn non_message_object() -> Result<(), serde_json::Error> {
+        let obj: Object = serde_json::from_value(json!({"user_id": 1, "key": "abc"}))?;
+
+        assert_eq!(obj.user_id(), &Some(1));
+        assert!(obj.message().is_none());
+
+        Ok(())
+    }
 }
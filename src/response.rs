@@ -1,24 +1,50 @@
 //! Structs for storing response information.
 
-use crate::keyboard::Keyboard;
-use std::fmt::{Display, Error, Formatter};
+use crate::{attachment::Attachment, keyboard::Keyboard, queue::Priority};
+use regex::Regex;
+use rvk::objects::Integer;
+use serde_derive::Serialize;
+use serde_json::Value;
+use std::{
+    convert::TryFrom,
+    fmt::{Display, Error, Formatter},
+    str::FromStr,
+};
+
+/// Maximum number of attachments in one message, as limited by VK.
+pub const MAX_ATTACHMENTS: usize = 10;
+
+/// Maximum length of a message's text in characters, as limited by VK.
+pub const MAX_MESSAGE_LENGTH: usize = 4096;
+
+/// What to do when a [`Response`]'s message is longer than
+/// [`MAX_MESSAGE_LENGTH`].
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, Default)]
+pub enum LongMessage {
+    /// Split the message into several messages, on paragraph, line, sentence
+    /// or word boundaries if possible. Attachments and the keyboard are sent
+    /// with the last part.
+    #[default]
+    Split,
+    /// Cut the message to [`MAX_MESSAGE_LENGTH`] characters, ending it with
+    /// `…`.
+    Truncate,
+    /// Do not send anything, and return
+    /// [`SendError::MessageTooLong`](crate::context::SendError::MessageTooLong).
+    Fail,
+}
 
 /// Manages the bot's current response to a message/event.
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Response {
     message: String,
     attachments: Vec<AttachmentInformation>,
     keyboard: Option<Keyboard>,
-}
-
-impl Default for Response {
-    fn default() -> Self {
-        Self {
-            message: String::new(),
-            attachments: Vec::new(),
-            keyboard: None,
-        }
-    }
+    long_message: LongMessage,
+    reply_to: Option<Integer>,
+    forward_messages: Vec<Integer>,
+    forward: Option<Forward>,
+    priority: Priority,
 }
 
 impl Response {
@@ -33,6 +59,9 @@ impl Response {
     }
 
     /// Attaches another attachment to the response.
+    ///
+    /// There may be more than [`MAX_ATTACHMENTS`] attachments, in which case
+    /// the response will be sent as several messages.
     pub fn attach(&mut self, info: AttachmentInformation) {
         self.attachments.push(info);
     }
@@ -43,10 +72,24 @@ impl Response {
     }
 
     /// Sets a new message text for the response.
+    ///
+    /// The text may be longer than [`MAX_MESSAGE_LENGTH`], see
+    /// [`Response::set_long_message`].
     pub fn set_message(&mut self, msg: &str) {
         self.message = msg.into();
     }
 
+    /// Returns what will be done if the message of this response is too long.
+    pub fn long_message(&self) -> LongMessage {
+        self.long_message
+    }
+
+    /// Sets what to do if the message of this response is longer than
+    /// [`MAX_MESSAGE_LENGTH`]. The default is [`LongMessage::Split`].
+    pub fn set_long_message(&mut self, long_message: LongMessage) {
+        self.long_message = long_message;
+    }
+
     /// Returns the keyboard of this response, if present.
     pub fn keyboard(&self) -> &Option<Keyboard> {
         &self.keyboard
@@ -62,12 +105,204 @@ impl Response {
     pub fn set_keyboard_empty(&mut self) {
         self.keyboard = Some(Default::default());
     }
+
+    /// Returns the ID of the message this response replies to, if set.
+    pub fn reply_to(&self) -> Option<Integer> {
+        self.reply_to
+    }
+
+    /// Sets the ID of the message this response replies to (`reply_to`).
+    ///
+    /// If the response is split into several messages, only the first one is
+    /// a reply. In group chats, message IDs may not be available to
+    /// communities; use [`Response::set_forward`] with `is_reply` instead.
+    pub fn set_reply_to(&mut self, message_id: Integer) {
+        self.reply_to = Some(message_id);
+    }
+
+    /// Returns the IDs of messages forwarded with this response.
+    pub fn forward_messages(&self) -> &Vec<Integer> {
+        &self.forward_messages
+    }
+
+    /// Sets the IDs of messages to forward with this response
+    /// (`forward_messages`). They are sent with the first message.
+    pub fn set_forward_messages(&mut self, message_ids: Vec<Integer>) {
+        self.forward_messages = message_ids;
+    }
+
+    /// Returns the messages forwarded by conversation message IDs with this
+    /// response, if set.
+    pub fn forward(&self) -> &Option<Forward> {
+        &self.forward
+    }
+
+    /// Sets the messages to forward (or reply to, if `is_reply` is set) by
+    /// their conversation message IDs (`forward`). They are sent with the
+    /// first message.
+    pub fn set_forward(&mut self, forward: Forward) {
+        self.forward = Some(forward);
+    }
+
+    /// Returns the priority of this response in the outbound
+    /// [`Queue`](crate::queue::Queue).
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    /// Sets the priority of this response in the outbound
+    /// [`Queue`](crate::queue::Queue) ([`Priority::Normal`] by default).
+    pub fn set_priority(&mut self, priority: Priority) {
+        self.priority = priority;
+    }
+}
+
+/// Messages to forward, identified by their conversation message IDs.
+///
+/// Serialized as the JSON `forward` parameter of `messages.send`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Forward {
+    peer_id: Integer,
+    conversation_message_ids: Vec<Integer>,
+    is_reply: bool,
+}
+
+impl Forward {
+    /// Creates a new [`Forward`] of messages from the conversation with
+    /// `peer_id`.
+    ///
+    /// If `is_reply` is `true`, the message (there must be only one) is
+    /// replied to instead of being forwarded.
+    pub fn new(peer_id: Integer, conversation_message_ids: Vec<Integer>, is_reply: bool) -> Self {
+        Self {
+            peer_id,
+            conversation_message_ids,
+            is_reply,
+        }
+    }
+
+    /// Creates a new [`Forward`] which replies to the message with
+    /// `conversation_message_id` in the conversation with `peer_id`.
+    pub fn reply(peer_id: Integer, conversation_message_id: Integer) -> Self {
+        Self::new(peer_id, vec![conversation_message_id], true)
+    }
+
+    /// Returns the peer ID of the conversation the messages are in.
+    pub fn peer_id(&self) -> Integer {
+        self.peer_id
+    }
+
+    /// Returns the conversation message IDs of the messages.
+    pub fn conversation_message_ids(&self) -> &Vec<Integer> {
+        &self.conversation_message_ids
+    }
+
+    /// Indicates whether the message is replied to instead of being
+    /// forwarded.
+    pub fn is_reply(&self) -> bool {
+        self.is_reply
+    }
+}
+
+/// Type of an [`AttachmentInformation`], i.e. of media that can be attached to
+/// an outgoing message.
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub enum AttachmentType {
+    /// `photo`.
+    Photo,
+    /// `video`.
+    Video,
+    /// `audio`.
+    Audio,
+    /// `doc`, also used for voice messages and graffiti.
+    Doc,
+    /// `wall` (a wall post).
+    Wall,
+    /// `market` (a market item).
+    Market,
+    /// `poll`.
+    Poll,
+}
+
+impl Display for AttachmentType {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        f.write_str(match self {
+            AttachmentType::Photo => "photo",
+            AttachmentType::Video => "video",
+            AttachmentType::Audio => "audio",
+            AttachmentType::Doc => "doc",
+            AttachmentType::Wall => "wall",
+            AttachmentType::Market => "market",
+            AttachmentType::Poll => "poll",
+        })
+    }
+}
+
+/// Error type for `impl FromStr for AttachmentType`.
+#[derive(Debug, Clone, PartialOrd, PartialEq, Eq, Ord)]
+pub struct AttachmentTypeFromStrError(String);
+
+impl Display for AttachmentTypeFromStrError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        write!(f, "unknown attachment type: `{}`", self.0)
+    }
+}
+
+impl FromStr for AttachmentType {
+    type Err = AttachmentTypeFromStrError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "photo" => Ok(AttachmentType::Photo),
+            "video" => Ok(AttachmentType::Video),
+            "audio" => Ok(AttachmentType::Audio),
+            "doc" => Ok(AttachmentType::Doc),
+            "wall" => Ok(AttachmentType::Wall),
+            "market" => Ok(AttachmentType::Market),
+            "poll" => Ok(AttachmentType::Poll),
+
+            _ => Err(AttachmentTypeFromStrError(s.into())),
+        }
+    }
+}
+
+impl TryFrom<&str> for AttachmentType {
+    type Error = <AttachmentType as FromStr>::Err;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        value.parse()
+    }
 }
 
 /// Essentially an attachment's unique ID, possibly with an access key.
-#[derive(Debug)]
+///
+/// Can be parsed from its string form (e.g. `photo1_2_key`), or created from
+/// an attachment of an incoming message, which allows to send received media
+/// back:
+///
+/// ```
+/// # use std::convert::TryFrom;
+/// # use vk_bot::{response::AttachmentInformation, Handler};
+/// Handler::new(|ctx| {
+///     let attachments: Vec<_> = match ctx.message() {
+///         Some(msg) => msg
+///             .attachments()
+///             .iter()
+///             .filter_map(|a| AttachmentInformation::try_from(a).ok())
+///             .collect(),
+///         None => return,
+///     };
+///
+///     for info in attachments {
+///         ctx.response().attach(info);
+///     }
+///
+///     eprintln!("{:?}", ctx.send());
+/// });
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct AttachmentInformation {
-    r#type: String,
+    r#type: AttachmentType,
     owner_id: i64,
     resource_id: i64,
     access_key: Option<String>,
@@ -88,8 +323,120 @@ impl Display for AttachmentInformation {
     }
 }
 
-impl From<(String, i64, i64)> for AttachmentInformation {
-    fn from((r#type, owner_id, resource_id): (String, i64, i64)) -> Self {
+/// Error type for parsing and conversions into [`AttachmentInformation`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AttachmentInformationError {
+    /// The string is not of the `{type}{owner_id}_{resource_id}[_{access_key}]`
+    /// form.
+    InvalidFormat(String),
+    /// The attachment type is unknown, or media of this type can not be sent as
+    /// an attachment (e.g. stickers and links).
+    UnsupportedType(String),
+    /// A field required to identify the attachment is missing.
+    MissingField(&'static str),
+}
+
+impl Display for AttachmentInformationError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        match self {
+            AttachmentInformationError::InvalidFormat(s) => {
+                write!(f, "invalid attachment format: `{}`", s)
+            }
+            AttachmentInformationError::UnsupportedType(t) => {
+                write!(f, "unsupported attachment type: `{}`", t)
+            }
+            AttachmentInformationError::MissingField(field) => {
+                write!(f, "missing attachment field: `{}`", field)
+            }
+        }
+    }
+}
+
+impl From<AttachmentTypeFromStrError> for AttachmentInformationError {
+    fn from(e: AttachmentTypeFromStrError) -> Self {
+        AttachmentInformationError::UnsupportedType(e.0)
+    }
+}
+
+impl FromStr for AttachmentInformation {
+    type Err = AttachmentInformationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let re = Regex::new(r"^([a-z]+)(-?\d+)_(\d+)(?:_([0-9A-Za-z]+))?$").expect("invalid regex");
+        let invalid = || AttachmentInformationError::InvalidFormat(s.into());
+
+        let captures = re.captures(s).ok_or_else(invalid)?;
+
+        Ok(Self {
+            r#type: captures[1].parse()?,
+            owner_id: captures[2].parse().map_err(|_| invalid())?,
+            resource_id: captures[3].parse().map_err(|_| invalid())?,
+            access_key: captures.get(4).map(|key| key.as_str().into()),
+        })
+    }
+}
+
+impl TryFrom<&str> for AttachmentInformation {
+    type Error = <AttachmentInformation as FromStr>::Err;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// Converts an attachment object of an incoming message (as sent by VK, e.g.
+/// `{"type": "photo", "photo": {...}}`).
+impl TryFrom<&Value> for AttachmentInformation {
+    type Error = AttachmentInformationError;
+
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        let r#type = value["type"]
+            .as_str()
+            .ok_or(AttachmentInformationError::MissingField("type"))?;
+
+        Self::from_object(r#type, &value[r#type])
+    }
+}
+
+/// Converts a typed attachment of an incoming message.
+impl TryFrom<&Attachment> for AttachmentInformation {
+    type Error = AttachmentInformationError;
+
+    fn try_from(attachment: &Attachment) -> Result<Self, Self::Error> {
+        use AttachmentType::*;
+
+        let (r#type, owner_id, resource_id, access_key) = match attachment {
+            Attachment::Photo(p) => (Photo, p.owner_id(), p.id(), p.access_key()),
+            Attachment::Video(v) => (Video, v.owner_id(), v.id(), v.access_key()),
+            Attachment::Audio(a) => (Audio, a.owner_id(), a.id(), a.access_key()),
+            Attachment::Doc(d) => (Doc, d.owner_id(), d.id(), d.access_key()),
+            Attachment::AudioMessage(m) => (Doc, m.owner_id(), m.id(), m.access_key()),
+            Attachment::Graffiti(g) => (Doc, g.owner_id(), g.id(), g.access_key()),
+            Attachment::Poll(p) => (Poll, p.owner_id(), p.id(), &None),
+            Attachment::Wall(w) => (
+                Wall,
+                w.owner_id()
+                    .ok_or(AttachmentInformationError::MissingField("owner_id"))?,
+                w.id(),
+                &None,
+            ),
+            Attachment::Sticker(_) => {
+                return Err(AttachmentInformationError::UnsupportedType(
+                    "sticker".into(),
+                ))
+            }
+            Attachment::Link(_) => {
+                return Err(AttachmentInformationError::UnsupportedType("link".into()))
+            }
+            Attachment::Other { r#type, object } => return Self::from_object(r#type, object),
+        };
+
+        Ok(Self::new(r#type, owner_id, resource_id, access_key.clone()))
+    }
+}
+
+impl From<(AttachmentType, i64, i64)> for AttachmentInformation {
+    fn from((r#type, owner_id, resource_id): (AttachmentType, i64, i64)) -> Self {
         Self {
             r#type,
             owner_id,
@@ -99,8 +446,10 @@ impl From<(String, i64, i64)> for AttachmentInformation {
     }
 }
 
-impl From<(String, i64, i64, String)> for AttachmentInformation {
-    fn from((r#type, owner_id, resource_id, access_key): (String, i64, i64, String)) -> Self {
+impl From<(AttachmentType, i64, i64, String)> for AttachmentInformation {
+    fn from(
+        (r#type, owner_id, resource_id, access_key): (AttachmentType, i64, i64, String),
+    ) -> Self {
         Self {
             r#type,
             owner_id,
@@ -113,7 +462,7 @@ impl From<(String, i64, i64, String)> for AttachmentInformation {
 impl AttachmentInformation {
     /// Creates a new [`AttachmentInformation`].
     pub fn new(
-        r#type: String,
+        r#type: AttachmentType,
         owner_id: i64,
         resource_id: i64,
         access_key: Option<String>,
@@ -125,4 +474,172 @@ impl AttachmentInformation {
             access_key,
         }
     }
+
+    /// Converts the object of an attachment of the given type, as sent by VK.
+    fn from_object(r#type: &str, object: &Value) -> Result<Self, AttachmentInformationError> {
+        let r#type = match r#type {
+            "audio_message" | "graffiti" => AttachmentType::Doc,
+            other => other.parse()?,
+        };
+
+        let owner_id = match r#type {
+            // Wall posts of messages may have `to_id` instead of `owner_id`.
+            AttachmentType::Wall => object["owner_id"].as_i64().or(object["to_id"].as_i64()),
+            _ => object["owner_id"].as_i64(),
+        };
+
+        Ok(Self {
+            r#type,
+            owner_id: owner_id.ok_or(AttachmentInformationError::MissingField("owner_id"))?,
+            resource_id: object["id"]
+                .as_i64()
+                .ok_or(AttachmentInformationError::MissingField("id"))?,
+            access_key: object["access_key"].as_str().map(Into::into),
+        })
+    }
+
+    /// Returns the type of this attachment.
+    pub fn r#type(&self) -> AttachmentType {
+        self.r#type
+    }
+
+    /// Returns the ID of the owner of this attachment.
+    pub fn owner_id(&self) -> i64 {
+        self.owner_id
+    }
+
+    /// Returns the ID of this attachment.
+    pub fn resource_id(&self) -> i64 {
+        self.resource_id
+    }
+
+    /// Returns the access key of this attachment, if present.
+    pub fn access_key(&self) -> &Option<String> {
+        &self.access_key
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod attachment_type {
+        use super::*;
+
+        fn test_display_parse(expected_str: &str, expected_type: AttachmentType) {
+            let r#type: AttachmentType = expected_str
+                .parse()
+                .unwrap_or_else(|_| panic!("could not parse attachment type: `{}`", expected_str));
+            assert_eq!(r#type, expected_type);
+            let str = format!("{}", r#type);
+            assert_eq!(str, expected_str);
+        }
+
+        #[test]
+        fn display_and_parse() {
+            test_display_parse("photo", AttachmentType::Photo);
+            test_display_parse("video", AttachmentType::Video);
+            test_display_parse("audio", AttachmentType::Audio);
+            test_display_parse("doc", AttachmentType::Doc);
+            test_display_parse("wall", AttachmentType::Wall);
+            test_display_parse("market", AttachmentType::Market);
+            test_display_parse("poll", AttachmentType::Poll);
+        }
+
+        #[test]
+        #[should_panic(expected = "unknown attachment type")]
+        fn unknown() {
+            panic!("{}", "sticker".parse::<AttachmentType>().unwrap_err());
+        }
+    }
+
+    mod attachment_information {
+        use super::*;
+        use serde_json::json;
+
+        #[test]
+        fn display_and_parse() {
+            for s in &["photo1_2", "photo-1_2_key", "doc123_456_a1b2c3"] {
+                let info: AttachmentInformation = s.parse().expect("could not parse");
+                assert_eq!(info.to_string(), *s);
+            }
+
+            assert_eq!(
+                "wall-1_2".parse(),
+                Ok(AttachmentInformation::new(
+                    AttachmentType::Wall,
+                    -1,
+                    2,
+                    None
+                ))
+            );
+        }
+
+        #[test]
+        fn parse_errors() {
+            assert_eq!(
+                "photo1".parse::<AttachmentInformation>(),
+                Err(AttachmentInformationError::InvalidFormat("photo1".into()))
+            );
+            assert_eq!(
+                "sticker1_2".parse::<AttachmentInformation>(),
+                Err(AttachmentInformationError::UnsupportedType(
+                    "sticker".into()
+                ))
+            );
+        }
+
+        #[test]
+        fn from_value() {
+            let value = json!({
+                "type": "audio_message",
+                "audio_message": {"id": 2, "owner_id": 1, "access_key": "key"},
+            });
+
+            assert_eq!(
+                AttachmentInformation::try_from(&value),
+                Ok(AttachmentInformation::new(
+                    AttachmentType::Doc,
+                    1,
+                    2,
+                    Some("key".into())
+                ))
+            );
+
+            assert_eq!(
+                AttachmentInformation::try_from(&json!({"type": "photo", "photo": {"id": 1}})),
+                Err(AttachmentInformationError::MissingField("owner_id"))
+            );
+        }
+
+        #[test]
+        fn echo() -> Result<(), serde_json::Error> {
+            let values = vec![
+                json!({"type": "photo", "photo": {"id": 2, "owner_id": 1, "access_key": "key"}}),
+                json!({"type": "wall", "wall": {"id": 4, "to_id": -3, "from_id": -3}}),
+                json!({"type": "sticker", "sticker": {"product_id": 1, "sticker_id": 9}}),
+            ];
+
+            let infos = values
+                .into_iter()
+                .map(serde_json::from_value::<Attachment>)
+                .collect::<Result<Vec<_>, _>>()?
+                .iter()
+                .map(|a| AttachmentInformation::try_from(a).map(|info| info.to_string()))
+                .collect::<Vec<_>>();
+
+            assert_eq!(
+                infos,
+                vec![
+                    Ok("photo1_2_key".into()),
+                    Ok("wall-3_4".into()),
+                    Err(AttachmentInformationError::UnsupportedType(
+                        "sticker".into()
+                    )),
+                ]
+            );
+
+            Ok(())
+        }
+    }
 }
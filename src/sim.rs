@@ -0,0 +1,428 @@
+//! An interactive console [`Simulator`], which lets a developer talk to a
+//! [`Core`] in the terminal, without a VK token or any network.
+//!
+//! Lines typed in the simulator are sent to the bot as messages from the
+//! current user (through a [`Harness`]), and the replies are shown as text,
+//! including attachments and keyboards. Lines starting with `:` are simulator
+//! commands:
+//!
+//! - `:press N` (or `:N`) presses the `N`th button of the current keyboard,
+//! - `:geo LATITUDE LONGITUDE` sends a location,
+//! - `:user ID` switches to another user,
+//! - `:keyboard` shows the current keyboard again,
+//! - `:help` lists the commands, and `:quit` exits.
+//!
+//! See `examples/sim.rs`:
+//!
+//! ```no_run
+//! use vk_bot::{sim, Core};
+//!
+//! let core = Core::new(); // The `Core` of your bot.
+//! sim::run(core).unwrap();
+//! ```
+
+use crate::{
+    core::Core,
+    testing::{Harness, Reply},
+};
+use rvk::objects::Integer;
+use serde_json::{json, Value};
+use std::io::{self, BufRead, Write};
+
+/// Default ID of the user talking to the bot in a [`Simulator`].
+pub const DEFAULT_USER_ID: Integer = 1;
+
+const HELP: &str = "\
+commands:
+  :press N, :N          press the Nth button of the keyboard
+  :geo LATITUDE LONGITUDE  send a location
+  :user ID              switch to another user
+  :keyboard             show the keyboard
+  :help                 show this help
+  :quit                 exit";
+
+/// Runs a [`Simulator`] for `core` on the standard input and output, until
+/// `:quit` or the end of input.
+///
+/// # Errors
+/// - if reading from the standard input or writing to the standard output
+///   failed.
+pub fn run(core: Core) -> io::Result<()> {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    Simulator::new(core).run(stdin.lock(), stdout.lock())
+}
+
+/// A console simulator, see the [module-level documentation](self).
+#[derive(Debug)]
+pub struct Simulator {
+    harness: Harness,
+    user_id: Integer,
+    keyboard: Option<Keyboard>,
+}
+
+/// The keyboard shown to the user.
+#[derive(Debug)]
+struct Keyboard {
+    buttons: Vec<Vec<Value>>,
+    one_time: bool,
+}
+
+impl Simulator {
+    /// Creates a new [`Simulator`] for `core`, with the user
+    /// [`DEFAULT_USER_ID`].
+    pub fn new(core: Core) -> Self {
+        Self {
+            harness: Harness::new(core),
+            user_id: DEFAULT_USER_ID,
+            keyboard: None,
+        }
+    }
+
+    /// Sets the ID of the user talking to the bot.
+    pub fn user(mut self, user_id: Integer) -> Self {
+        self.user_id = user_id;
+        self
+    }
+
+    /// Returns the [`Harness`] the bot runs in.
+    pub fn harness(&self) -> &Harness {
+        &self.harness
+    }
+
+    /// Reads lines from `input` and handles them (see [`Simulator::input`]),
+    /// writing the output to `output`, until `:quit` or the end of input.
+    ///
+    /// # Errors
+    /// - if reading or writing failed.
+    pub fn run<R: BufRead, W: Write>(&mut self, input: R, mut output: W) -> io::Result<()> {
+        writeln!(
+            output,
+            "talking as user {}, type :help for help",
+            self.user_id
+        )?;
+
+        let mut lines = input.lines();
+        loop {
+            write!(output, "> ")?;
+            output.flush()?;
+
+            let line = match lines.next() {
+                Some(line) => line?,
+                None => break,
+            };
+
+            if line.trim() == ":quit" {
+                break;
+            }
+
+            for out in self.input(&line) {
+                writeln!(output, "{}", out)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handles one line typed by the developer: sends it as a message, or runs
+    /// a simulator command. Returns the lines to show.
+    pub fn input(&mut self, line: &str) -> Vec<String> {
+        let line = line.trim();
+
+        let command = match line.strip_prefix(':') {
+            Some(command) => command,
+            None => return self.send(json!({ "text": line })),
+        };
+
+        let mut words = command.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (Some("help"), None, None) => HELP.lines().map(Into::into).collect(),
+            (Some("keyboard"), None, None) => match &self.keyboard {
+                Some(keyboard) => keyboard.render(),
+                None => vec!["(no keyboard)".into()],
+            },
+            (Some("user"), Some(id), None) => match id.parse() {
+                Ok(id) => {
+                    self.user_id = id;
+                    self.keyboard = None;
+                    vec![format!("talking as user {}", id)]
+                }
+                Err(_) => vec![format!("invalid user ID: {}", id)],
+            },
+            (Some("geo"), Some(latitude), Some(longitude)) => {
+                match (latitude.parse::<f64>(), longitude.parse::<f64>()) {
+                    (Ok(latitude), Ok(longitude)) => self.send(geo(latitude, longitude, None)),
+                    _ => vec!["invalid coordinates".into()],
+                }
+            }
+            (Some("press"), Some(n), None) | (Some(n), None, None) => match n.parse() {
+                Ok(n) => self.press(n),
+                Err(_) => vec![format!("unknown command: {}, type :help for help", line)],
+            },
+            _ => vec![format!("unknown command: {}, type :help for help", line)],
+        }
+    }
+
+    /// Presses the `n`th (1-based) button of the current keyboard.
+    fn press(&mut self, n: usize) -> Vec<String> {
+        let keyboard = match &self.keyboard {
+            Some(keyboard) => keyboard,
+            None => return vec!["(no keyboard)".into()],
+        };
+
+        let action = match keyboard.buttons.iter().flatten().nth(n.wrapping_sub(1)) {
+            Some(button) => button["action"].clone(),
+            None => return vec![format!("no button {}", n)],
+        };
+
+        let payload = action["payload"].as_str();
+        let message = match action["type"].as_str() {
+            Some("text") => {
+                let mut message = json!({ "text": action["label"] });
+                if let Some(payload) = payload {
+                    message["payload"] = payload.into();
+                }
+                message
+            }
+            Some("location") => geo(0.0, 0.0, payload),
+            other => {
+                return vec![format!(
+                    "can not press a `{}` button",
+                    other.unwrap_or_default()
+                )]
+            }
+        };
+
+        if keyboard.one_time {
+            self.keyboard = None;
+        }
+
+        self.send(message)
+    }
+
+    /// Sends a message with `fields` from the current user, and renders the
+    /// replies.
+    fn send(&mut self, fields: Value) -> Vec<String> {
+        let replies = self
+            .harness
+            .user(self.user_id)
+            .sends(fields)
+            .replies()
+            .to_vec();
+
+        if replies.is_empty() {
+            return vec!["(no reply)".into()];
+        }
+
+        replies
+            .iter()
+            .flat_map(|reply| self.render(reply))
+            .collect()
+    }
+
+    /// Renders a reply, and remembers its keyboard if it was sent to the
+    /// current user.
+    fn render(&mut self, reply: &Reply) -> Vec<String> {
+        let mut out = Vec::new();
+
+        let to_user = reply.peer_id() == Some(self.user_id);
+        let prefix = match reply.peer_id() {
+            _ if to_user => "bot: ".to_string(),
+            Some(peer_id) => format!("bot -> {}: ", peer_id),
+            None => format!("bot -> {}: ", reply.call().param("peer_ids").unwrap_or("?")),
+        };
+
+        let mut lines = reply.text().lines();
+        out.push(format!("{}{}", prefix, lines.next().unwrap_or_default()));
+        let indent = " ".repeat(prefix.len());
+        out.extend(lines.map(|line| format!("{}{}", indent, line)));
+
+        for attachment in reply.attachments() {
+            out.push(format!("{}[{}]", indent, attachment));
+        }
+
+        if let (true, Some(keyboard)) = (to_user, reply.keyboard()) {
+            let keyboard = Keyboard::from_json(&keyboard);
+            if keyboard.buttons.is_empty() {
+                out.push(format!("{}(keyboard removed)", indent));
+                self.keyboard = None;
+            } else {
+                out.extend(
+                    keyboard
+                        .render()
+                        .into_iter()
+                        .map(|l| format!("{}{}", indent, l)),
+                );
+                self.keyboard = Some(keyboard);
+            }
+        }
+
+        out
+    }
+}
+
+impl Keyboard {
+    fn from_json(keyboard: &Value) -> Self {
+        let buttons = keyboard["buttons"]
+            .as_array()
+            .map(|rows| {
+                rows.iter()
+                    .filter_map(Value::as_array)
+                    .filter(|row| !row.is_empty())
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Self {
+            buttons,
+            one_time: keyboard["one_time"].as_bool().unwrap_or_default(),
+        }
+    }
+
+    /// Renders the buttons row by row, numbered for `:press`.
+    fn render(&self) -> Vec<String> {
+        let mut n = 0;
+        let mut out: Vec<String> = self
+            .buttons
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|button| {
+                        n += 1;
+                        let action = &button["action"];
+                        let label = match action["type"].as_str() {
+                            Some("text") | Some("open_app") => {
+                                action["label"].as_str().unwrap_or_default().to_string()
+                            }
+                            Some(other) => format!("<{}>", other),
+                            None => "?".into(),
+                        };
+                        format!("[{}: {}]", n, label)
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .collect();
+
+        if self.one_time {
+            out.push("(one-time keyboard)".into());
+        }
+        out
+    }
+}
+
+/// Returns the fields of a message with a location.
+fn geo(latitude: f64, longitude: f64, payload: Option<&str>) -> Value {
+    let mut message = json!({
+        "text": "",
+        "geo": {
+            "type": "point",
+            "coordinates": { "latitude": latitude, "longitude": longitude },
+        },
+    });
+    if let Some(payload) = payload {
+        message["payload"] = payload.into();
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        core::{Event, Handler},
+        keyboard::{Button, Color, Keyboard},
+        response::{AttachmentInformation, AttachmentType},
+    };
+
+    fn core() -> Core {
+        Core::new()
+            .cmd_prefix("/")
+            .cmd(
+                "start",
+                Handler::new(|ctx| {
+                    let buttons = vec![
+                        vec![
+                            Button::text("Yes", Color::Positive, Some(r#""yes""#.into())),
+                            Button::text("No", Color::Negative, None),
+                        ],
+                        vec![Button::location(None)],
+                    ];
+                    ctx.response().set_message("Welcome!\nContinue?");
+                    ctx.response().set_keyboard(Keyboard::new(buttons, true));
+                    ctx.send().unwrap();
+                }),
+            )
+            .payload(
+                r#""yes""#,
+                Handler::new(|ctx| {
+                    ctx.response().set_message("Great");
+                    ctx.response().attach(AttachmentInformation::new(
+                        AttachmentType::Photo,
+                        1,
+                        2,
+                        None,
+                    ));
+                    ctx.send().unwrap();
+                }),
+            )
+            .on_geo(Handler::new(|ctx| {
+                let coordinates = ctx.coordinates().unwrap();
+                let text = format!("{} {}", coordinates.latitude(), coordinates.longitude());
+                ctx.response().set_message(&text);
+                ctx.send().unwrap();
+            }))
+            .on(
+                Event::NoMatch,
+                Handler::new(|ctx| {
+                    let text = format!("you said {}", ctx.message().unwrap().text());
+                    ctx.response().set_message(&text);
+                    ctx.send().unwrap();
+                }),
+            )
+    }
+
+    #[test]
+    fn conversation() {
+        let mut sim = Simulator::new(core()).user(42);
+
+        assert_eq!(
+            sim.input("/start"),
+            [
+                "bot: Welcome!",
+                "     Continue?",
+                "     [1: Yes] [2: No]",
+                "     [3: <location>]",
+                "     (one-time keyboard)",
+            ]
+        );
+        assert_eq!(sim.input(":press 1"), ["bot: Great", "     [photo1_2]"]);
+        assert_eq!(sim.input(":1"), ["(no keyboard)"]);
+
+        sim.input("/start");
+        assert_eq!(sim.input(":2"), ["bot: you said No"]);
+
+        sim.input("/start");
+        assert_eq!(sim.input(":3"), ["bot: 0 0"]);
+        assert_eq!(sim.input(":geo 55.75 37.62"), ["bot: 55.75 37.62"]);
+
+        assert_eq!(sim.input(":user 7"), ["talking as user 7"]);
+        assert_eq!(sim.input("hi"), ["bot: you said hi"]);
+        assert_eq!(sim.harness().replies().last().unwrap().peer_id(), Some(7));
+
+        assert!(sim.input(":unknown")[0].starts_with("unknown command"));
+    }
+
+    #[test]
+    fn run() -> io::Result<()> {
+        let mut output = Vec::new();
+        Simulator::new(core()).run(&b"hello\n:quit\nignored\n"[..], &mut output)?;
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "talking as user 1, type :help for help\n> bot: you said hello\n> "
+        );
+        Ok(())
+    }
+}
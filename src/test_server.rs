@@ -0,0 +1,61 @@
+//! A minimal local HTTP server for tests which make real HTTP requests.
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    thread,
+};
+
+/// A request received by a [`serve`]d server.
+#[derive(Debug)]
+pub(crate) struct Received {
+    /// Header lines, lowercased.
+    pub(crate) headers: Vec<String>,
+    /// The raw body.
+    pub(crate) body: String,
+}
+
+/// Starts a local server, which accepts one request to any path, and responds
+/// with `response` (with `Content-Type: application/json`). Returns the URL
+/// of `path` on the server, and a handle to get the received request.
+pub(crate) fn serve(path: &str, response: &'static str) -> (String, thread::JoinHandle<Received>) {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind");
+    let url = format!("http://{}{}", listener.local_addr().unwrap(), path);
+
+    let handle = thread::spawn(move || {
+        let (stream, _) = listener.accept().expect("failed to accept");
+        let mut reader = BufReader::new(stream);
+
+        let mut headers = Vec::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).expect("failed to read header");
+            if line.trim_end().is_empty() {
+                break;
+            }
+            headers.push(line.trim_end().to_lowercase());
+        }
+
+        let length = headers
+            .iter()
+            .find_map(|header| header.strip_prefix("content-length:"))
+            .map_or(0, |len| len.trim().parse().expect("invalid Content-Length"));
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).expect("failed to read body");
+
+        write!(
+            reader.get_mut(),
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            response.len(),
+            response
+        )
+        .expect("failed to respond");
+
+        Received {
+            headers,
+            body: String::from_utf8_lossy(&body).into_owned(),
+        }
+    });
+
+    (url, handle)
+}
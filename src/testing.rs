@@ -0,0 +1,482 @@
+//! A [`Harness`] for testing conversations with a bot without any network.
+//!
+//! The harness builds real [`CallbackAPIRequest`]s, feeds them through
+//! [`Core::handle`] against a [`MockAPI`], and captures the messages the
+//! handlers send, in order, as [`Reply`]s:
+//!
+//! ```
+//! use vk_bot::{keyboard::{Button, Color, Keyboard}, testing::Harness, Core, Handler};
+//!
+//! let core = Core::new().cmd_prefix("/").cmd(
+//!     "start",
+//!     Handler::new(|ctx| {
+//!         let button = Button::text("Help", Color::Primary, None);
+//!         ctx.response().set_message("Welcome!");
+//!         ctx.response().set_keyboard(Keyboard::new(vec![vec![button]], false));
+//!         ctx.send().unwrap();
+//!     }),
+//! );
+//!
+//! let harness = Harness::new(core);
+//! harness
+//!     .user(42)
+//!     .says("/start")
+//!     .expect_reply(|r| r.text_contains("Welcome").has_keyboard().has_button("Help"))
+//!     .expect_no_more_replies();
+//! ```
+
+use crate::{
+    api::{Call, MockAPI},
+    core::{Core, Event},
+    request::CallbackAPIRequest,
+};
+use rvk::objects::Integer;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicI64, Ordering};
+
+/// Runs a [`Core`] against a [`MockAPI`], see the
+/// [module-level documentation](self).
+#[derive(Debug)]
+pub struct Harness {
+    core: Core,
+    api: MockAPI,
+    group_id: i32,
+    last_id: AtomicI64,
+}
+
+impl Harness {
+    /// Creates a new [`Harness`] for `core`, with group ID `1`.
+    pub fn new(core: Core) -> Self {
+        Self {
+            core,
+            api: MockAPI::new(),
+            group_id: 1,
+            last_id: AtomicI64::new(0),
+        }
+    }
+
+    /// Sets the group ID of requests built by this harness.
+    pub fn group_id(mut self, group_id: i32) -> Self {
+        self.group_id = group_id;
+        self
+    }
+
+    /// Returns the [`MockAPI`] handlers make calls through, e.g. to script
+    /// responses of other methods, or to assert on them.
+    pub fn api(&self) -> &MockAPI {
+        &self.api
+    }
+
+    /// Returns a [`User`] with the ID `user_id`, who talks to the bot in a
+    /// private conversation.
+    pub fn user(&self, user_id: Integer) -> User<'_> {
+        User {
+            harness: self,
+            user_id,
+        }
+    }
+
+    /// Handles a request for `event` with `object`, and returns the replies
+    /// sent while handling it.
+    ///
+    /// # Panics
+    /// - if `object` is not a valid Callback API object.
+    pub fn event(&self, event: Event, object: Value) -> Step<'_> {
+        let object = serde_json::from_value(object).expect("invalid Callback API object");
+        let event_id = format!("harness-{}", self.next_id());
+        let req = CallbackAPIRequest::new(None, self.group_id, &event.to_string(), object)
+            .with_event_id(&event_id);
+
+        self.handle(&req)
+    }
+
+    /// Handles `req`, and returns the replies sent while handling it.
+    ///
+    /// # Panics
+    /// - if the request's type is not a supported [`Event`].
+    pub fn handle(&self, req: &CallbackAPIRequest) -> Step<'_> {
+        let before = self.api.calls_to("messages.send").len();
+
+        self.core
+            .handle(req, &self.api)
+            .expect("unsupported event type");
+
+        let replies = self.api.calls_to("messages.send")[before..]
+            .iter()
+            .map(Reply::from_call)
+            .collect();
+
+        Step {
+            harness: self,
+            replies,
+            next: 0,
+        }
+    }
+
+    /// Returns all replies sent so far, in order.
+    pub fn replies(&self) -> Vec<Reply> {
+        self.api
+            .calls_to("messages.send")
+            .iter()
+            .map(Reply::from_call)
+            .collect()
+    }
+
+    fn next_id(&self) -> i64 {
+        self.last_id.fetch_add(1, Ordering::SeqCst) + 1
+    }
+}
+
+/// A user talking to the bot through a [`Harness`].
+#[derive(Debug, Clone, Copy)]
+pub struct User<'h> {
+    harness: &'h Harness,
+    user_id: Integer,
+}
+
+impl<'h> User<'h> {
+    /// Returns the ID of this user.
+    pub fn id(&self) -> Integer {
+        self.user_id
+    }
+
+    /// Sends a message with `text` to the bot.
+    pub fn says(&self, text: &str) -> Step<'h> {
+        self.sends(json!({ "text": text }))
+    }
+
+    /// Presses a keyboard button with `label` and `payload`.
+    pub fn presses(&self, label: &str, payload: &str) -> Step<'h> {
+        self.sends(json!({ "text": label, "payload": payload }))
+    }
+
+    /// Sends a message with `fields` (e.g. `attachments` or `geo`) to the bot.
+    /// Fields identifying the message and its sender are filled in.
+    pub fn sends(&self, fields: Value) -> Step<'h> {
+        let id = self.harness.next_id();
+        let mut message = json!({
+            "id": id,
+            "conversation_message_id": id,
+            "date": id,
+            "peer_id": self.user_id,
+            "from_id": self.user_id,
+            "text": "",
+        });
+
+        if let (Some(message), Value::Object(fields)) = (message.as_object_mut(), fields) {
+            message.extend(fields);
+        }
+
+        self.harness.event(Event::MessageNew, message)
+    }
+}
+
+/// Replies sent while handling one request in a [`Harness`], to be checked in
+/// order.
+#[derive(Debug)]
+pub struct Step<'h> {
+    harness: &'h Harness,
+    replies: Vec<Reply>,
+    next: usize,
+}
+
+impl<'h> Step<'h> {
+    /// Returns all replies sent while handling the request.
+    pub fn replies(&self) -> &[Reply] {
+        &self.replies
+    }
+
+    /// Returns the [`Harness`] this step was made in.
+    pub fn harness(&self) -> &'h Harness {
+        self.harness
+    }
+
+    /// Checks the next reply using `check`, which usually calls assertion
+    /// methods of [`Reply`].
+    ///
+    /// # Panics
+    /// - if there are no more replies.
+    #[track_caller]
+    pub fn expect_reply<F>(mut self, check: F) -> Self
+    where
+        F: FnOnce(&Reply) -> &Reply,
+    {
+        match self.replies.get(self.next) {
+            Some(reply) => {
+                check(reply);
+                self.next += 1;
+                self
+            }
+            None => panic!(
+                "expected reply #{}, but only {} were sent",
+                self.next + 1,
+                self.replies.len()
+            ),
+        }
+    }
+
+    /// Checks that no replies were sent at all.
+    ///
+    /// # Panics
+    /// - if there were any.
+    #[track_caller]
+    pub fn expect_no_reply(self) -> Self {
+        assert!(
+            self.replies.is_empty(),
+            "expected no replies, but got {:?}",
+            self.replies
+        );
+        self
+    }
+
+    /// Checks that all replies were checked by [`Step::expect_reply`].
+    ///
+    /// # Panics
+    /// - if there are unchecked replies.
+    #[track_caller]
+    pub fn expect_no_more_replies(self) -> Self {
+        assert!(
+            self.next >= self.replies.len(),
+            "unexpected replies: {:?}",
+            &self.replies[self.next..]
+        );
+        self
+    }
+}
+
+/// A message sent by the bot, i.e. the parameters of a `messages.send` call.
+///
+/// Assertion methods panic with a message describing the reply if it does not
+/// match, and return the reply so that they can be chained.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reply {
+    call: Call,
+}
+
+impl Reply {
+    fn from_call(call: &Call) -> Self {
+        Self { call: call.clone() }
+    }
+
+    /// Returns the `messages.send` call of this reply.
+    pub fn call(&self) -> &Call {
+        &self.call
+    }
+
+    /// Returns the ID of the peer this reply was sent to, if it was sent to
+    /// one peer.
+    pub fn peer_id(&self) -> Option<Integer> {
+        self.call.param("peer_id").and_then(|id| id.parse().ok())
+    }
+
+    /// Returns the text of this reply (empty if there is none).
+    pub fn text(&self) -> &str {
+        self.call.param("message").unwrap_or_default()
+    }
+
+    /// Returns the attachments of this reply, e.g. `photo1_2`.
+    pub fn attachments(&self) -> Vec<&str> {
+        self.call
+            .param("attachment")
+            .map_or_else(Vec::new, |attachments| attachments.split(',').collect())
+    }
+
+    /// Returns the keyboard of this reply (as JSON), if any.
+    pub fn keyboard(&self) -> Option<Value> {
+        self.call
+            .param("keyboard")
+            .and_then(|kbd| serde_json::from_str(kbd).ok())
+    }
+
+    /// Returns the labels of the buttons of the keyboard of this reply, row
+    /// by row.
+    pub fn buttons(&self) -> Vec<String> {
+        let keyboard = self.keyboard().unwrap_or_default();
+        let rows = keyboard["buttons"].as_array().cloned().unwrap_or_default();
+
+        rows.iter()
+            .filter_map(Value::as_array)
+            .flatten()
+            .filter_map(|button| button["action"]["label"].as_str().map(Into::into))
+            .collect()
+    }
+
+    /// Asserts that this reply was sent to `peer_id`.
+    #[track_caller]
+    pub fn to(&self, peer_id: Integer) -> &Self {
+        assert_eq!(
+            self.peer_id(),
+            Some(peer_id),
+            "reply was sent to another peer: {:?}",
+            self
+        );
+        self
+    }
+
+    /// Asserts that the text of this reply is `text`.
+    #[track_caller]
+    pub fn text_is(&self, text: &str) -> &Self {
+        assert_eq!(self.text(), text, "unexpected reply text");
+        self
+    }
+
+    /// Asserts that the text of this reply contains `part`.
+    #[track_caller]
+    pub fn text_contains(&self, part: &str) -> &Self {
+        assert!(
+            self.text().contains(part),
+            "reply text {:?} does not contain {:?}",
+            self.text(),
+            part
+        );
+        self
+    }
+
+    /// Asserts that this reply has a keyboard (possibly an empty one).
+    #[track_caller]
+    pub fn has_keyboard(&self) -> &Self {
+        assert!(
+            self.keyboard().is_some(),
+            "reply has no keyboard: {:?}",
+            self
+        );
+        self
+    }
+
+    /// Asserts that this reply has no keyboard.
+    #[track_caller]
+    pub fn has_no_keyboard(&self) -> &Self {
+        assert!(
+            self.keyboard().is_none(),
+            "reply has a keyboard: {:?}",
+            self
+        );
+        self
+    }
+
+    /// Asserts that the keyboard of this reply has a button with `label`.
+    #[track_caller]
+    pub fn has_button(&self, label: &str) -> &Self {
+        let buttons = self.buttons();
+        assert!(
+            buttons.iter().any(|button| button == label),
+            "reply keyboard has no button {:?}, only {:?}",
+            label,
+            buttons
+        );
+        self
+    }
+
+    /// Asserts that this reply has an attachment which starts with `prefix`
+    /// (e.g. `photo`, or a full attachment like `photo1_2`).
+    #[track_caller]
+    pub fn has_attachment(&self, prefix: &str) -> &Self {
+        assert!(
+            self.attachments()
+                .iter()
+                .any(|attachment| attachment.starts_with(prefix)),
+            "reply has no attachment {:?}: {:?}",
+            prefix,
+            self
+        );
+        self
+    }
+
+    /// Asserts that the `messages.send` parameter `key` of this reply is
+    /// `value`.
+    #[track_caller]
+    pub fn param_is(&self, key: &str, value: &str) -> &Self {
+        assert_eq!(
+            self.call.param(key),
+            Some(value),
+            "unexpected `{}` of reply",
+            key
+        );
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        core::Handler,
+        keyboard::{Button, Color, Keyboard},
+        response::{AttachmentInformation, AttachmentType},
+    };
+
+    fn core() -> Core {
+        Core::new()
+            .cmd_prefix("/")
+            .cmd(
+                "start",
+                Handler::new(|ctx| {
+                    let button =
+                        Button::text("Photo", Color::Primary, Some(r#"{"photo":1}"#.into()));
+                    ctx.response().set_message("Welcome!");
+                    ctx.response()
+                        .set_keyboard(Keyboard::new(vec![vec![button]], true));
+                    ctx.send().unwrap();
+                }),
+            )
+            .payload(
+                r#"{"photo":1}"#,
+                Handler::new(|ctx| {
+                    ctx.response().set_message("Here you go");
+                    ctx.response().attach(AttachmentInformation::new(
+                        AttachmentType::Photo,
+                        1,
+                        2,
+                        None,
+                    ));
+                    ctx.send().unwrap();
+                    ctx.response().set_message("Anything else?");
+                    ctx.send().unwrap();
+                }),
+            )
+            .on(Event::NoMatch, Handler::new(|_| {}))
+    }
+
+    #[test]
+    fn conversation() {
+        let harness = Harness::new(core());
+        let user = harness.user(42);
+
+        user.says("/start")
+            .expect_reply(|r| {
+                r.to(42)
+                    .text_is("Welcome!")
+                    .has_keyboard()
+                    .has_button("Photo")
+            })
+            .expect_no_more_replies();
+
+        user.presses("Photo", r#"{"photo":1}"#)
+            .expect_reply(|r| r.text_contains("Here").has_attachment("photo1_2"))
+            .expect_reply(|r| r.text_is("Anything else?").has_no_keyboard())
+            .expect_no_more_replies();
+
+        user.says("hello").expect_no_reply();
+
+        assert_eq!(harness.replies().len(), 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "does not contain")]
+    fn mismatch_panics() {
+        Harness::new(core())
+            .user(1)
+            .says("/start")
+            .expect_reply(|r| r.text_contains("Goodbye"));
+    }
+
+    #[test]
+    #[should_panic(expected = "expected reply #2")]
+    fn missing_reply_panics() {
+        Harness::new(core())
+            .user(1)
+            .says("/start")
+            .expect_reply(|r| r)
+            .expect_reply(|r| r);
+    }
+}
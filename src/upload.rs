@@ -0,0 +1,296 @@
+//! Uploading media to VK, to be used as outgoing attachments.
+//!
+//! Every upload consists of three steps: getting an upload server URL from VK
+//! API, `POST`ing the file to that URL as `multipart/form-data`, and saving
+//! the uploaded file using VK API, which results in an
+//! [`AttachmentInformation`] that can be
+//! [attached](crate::response::Response::attach) to a
+//! [`Response`](crate::response::Response).
+//!
+//! See also [`Context::upload_photo`](crate::Context::upload_photo) and
+//! [`Bot::upload_photo`](crate::Bot::upload_photo) (and similar methods).
+
+use crate::{
+    api::VkAPI,
+    response::{AttachmentInformation, AttachmentType},
+};
+use reqwest::blocking::{
+    multipart::{Form, Part},
+    Client,
+};
+use rvk::{error::Error, objects::Integer, Params};
+use serde_json::Value;
+use std::{
+    convert::TryFrom,
+    fmt::{self, Display, Formatter},
+    time::Duration,
+};
+
+/// How long [`upload_file`] waits for an upload server to respond, so that
+/// an unresponsive server does not block the handler forever.
+pub const UPLOAD_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Uploads a photo to be sent in a private message.
+///
+/// `peer_id` may be omitted, but uploading for a specific peer is recommended
+/// by VK.
+pub fn upload_photo(
+    api: &dyn VkAPI,
+    peer_id: Option<Integer>,
+    file_name: &str,
+    bytes: Vec<u8>,
+) -> Result<AttachmentInformation, UploadError> {
+    let mut params = Params::new();
+    if let Some(peer_id) = peer_id {
+        params.insert("peer_id".into(), peer_id.to_string());
+    }
+
+    let server = api.call_method("photos.getMessagesUploadServer", params)?;
+    let uploaded = api.upload_file(&upload_url(&server)?, "photo", file_name, bytes)?;
+
+    if !matches!(uploaded["photo"].as_str(), Some(photo) if photo != "[]") {
+        return Err(UploadError::Response(format!(
+            "photo was not uploaded: {}",
+            uploaded
+        )));
+    }
+
+    let mut params = Params::new();
+    for key in &["server", "photo", "hash"] {
+        params.insert((*key).into(), value_to_param(&uploaded[key]));
+    }
+
+    let saved = api.call_method("photos.saveMessagesPhoto", params)?;
+    let photo = &saved[0];
+
+    match (photo["owner_id"].as_i64(), photo["id"].as_i64()) {
+        (Some(owner_id), Some(id)) => Ok(AttachmentInformation::new(
+            AttachmentType::Photo,
+            owner_id,
+            id,
+            photo["access_key"].as_str().map(Into::into),
+        )),
+        _ => Err(UploadError::Response(format!(
+            "unexpected `photos.saveMessagesPhoto` response: {}",
+            saved
+        ))),
+    }
+}
+
+/// Uploads a document to be sent in a private message to `peer_id`.
+///
+/// `title` defaults to `file_name`.
+pub fn upload_document(
+    api: &dyn VkAPI,
+    peer_id: Integer,
+    file_name: &str,
+    bytes: Vec<u8>,
+    title: Option<&str>,
+) -> Result<AttachmentInformation, UploadError> {
+    upload_doc(api, peer_id, "doc", file_name, bytes, title)
+}
+
+/// Uploads a voice message (preferably an `.ogg` file encoded with Opus) to be
+/// sent in a private message to `peer_id`.
+pub fn upload_audio_message(
+    api: &dyn VkAPI,
+    peer_id: Integer,
+    file_name: &str,
+    bytes: Vec<u8>,
+) -> Result<AttachmentInformation, UploadError> {
+    upload_doc(api, peer_id, "audio_message", file_name, bytes, None)
+}
+
+/// Uploads a document of the given `docs.getMessagesUploadServer` type.
+fn upload_doc(
+    api: &dyn VkAPI,
+    peer_id: Integer,
+    r#type: &str,
+    file_name: &str,
+    bytes: Vec<u8>,
+    title: Option<&str>,
+) -> Result<AttachmentInformation, UploadError> {
+    let mut params = Params::new();
+    params.insert("type".into(), r#type.into());
+    params.insert("peer_id".into(), peer_id.to_string());
+
+    let server = api.call_method("docs.getMessagesUploadServer", params)?;
+    let uploaded = api.upload_file(&upload_url(&server)?, "file", file_name, bytes)?;
+
+    let file = match uploaded["file"].as_str() {
+        Some(file) => file,
+        None => {
+            return Err(UploadError::Response(format!(
+                "document was not uploaded: {}",
+                uploaded
+            )))
+        }
+    };
+
+    let mut params = Params::new();
+    params.insert("file".into(), file.into());
+    params.insert("title".into(), title.unwrap_or(file_name).into());
+
+    let saved = api.call_method("docs.save", params)?;
+
+    AttachmentInformation::try_from(&saved).map_err(|e| {
+        UploadError::Response(format!(
+            "unexpected `docs.save` response ({}): {}",
+            e, saved
+        ))
+    })
+}
+
+/// `POST`s a file to an upload server URL as `multipart/form-data`, in the
+/// form field `field`, and returns the JSON response of the upload server.
+///
+/// The request fails if the upload server does not respond within
+/// [`UPLOAD_TIMEOUT`].
+pub fn upload_file(
+    url: &str,
+    field: &str,
+    file_name: &str,
+    bytes: Vec<u8>,
+) -> Result<Value, UploadError> {
+    post_file(url, field, file_name, bytes, UPLOAD_TIMEOUT)
+}
+
+/// See [`upload_file`].
+fn post_file(
+    url: &str,
+    field: &str,
+    file_name: &str,
+    bytes: Vec<u8>,
+    timeout: Duration,
+) -> Result<Value, UploadError> {
+    trace!(
+        "uploading {:?} ({} bytes) to {}",
+        file_name,
+        bytes.len(),
+        url
+    );
+
+    let form = Form::new().part(
+        field.to_string(),
+        Part::bytes(bytes).file_name(file_name.to_string()),
+    );
+
+    let text = Client::builder()
+        .timeout(timeout)
+        .build()?
+        .post(url)
+        .multipart(form)
+        .send()?
+        .error_for_status()?
+        .text()?;
+
+    serde_json::from_str(&text).map_err(|_| {
+        UploadError::Response(format!("upload server returned invalid JSON: {}", text))
+    })
+}
+
+/// Extracts `upload_url` from a `*.getMessagesUploadServer` response.
+fn upload_url(server: &Value) -> Result<String, UploadError> {
+    server["upload_url"]
+        .as_str()
+        .map(Into::into)
+        .ok_or_else(|| UploadError::Response(format!("no `upload_url` in {}", server)))
+}
+
+/// Converts a JSON value returned by an upload server to a VK API parameter.
+fn value_to_param(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// Error type for uploads.
+#[derive(Debug)]
+pub enum UploadError {
+    /// There is no peer to upload the file for.
+    NoPeer,
+    /// VK API returned an error.
+    API(Error),
+    /// The HTTP request to the upload server failed.
+    HTTP(reqwest::Error),
+    /// The upload server or VK API returned an unexpected response.
+    Response(String),
+}
+
+impl Display for UploadError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            UploadError::NoPeer => f.write_str("no peer to upload the file for"),
+            UploadError::API(e) => write!(f, "VK API error: {:?}", e),
+            UploadError::HTTP(e) => write!(f, "upload request failed: {}", e),
+            UploadError::Response(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl From<Error> for UploadError {
+    fn from(e: Error) -> Self {
+        UploadError::API(e)
+    }
+}
+
+impl From<reqwest::Error> for UploadError {
+    fn from(e: reqwest::Error) -> Self {
+        UploadError::HTTP(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::serve;
+
+    #[test]
+    fn upload_file_sends_multipart() -> Result<(), UploadError> {
+        let (url, server) = serve(
+            "/upload",
+            r#"{"server": 1, "photo": "[{}]", "hash": "abc"}"#,
+        );
+
+        let response = upload_file(&url, "photo", "cat.jpg", b"meow".to_vec())?;
+        assert_eq!(response["hash"], "abc");
+        assert_eq!(value_to_param(&response["server"]), "1");
+
+        let body = server.join().expect("upload server panicked").body;
+        assert!(body.contains(r#"name="photo"; filename="cat.jpg""#));
+        assert!(body.contains("meow"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn upload_file_invalid_json() {
+        let (url, server) = serve("/upload", "not json");
+
+        match upload_file(&url, "file", "a.txt", Vec::new()) {
+            Err(UploadError::Response(_)) => {}
+            other => panic!("expected `UploadError::Response`, got {:?}", other),
+        }
+
+        server.join().expect("upload server panicked");
+    }
+
+    #[test]
+    fn upload_file_times_out() {
+        // Accepts connections, but never responds.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("failed to bind");
+        let url = format!("http://{}/upload", listener.local_addr().unwrap());
+
+        match post_file(
+            &url,
+            "file",
+            "a.txt",
+            Vec::new(),
+            Duration::from_millis(100),
+        ) {
+            Err(UploadError::HTTP(e)) if e.is_timeout() => {}
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+    }
+}
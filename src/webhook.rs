@@ -0,0 +1,241 @@
+//! Handling Callback API requests independently of a server framework.
+//!
+//! A [`Webhook`] ([`Bot`](crate::Bot) or
+//! [`MultiBot`](crate::multi::MultiBot)) responds to the JSON body of a
+//! request, and [`handle`] turns a raw HTTP request (method, path,
+//! `Content-Type` header and body) into a [`Response`]. This is what the
+//! Rocket routes (the default `rocket` feature) use, and what other servers
+//! can use too.
+//!
+//! Adapters for other frameworks are available behind cargo features:
+//! - `hyper` (hyper 1): [`hyper::handle`],
+//! - `axum` (axum 0.7): [`axum::router`],
+//! - `actix-web` (actix-web 4): [`actix::configure`].
+//!
+//! None of them need nightly Rust, so with `default-features = false` and one
+//! of them, this crate builds on stable.
+//!
+//! Handling a request may block (e.g. on VK API calls made by handlers, unless
+//! the bot has a worker pool), so the adapters handle requests on a thread
+//! where blocking is allowed. Code calling [`handle`] directly from an async
+//! runtime should do the same.
+//!
+//! ```
+//! use vk_bot::{
+//!     webhook::{self, OK},
+//!     Bot, Core,
+//! };
+//!
+//! let bot = Bot::new("...", "f123456", 1, None, 12345, Core::new());
+//! let body = br#"{"type": "confirmation", "group_id": 1}"#;
+//!
+//! let response = webhook::handle(&bot, "POST", "/", Some("application/json"), body);
+//! assert_eq!(response.status(), OK);
+//! assert_eq!(response.body(), "f123456");
+//! ```
+
+use serde_json::Value;
+#[cfg(any(feature = "hyper", feature = "axum"))]
+use std::sync::Arc;
+
+#[cfg(feature = "actix-web")]
+pub mod actix;
+#[cfg(feature = "axum")]
+pub mod axum;
+#[cfg(feature = "hyper")]
+pub mod hyper;
+
+/// Status code of successful responses.
+pub const OK: u16 = 200;
+/// Status code of responses to bodies which are not valid JSON.
+pub const BAD_REQUEST: u16 = 400;
+/// Status code of responses to requests with a wrong `secret` or group ID.
+pub const FORBIDDEN: u16 = 403;
+/// Status code of responses to requests sent to an unknown path.
+pub const NOT_FOUND: u16 = 404;
+/// Status code of responses to requests with a method other than `POST`.
+pub const METHOD_NOT_ALLOWED: u16 = 405;
+/// Status code of responses to requests with a body which is too large.
+pub const PAYLOAD_TOO_LARGE: u16 = 413;
+/// Status code of responses to requests with a body which is not JSON.
+pub const UNSUPPORTED_MEDIA_TYPE: u16 = 415;
+/// Status code of responses to bodies which are not valid Callback API
+/// requests.
+pub const UNPROCESSABLE_ENTITY: u16 = 422;
+/// Status code of responses to requests which could not be handled because
+/// of a bug (e.g. a panicking handler).
+pub const INTERNAL_SERVER_ERROR: u16 = 500;
+/// Status code of responses to requests which can not be handled right now.
+pub const SERVICE_UNAVAILABLE: u16 = 503;
+
+/// `Content-Type` of [`Response`] bodies.
+pub const CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+
+/// Something which responds to Callback API requests.
+pub trait Webhook: Send + Sync {
+    /// Responds to a Callback API request with the JSON body `data`, sent to
+    /// `path` (relative to where the webhook is served, e.g. `/` or
+    /// `/shop`).
+    fn respond(&self, path: &str, data: &Value) -> Response;
+}
+
+impl<W: Webhook + ?Sized> Webhook for &W {
+    fn respond(&self, path: &str, data: &Value) -> Response {
+        (**self).respond(path, data)
+    }
+}
+
+/// Response to a Callback API request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    status: u16,
+    body: String,
+}
+
+impl Response {
+    /// Creates a new [`Response`] with a status code and body.
+    pub fn new(status: u16, body: &str) -> Self {
+        Self {
+            status,
+            body: body.into(),
+        }
+    }
+
+    /// Creates a new successful [`Response`] with `body`.
+    pub fn ok(body: &str) -> Self {
+        Self::new(OK, body)
+    }
+
+    /// Creates a new [`Response`] with an error status code and an empty
+    /// body.
+    pub fn error(status: u16) -> Self {
+        Self::new(status, "")
+    }
+
+    /// Returns the status code.
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// Returns the body (`ok`, the confirmation token, or empty for errors).
+    pub fn body(&self) -> &str {
+        &self.body
+    }
+
+    /// Returns whether the status code is `2xx`.
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// Returns the body if this response is successful, or the status code
+    /// otherwise.
+    pub fn into_result(self) -> Result<String, u16> {
+        if self.is_success() {
+            Ok(self.body)
+        } else {
+            Err(self.status)
+        }
+    }
+}
+
+/// Responds to a raw HTTP request sent to `path` (relative to where
+/// `webhook` is served), using `webhook` for `POST` requests with a JSON
+/// body.
+pub fn handle<W: Webhook + ?Sized>(
+    webhook: &W,
+    method: &str,
+    path: &str,
+    content_type: Option<&str>,
+    body: &[u8],
+) -> Response {
+    if !method.eq_ignore_ascii_case("POST") {
+        debug!("received a {} request", method);
+        return Response::error(METHOD_NOT_ALLOWED);
+    }
+
+    let is_json = match content_type.and_then(|content_type| content_type.split(';').next()) {
+        Some(mime) => mime.trim().eq_ignore_ascii_case("application/json"),
+        None => false,
+    };
+    if !is_json {
+        debug!(
+            "received a POST request with `Content-Type` {:?}",
+            content_type
+        );
+        return Response::error(UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    match serde_json::from_slice(body) {
+        Ok(data) => webhook.respond(path, &data),
+        Err(e) => {
+            debug!("received a POST request with invalid JSON: {}", e);
+            Response::error(BAD_REQUEST)
+        }
+    }
+}
+
+/// Runs [`handle`] on a thread of the Tokio blocking pool.
+#[cfg(any(feature = "hyper", feature = "axum"))]
+async fn handle_blocking<W, B>(
+    webhook: Arc<W>,
+    method: String,
+    path: String,
+    content_type: Option<String>,
+    body: B,
+) -> Response
+where
+    W: Webhook + ?Sized + 'static,
+    B: AsRef<[u8]> + Send + 'static,
+{
+    let handled = tokio::task::spawn_blocking(move || {
+        handle(
+            &*webhook,
+            &method,
+            &path,
+            content_type.as_deref(),
+            body.as_ref(),
+        )
+    });
+
+    handled.await.unwrap_or_else(|e| {
+        error!("failed to handle request: {}", e);
+        Response::error(INTERNAL_SERVER_ERROR)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct Echo;
+
+    impl Webhook for Echo {
+        fn respond(&self, path: &str, data: &Value) -> Response {
+            Response::ok(&format!("{} {}", path, data["type"]))
+        }
+    }
+
+    #[test]
+    fn handle_checks_request() {
+        let json = Some("application/json; charset=utf-8");
+        let body = br#"{"type": "confirmation"}"#;
+
+        assert_eq!(
+            handle(&Echo, "POST", "/a", json, body),
+            Response::ok(r#"/a "confirmation""#)
+        );
+        assert_eq!(
+            handle(&Echo, "GET", "/", None, b"").status(),
+            METHOD_NOT_ALLOWED
+        );
+        assert_eq!(
+            handle(&Echo, "POST", "/", Some("text/plain"), body).status(),
+            UNSUPPORTED_MEDIA_TYPE
+        );
+        assert_eq!(
+            handle(&Echo, "POST", "/", json, b"{").into_result(),
+            Err(BAD_REQUEST)
+        );
+    }
+}
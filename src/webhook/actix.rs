@@ -0,0 +1,112 @@
+//! Serving a [`Webhook`] with actix-web 4 (the `actix-web` feature).
+//!
+//! ```ignore
+//! use actix_web::{web, App, HttpServer};
+//! use std::sync::Arc;
+//! use vk_bot::{webhook, Bot};
+//!
+//! let bot: Arc<Bot> = Arc::new(/* ... */);
+//! HttpServer::new(move || {
+//!     App::new().service(web::scope("/vk").configure(webhook::actix::configure(Arc::clone(&bot))))
+//! })
+//! .bind("127.0.0.1:12345")?
+//! .run()
+//! .await
+//! ```
+
+use super::{Webhook, CONTENT_TYPE};
+use ::actix_web::{
+    http::{header, StatusCode},
+    web::{self, Bytes, Data, ServiceConfig},
+    HttpRequest, HttpResponse,
+};
+use std::sync::Arc;
+
+/// Returns a function which configures a scope (or an application) to
+/// respond to all requests using `webhook` (see [`handle`](super::handle)).
+pub fn configure<W: Webhook + 'static>(webhook: Arc<W>) -> impl FnOnce(&mut ServiceConfig) {
+    move |config| {
+        config
+            .app_data(Data::from(webhook))
+            .route("", web::to(handle::<W>))
+            .route("/{path:.*}", web::to(handle::<W>));
+    }
+}
+
+/// Handles any request.
+async fn handle<W: Webhook + 'static>(
+    req: HttpRequest,
+    body: Bytes,
+    webhook: Data<W>,
+) -> HttpResponse {
+    let method = req.method().to_string();
+    let path = format!("/{}", req.match_info().get("path").unwrap_or_default());
+    let content_type = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+
+    let handled = web::block(move || {
+        super::handle(&**webhook, &method, &path, content_type.as_deref(), &body)
+    });
+
+    match handled.await {
+        Ok(response) => {
+            let status = StatusCode::from_u16(response.status())
+                .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+
+            HttpResponse::build(status)
+                .content_type(CONTENT_TYPE)
+                .body(response.body)
+        }
+        Err(e) => {
+            error!("failed to handle request: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        webhook::{METHOD_NOT_ALLOWED, NOT_FOUND, OK},
+        Bot, Core,
+    };
+    use ::actix_web::{rt::System, test, App};
+
+    /// Sends `req` to an application with a [`Bot`] in the scope `/vk`, and
+    /// returns the status code and body of the response.
+    fn respond(req: test::TestRequest) -> (u16, String) {
+        let bot = Arc::new(Bot::new("...", "f123456", 1, None, 12345, Core::new()));
+
+        System::new().block_on(async {
+            let app =
+                test::init_service(App::new().service(web::scope("/vk").configure(configure(bot))))
+                    .await;
+            let response = test::call_service(&app, req.to_request()).await;
+            let status = response.status().as_u16();
+            let body = test::read_body(response).await;
+            (status, String::from_utf8(body.to_vec()).unwrap())
+        })
+    }
+
+    fn post(uri: &str) -> test::TestRequest {
+        test::TestRequest::post()
+            .uri(uri)
+            .insert_header((header::CONTENT_TYPE, "application/json"))
+            .set_payload(r#"{"type": "confirmation", "group_id": 1}"#)
+    }
+
+    #[test]
+    fn configure_handles_requests() {
+        assert_eq!(respond(post("/vk")), (OK, "f123456".into()));
+        // Other paths below `/vk` are handed to the bot, which only serves `/`.
+        assert_eq!(respond(post("/vk/shop")).0, NOT_FOUND);
+        assert_eq!(respond(post("/other")).0, NOT_FOUND);
+
+        let get = test::TestRequest::get().uri("/vk");
+        assert_eq!(respond(get).0, METHOD_NOT_ALLOWED);
+    }
+}
@@ -0,0 +1,116 @@
+//! Serving a [`Webhook`] with axum 0.7 (the `axum` feature).
+//!
+//! ```
+//! use axum::{routing::get, Router};
+//! use std::sync::Arc;
+//! use vk_bot::{webhook, Bot, Core};
+//!
+//! let bot = Arc::new(Bot::new("...", "f123456", 1, None, 12345, Core::new()));
+//! let app: Router = Router::new()
+//!     .nest("/vk", webhook::axum::router(bot))
+//!     .route("/admin", get(|| async { "admin" }));
+//! ```
+
+use super::{handle_blocking, Webhook, CONTENT_TYPE};
+use ::axum::{
+    body::Bytes,
+    extract::State,
+    http::{header, HeaderMap, Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    routing::any,
+    Router,
+};
+use std::sync::Arc;
+
+/// Returns a [`Router`] which responds to all requests using `webhook` (see
+/// [`handle`](super::handle)), to be nested at a path of the application.
+pub fn router<W: Webhook + 'static>(webhook: Arc<W>) -> Router {
+    Router::new()
+        .route("/", any(handle::<W>))
+        .route("/*path", any(handle::<W>))
+        .with_state(webhook)
+}
+
+/// Handles any request.
+async fn handle<W: Webhook + 'static>(
+    State(webhook): State<Arc<W>>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+
+    let response = handle_blocking(
+        webhook,
+        method.to_string(),
+        uri.path().into(),
+        content_type,
+        body,
+    )
+    .await;
+
+    let status =
+        StatusCode::from_u16(response.status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+
+    (
+        status,
+        [(header::CONTENT_TYPE, CONTENT_TYPE)],
+        response.body,
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        webhook::{METHOD_NOT_ALLOWED, NOT_FOUND, OK},
+        Bot, Core,
+    };
+    use ::axum::{
+        body::{self, Body},
+        http::Request,
+    };
+    use tower::ServiceExt;
+
+    /// Sends `req` to an application with a [`Bot`] nested at `/vk`, and
+    /// returns the status code and body of the response.
+    fn respond(req: Request<Body>) -> (u16, String) {
+        let bot = Arc::new(Bot::new("...", "f123456", 1, None, 12345, Core::new()));
+        let app = Router::new().nest("/vk", router(bot));
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            let response = app.oneshot(req).await.unwrap();
+            let status = response.status().as_u16();
+            let body = body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            (status, String::from_utf8(body.to_vec()).unwrap())
+        })
+    }
+
+    fn post(uri: &str) -> Request<Body> {
+        Request::post(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"type": "confirmation", "group_id": 1}"#))
+            .unwrap()
+    }
+
+    #[test]
+    fn router_handles_requests() {
+        assert_eq!(respond(post("/vk")), (OK, "f123456".into()));
+        // Other paths below `/vk` are handed to the bot, which only serves `/`.
+        assert_eq!(respond(post("/vk/shop")).0, NOT_FOUND);
+        assert_eq!(respond(post("/other")).0, NOT_FOUND);
+
+        let get = Request::get("/vk").body(Body::empty()).unwrap();
+        assert_eq!(respond(get).0, METHOD_NOT_ALLOWED);
+    }
+}
@@ -0,0 +1,205 @@
+//! Serving a [`Webhook`] with hyper 1 (the `hyper` feature).
+//!
+//! ```ignore
+//! use hyper::{server::conn::http1, service::service_fn};
+//! use std::sync::Arc;
+//! use vk_bot::{webhook, Bot};
+//!
+//! let bot: Arc<Bot> = Arc::new(/* ... */);
+//!
+//! // For each accepted connection (`io`, e.g. a `hyper_util::rt::TokioIo`):
+//! let bot = Arc::clone(&bot);
+//! let service = service_fn(move |req| webhook::hyper::handle(Arc::clone(&bot), "/vk", req));
+//! http1::Builder::new().serve_connection(io, service).await?;
+//! ```
+
+use super::{
+    handle_blocking, Response, Webhook, BAD_REQUEST, CONTENT_TYPE, NOT_FOUND, PAYLOAD_TOO_LARGE,
+};
+use ::hyper::{
+    body::{Body, Bytes},
+    header::{self, HeaderValue},
+    Request, StatusCode,
+};
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
+use std::{convert::Infallible, error::Error, sync::Arc};
+
+/// Maximum size of a request body, in bytes (1 MiB, like the limit of JSON
+/// bodies in Rocket). Requests with larger bodies get a
+/// `413 Payload Too Large` response.
+pub const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// Responds to `req` using `webhook` (see [`handle`](super::handle)), which
+/// is served at `prefix` (e.g. `/vk`, or `/` for the whole server): the path
+/// given to `webhook` is the URI path without `prefix`. Requests to paths
+/// outside of `prefix` get a `404 Not Found` response.
+///
+/// At most [`MAX_BODY_SIZE`] bytes of the body are read.
+pub async fn handle<W, B>(
+    webhook: Arc<W>,
+    prefix: &str,
+    req: Request<B>,
+) -> Result<::hyper::Response<Full<Bytes>>, Infallible>
+where
+    W: Webhook + ?Sized + 'static,
+    B: Body,
+    B::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    let (parts, body) = req.into_parts();
+
+    let path = match strip_prefix(parts.uri.path(), prefix) {
+        Some(path) => path.to_string(),
+        None => {
+            debug!("received a request to `{}`", parts.uri.path());
+            return Ok(to_hyper(Response::error(NOT_FOUND)));
+        }
+    };
+
+    let response = match Limited::new(body, MAX_BODY_SIZE).collect().await {
+        Ok(body) => {
+            let content_type = parts
+                .headers
+                .get(header::CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .map(String::from);
+
+            handle_blocking(
+                webhook,
+                parts.method.to_string(),
+                path,
+                content_type,
+                body.to_bytes(),
+            )
+            .await
+        }
+        Err(e) if e.is::<LengthLimitError>() => {
+            debug!(
+                "received a request with a body over {} bytes",
+                MAX_BODY_SIZE
+            );
+            Response::error(PAYLOAD_TOO_LARGE)
+        }
+        Err(_) => {
+            debug!("failed to read the body of a request");
+            Response::error(BAD_REQUEST)
+        }
+    };
+
+    Ok(to_hyper(response))
+}
+
+/// Returns `path` relative to `prefix`, if it is `prefix` or below it.
+fn strip_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    match path.strip_prefix(prefix.trim_end_matches('/'))? {
+        "" => Some("/"),
+        rest if rest.starts_with('/') => Some(rest),
+        _ => None,
+    }
+}
+
+/// Converts a [`Response`] to a hyper response.
+fn to_hyper(response: Response) -> ::hyper::Response<Full<Bytes>> {
+    let status =
+        StatusCode::from_u16(response.status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+
+    let mut hyper_response = ::hyper::Response::new(Full::new(Bytes::from(response.body)));
+    *hyper_response.status_mut() = status;
+    hyper_response
+        .headers_mut()
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(CONTENT_TYPE));
+
+    hyper_response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        webhook::{METHOD_NOT_ALLOWED, OK},
+        Bot, Core,
+    };
+    use ::hyper::Method;
+
+    fn post(uri: &str, body: &'static str) -> Request<Full<Bytes>> {
+        Request::builder()
+            .method(Method::POST)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Full::new(Bytes::from(body)))
+            .unwrap()
+    }
+
+    /// Handles `req` with a [`Bot`] served at `prefix`, and returns the
+    /// status code and body of the response.
+    fn respond(prefix: &str, req: Request<Full<Bytes>>) -> (u16, String) {
+        let bot = Arc::new(Bot::new("...", "f123456", 1, None, 12345, Core::new()));
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            let response = handle(bot, prefix, req).await.unwrap();
+            let status = response.status().as_u16();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            (status, String::from_utf8(body.to_vec()).unwrap())
+        })
+    }
+
+    const CONFIRMATION: &str = r#"{"type": "confirmation", "group_id": 1}"#;
+
+    #[test]
+    fn handle_request() {
+        assert_eq!(
+            respond("/", post("/", CONFIRMATION)),
+            (OK, "f123456".into())
+        );
+        assert_eq!(respond("/", post("/vk", CONFIRMATION)).0, NOT_FOUND);
+
+        let get = Request::get("/").body(Full::default()).unwrap();
+        assert_eq!(respond("/", get).0, METHOD_NOT_ALLOWED);
+    }
+
+    #[test]
+    fn handle_prefixed_request() {
+        for uri in &["/vk", "/vk/", "/vk?a=b"] {
+            assert_eq!(
+                respond("/vk", post(uri, CONFIRMATION)),
+                (OK, "f123456".into()),
+                "{}",
+                uri
+            );
+        }
+        assert_eq!(respond("/vk/", post("/vk", CONFIRMATION)).0, OK);
+
+        for uri in &["/", "/vkontakte", "/vk/shop"] {
+            assert_eq!(
+                respond("/vk", post(uri, CONFIRMATION)).0,
+                NOT_FOUND,
+                "{}",
+                uri
+            );
+        }
+    }
+
+    #[test]
+    fn handle_limits_body_size() {
+        let req = |size| {
+            Request::post("/")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Full::new(Bytes::from(vec![b' '; size])))
+                .unwrap()
+        };
+
+        // Bodies within the limit are read (and are not valid JSON here).
+        assert_eq!(respond("/", req(MAX_BODY_SIZE)).0, BAD_REQUEST);
+        assert_eq!(respond("/", req(MAX_BODY_SIZE + 1)).0, PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn strip() {
+        assert_eq!(strip_prefix("/a/b", "/a"), Some("/b"));
+        assert_eq!(strip_prefix("/a", "/a/"), Some("/"));
+        assert_eq!(strip_prefix("/a", ""), Some("/a"));
+        assert_eq!(strip_prefix("/ab", "/a"), None);
+    }
+}